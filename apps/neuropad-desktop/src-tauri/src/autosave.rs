@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use neuropad_core::{MetadataStore, Notebook, RecoveryJournal, RecoverySession};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use uuid::Uuid;
+
+use crate::AppState;
+
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+struct PendingSnapshot {
+    path: Option<String>,
+    notebook: Notebook,
+}
+
+/// Unsaved state per editing session on its way to the recovery journal. A
+/// background worker collects changed open notebooks and flushes them, so the
+/// command path never waits on disk. A session is an open notebook's id.
+#[derive(Default)]
+pub struct AutosaveQueue {
+    pending: Mutex<HashMap<Uuid, PendingSnapshot>>,
+    flushing: Mutex<()>,
+}
+
+impl AutosaveQueue {
+    /// Stages what `collect` returns, replacing older state of the same
+    /// sessions, and writes every staged session. Collecting under the flush
+    /// lock keeps a concurrent [`AutosaveQueue::discard`] from being undone.
+    /// A session that fails is staged again, unless something newer was
+    /// staged meanwhile, and the rest still go out.
+    pub fn flush(
+        &self,
+        collect: impl FnOnce() -> Result<Vec<(Uuid, Option<String>, Notebook)>>,
+        journal: &RecoveryJournal,
+        metadata: &Mutex<MetadataStore>,
+    ) -> Result<()> {
+        let _flushing = self.flushing.lock().map_err(|_| anyhow!("autosave lock poisoned"))?;
+        let drained = {
+            let mut pending = self.pending.lock().map_err(|_| anyhow!("autosave lock poisoned"))?;
+            for (session_id, path, notebook) in collect()? {
+                pending.insert(session_id, PendingSnapshot { path, notebook });
+            }
+            std::mem::take(&mut *pending)
+        };
+        let mut failed = vec![];
+        for (session_id, pending) in drained {
+            if let Err(err) = write_session(session_id, &pending, journal, metadata) {
+                failed.push(format!("session {session_id}: {err:#}"));
+                self.pending
+                    .lock()
+                    .map_err(|_| anyhow!("autosave lock poisoned"))?
+                    .entry(session_id)
+                    .or_insert(pending);
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("autosave failed for {}", failed.join("; ")))
+        }
+    }
+
+    /// Drops a session's unsaved state, waiting out any in-flight flush so a
+    /// stale snapshot cannot reappear after an explicit save.
+    pub fn discard(
+        &self,
+        session_id: Uuid,
+        journal: &RecoveryJournal,
+        metadata: &Mutex<MetadataStore>,
+    ) -> Result<()> {
+        let _flushing = self.flushing.lock().map_err(|_| anyhow!("autosave lock poisoned"))?;
+        self.pending
+            .lock()
+            .map_err(|_| anyhow!("autosave lock poisoned"))?
+            .remove(&session_id);
+        journal.remove_snapshot(session_id)?;
+        metadata
+            .lock()
+            .map_err(|_| anyhow!("metadata lock poisoned"))?
+            .remove_recovery_session(&session_id.to_string())?;
+        Ok(())
+    }
+}
+
+fn write_session(
+    session_id: Uuid,
+    pending: &PendingSnapshot,
+    journal: &RecoveryJournal,
+    metadata: &Mutex<MetadataStore>,
+) -> Result<()> {
+    let snapshot = journal.write_snapshot(session_id, &pending.notebook)?;
+    metadata
+        .lock()
+        .map_err(|_| anyhow!("metadata lock poisoned"))?
+        .upsert_recovery_session(&RecoverySession {
+            session_id: session_id.to_string(),
+            notebook_path: pending.path.clone(),
+            title: pending.notebook.metadata.title.clone(),
+            snapshot_path: snapshot.to_string_lossy().to_string(),
+            saved_at: Utc::now().to_rfc3339(),
+        })?;
+    Ok(())
+}
+
+pub fn spawn_worker(app: AppHandle) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(AUTOSAVE_INTERVAL);
+        let state = app.state::<AppState>();
+        let collect = || state.notebooks.take_dirty().map_err(|e| anyhow!(e));
+        if let Err(err) = state.autosave.flush(collect, &state.journal, &state.metadata) {
+            log::warn!("{err:#}");
+        }
+    });
+}
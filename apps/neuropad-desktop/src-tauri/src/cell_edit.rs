@@ -0,0 +1,223 @@
+use neuropad_core::{Cell, CellTag, CellType, CoreResult, EditOp, Notebook, NotebookMetadata};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use uuid::Uuid;
+
+use crate::AppState;
+
+/// What an edit changed, for the UI to patch its copy with: the cells it
+/// added or modified and, if cells were added, removed or moved, the new order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditResult {
+    cell_id: Option<Uuid>,
+    cells: Vec<Cell>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    order: Option<Vec<Uuid>>,
+    metadata: NotebookMetadata,
+}
+
+fn edited(
+    notebook: &Notebook,
+    cell_id: Option<Uuid>,
+    changed: &[Uuid],
+    reordered: bool,
+) -> EditResult {
+    EditResult {
+        cell_id,
+        cells: changed.iter().filter_map(|id| notebook.cell(*id)).cloned().collect(),
+        order: reordered.then(|| notebook.cells.iter().map(|cell| cell.id).collect()),
+        metadata: notebook.metadata.clone(),
+    }
+}
+
+/// The change made by `op`, e.g. an edit just undone or a restore.
+pub(crate) fn edited_by(notebook: &Notebook, op: Option<&EditOp>) -> EditResult {
+    match op {
+        Some(op) => edited(notebook, None, &op.cell_ids(), op.reorders()),
+        None => edited(notebook, None, &[], false),
+    }
+}
+
+fn edit(
+    state: &AppState,
+    notebook_id: Uuid,
+    change: impl FnOnce(&mut Notebook) -> CoreResult<EditResult>,
+) -> Result<EditResult, String> {
+    state
+        .notebooks
+        .edit(notebook_id, |open| change(&mut open.notebook).map_err(|e| e.to_string()))
+}
+
+#[tauri::command]
+pub fn cell_insert(
+    notebook_id: Uuid,
+    anchor_id: Option<Uuid>,
+    after: bool,
+    cell_type: CellType,
+    language: Option<String>,
+    source: String,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    let cell = match cell_type {
+        CellType::Markdown => Cell::new_markdown(source),
+        CellType::Code => Cell::new_code(language.unwrap_or_default(), source),
+    };
+    edit(&state, notebook_id, |notebook| {
+        let id = match anchor_id {
+            Some(anchor) if after => notebook.insert_cell_after(anchor, cell),
+            Some(anchor) => notebook.insert_cell_before(anchor, cell),
+            None => notebook.insert_cell_at(notebook.cells.len(), cell),
+        }?;
+        Ok(edited(notebook, Some(id), &[id], true))
+    })
+}
+
+#[tauri::command]
+pub fn cell_move(
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    to_index: usize,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        notebook.move_cell(cell_id, to_index)?;
+        Ok(edited(notebook, Some(cell_id), &[], true))
+    })
+}
+
+#[tauri::command]
+pub fn cell_delete(notebook_id: Uuid, cell_id: Uuid, state: State<AppState>) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        notebook.delete_cell(cell_id)?;
+        Ok(edited(notebook, None, &[], true))
+    })
+}
+
+#[tauri::command]
+pub fn cell_duplicate(notebook_id: Uuid, cell_id: Uuid, state: State<AppState>) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        let id = notebook.duplicate_cell(cell_id)?;
+        Ok(edited(notebook, Some(id), &[id], true))
+    })
+}
+
+#[tauri::command]
+pub fn cell_change_type(
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    cell_type: CellType,
+    language: Option<String>,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        notebook.change_cell_type(cell_id, cell_type, language)?;
+        Ok(edited(notebook, Some(cell_id), &[cell_id], false))
+    })
+}
+
+#[tauri::command]
+pub fn cell_split(
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    cursor: usize,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        let id = notebook.split_cell(cell_id, cursor)?;
+        Ok(edited(notebook, Some(id), &[cell_id, id], true))
+    })
+}
+
+#[tauri::command]
+pub fn cell_merge(notebook_id: Uuid, cell_id: Uuid, state: State<AppState>) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        notebook.merge_with_next(cell_id)?;
+        Ok(edited(notebook, Some(cell_id), &[cell_id], true))
+    })
+}
+
+#[tauri::command]
+pub fn cell_set_source(
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    source: String,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        notebook.set_cell_source(cell_id, source)?;
+        // The UI already shows the text it sent.
+        Ok(edited(notebook, Some(cell_id), &[], false))
+    })
+}
+
+#[tauri::command]
+pub fn cell_tag(
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    tag: CellTag,
+    enabled: bool,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        if enabled {
+            notebook.tag_cell(cell_id, tag)
+        } else {
+            notebook.untag_cell(cell_id, &tag)
+        }?;
+        Ok(edited(notebook, Some(cell_id), &[cell_id], false))
+    })
+}
+
+#[tauri::command]
+pub fn cell_set_metadata(
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    key: String,
+    value: Option<serde_json::Value>,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        notebook.set_cell_metadata(cell_id, &key, value)?;
+        Ok(edited(notebook, Some(cell_id), &[cell_id], false))
+    })
+}
+
+#[tauri::command]
+pub fn cell_clear_outputs(
+    notebook_id: Uuid,
+    cell_id: Option<Uuid>,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        let changed = match cell_id {
+            Some(id) => vec![id],
+            None => notebook
+                .cells
+                .iter()
+                .filter(|cell| !cell.outputs.is_empty() || cell.execution.count > 0)
+                .map(|cell| cell.id)
+                .collect(),
+        };
+        match cell_id {
+            Some(id) => notebook.clear_outputs(id),
+            None => notebook.clear_all_outputs(),
+        }?;
+        Ok(edited(notebook, cell_id, &changed, false))
+    })
+}
+
+#[tauri::command]
+pub fn notebook_undo(notebook_id: Uuid, state: State<AppState>) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        let undone = notebook.undo()?;
+        Ok(edited_by(notebook, undone.then(|| notebook.history().last_undone()).flatten()))
+    })
+}
+
+#[tauri::command]
+pub fn notebook_redo(notebook_id: Uuid, state: State<AppState>) -> Result<EditResult, String> {
+    edit(&state, notebook_id, |notebook| {
+        let redone = notebook.redo()?;
+        Ok(edited_by(notebook, redone.then(|| notebook.history().last_done()).flatten()))
+    })
+}
@@ -1,10 +1,41 @@
 use anyhow::{anyhow, Context, Result};
-use neuropad_ipc::{IpcEnvelope, IpcRequest};
-use serde_json::json;
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
+use neuropad_ipc::transport::{ChildListener, TOKEN_ENV};
+use neuropad_ipc::{
+    ClientError, ClientResult, CompleteParams, ExecuteParams, HelloParams, InspectParams, IpcError,
+    KernelAddress, KernelClient, KernelInfo, KernelRequest, KernelResponse, StreamName,
+};
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
 use std::path::PathBuf;
-use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use uuid::Uuid;
+
+/// How long a kernel gets to acknowledge `shutdown` before it is killed.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+/// A kernel that has not connected back, or not answered `hello`, by then is
+/// considered broken.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long output a cell printed may trail the kernel's reply, and the reply
+/// the end of the output.
+const OUTPUT_SETTLE: Duration = Duration::from_secs(2);
+
+/// Outer error: the kernel could not be reached. Inner error: the kernel's own
+/// answer, e.g. an exception raised by the cell.
+pub type KernelReply = Result<std::result::Result<KernelResponse, IpcError>>;
+
+/// Everything a cell produced: output the program wrote directly, in order,
+/// followed by the kernel's reply.
+pub struct Execution {
+    pub streams: Vec<(StreamName, String)>,
+    pub reply: std::result::Result<KernelResponse, IpcError>,
+}
+
+/// Receives each piece of output a running cell prints, as it is printed.
+pub type OutputSink = Box<dyn FnMut(StreamName, &str) + Send>;
 
 #[derive(Clone)]
 pub struct KernelLaunch {
@@ -12,74 +43,414 @@ pub struct KernelLaunch {
     pub args: Vec<String>,
 }
 
+/// Where a notebook's kernel comes from.
+#[derive(Clone)]
+enum KernelSource {
+    /// A bundled kernel, spawned as a child process that connects back to us.
+    Spawn(KernelLaunch),
+    /// A kernel someone started on their own, reached over a socket.
+    Attach(KernelAddress),
+}
+
+/// A running kernel. Requests may be issued from several threads at once;
+/// replies are matched to callers by request id.
 struct KernelProcess {
-    child: Child,
-    stdin: ChildStdin,
-    stdout: BufReader<ChildStdout>,
+    /// `None` for an attached kernel, whose lifetime is not ours to manage.
+    child: Option<Mutex<Child>>,
+    client: KernelClient,
+    info: KernelInfo,
 }
 
 impl KernelProcess {
+    /// Starts a bundled kernel. Protocol messages go over a private socket the
+    /// kernel connects back to, so everything on its stdout and stderr is
+    /// program output.
     fn spawn(launch: &KernelLaunch) -> Result<Self> {
+        let channel = ChildListener::bind().context("failed to open a channel for the kernel")?;
+        let token = Uuid::new_v4().simple().to_string();
         let mut cmd = Command::new(&launch.executable);
         if !launch.args.is_empty() {
             cmd.args(&launch.args);
         }
 
         let mut child = cmd
-            .stdin(Stdio::piped())
+            .arg("--connect")
+            .arg(channel.endpoint().to_string())
+            .env(TOKEN_ENV, &token)
+            .stdin(Stdio::null())
             .stdout(Stdio::piped())
-            .stderr(Stdio::null())
+            .stderr(Stdio::piped())
             .spawn()
             .with_context(|| format!("failed to spawn kernel process '{}'", launch.executable.display()))?;
-        let stdin = child
-            .stdin
-            .take()
-            .ok_or_else(|| anyhow!("kernel stdin unavailable"))?;
         let stdout = child
             .stdout
             .take()
             .ok_or_else(|| anyhow!("kernel stdout unavailable"))?;
+        let mut stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| anyhow!("kernel stderr unavailable"))?;
+        let connected = channel.accept(&token, HELLO_TIMEOUT, || matches!(child.try_wait(), Ok(None)));
+        let (reader, writer) = match connected {
+            Ok(halves) => halves,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                // Whatever the kernel printed before dying usually says why.
+                let mut printed = Vec::new();
+                let _ = stderr.by_ref().take(4096).read_to_end(&mut printed);
+                let printed = String::from_utf8_lossy(&printed);
+                let kernel = launch.executable.display();
+                let mut message = format!("kernel '{kernel}' did not connect: {err}");
+                if !printed.trim().is_empty() {
+                    message = format!("{message}\n{}", printed.trim());
+                }
+                return Err(anyhow!(message));
+            }
+        };
+        let client = KernelClient::new(reader, writer);
+        client.capture_output(stdout, StreamName::Stdout);
+        client.capture_output(stderr, StreamName::Stderr);
+        let info = match hello(&client, None) {
+            Ok(info) => info,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                let kernel = launch.executable.display();
+                return Err(err.context(format!("kernel '{kernel}' failed the handshake")));
+            }
+        };
+        Ok(Self {
+            child: Some(Mutex::new(child)),
+            client,
+            info,
+        })
+    }
+
+    /// Connects to a kernel that is already listening at `address`. Output it
+    /// writes outside the protocol stays on its own terminal.
+    fn attach(address: &KernelAddress) -> Result<Self> {
+        let endpoint = &address.endpoint;
+        let client = KernelClient::connect(endpoint)
+            .map_err(|err| anyhow!("failed to connect to kernel at {endpoint}: {err}"))?;
+        let info = hello(&client, address.token.clone())
+            .map_err(|err| anyhow!("kernel at {endpoint} failed the handshake: {err}"))?;
         Ok(Self {
-            child,
-            stdin,
-            stdout: BufReader::new(stdout),
+            child: None,
+            client,
+            info,
         })
     }
 
-    fn call(&mut self, request: &IpcRequest) -> Result<IpcEnvelope> {
-        let payload = serde_json::to_string(request)?;
-        writeln!(self.stdin, "{payload}")?;
-        self.stdin.flush()?;
+    fn start(source: &KernelSource) -> Result<Self> {
+        match source {
+            KernelSource::Spawn(launch) => Self::spawn(launch),
+            KernelSource::Attach(address) => Self::attach(address),
+        }
+    }
+
+    /// The outer error means the kernel could not be reached; the inner one is
+    /// the kernel's own answer.
+    fn call(&self, request: KernelRequest) -> KernelReply {
+        self.check_supported(&request)?;
+        split_reply(self.client.call(request))
+    }
+
+    /// Runs a cell and collects what it printed outside the protocol. The
+    /// kernel marks where each cell's output starts and ends on both streams;
+    /// `on_output` sees each piece while the cell runs.
+    fn execute(&self, code: String, on_output: OutputSink) -> Result<Execution> {
+        let request = KernelRequest::Execute(ExecuteParams { code });
+        self.check_supported(&request)?;
+        let (streams, reply) = self.client.call_with_output(request, OUTPUT_SETTLE, on_output);
+        let reply = split_reply(reply)?;
+        Ok(Execution { streams, reply })
+    }
 
-        let mut line = String::new();
-        self.stdout.read_line(&mut line)?;
-        if line.trim().is_empty() {
-            return Err(anyhow!("kernel returned empty response"));
+    fn check_supported(&self, request: &KernelRequest) -> Result<()> {
+        if self.info.supports(request.method()) {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "{} kernel does not support '{}'",
+            self.info.language,
+            request.method()
+        ))
+    }
+
+    fn is_alive(&self) -> bool {
+        !self.client.is_closed()
+    }
+
+    /// Stops a spawned kernel. An attached kernel keeps running; the
+    /// connection closes once the last request using it has finished.
+    fn shutdown(&self) {
+        let Some(child) = &self.child else {
+            return;
+        };
+        // Give the kernel a chance to clean up; it is killed either way.
+        if self.info.supports("shutdown") {
+            let _ = self.client.call_timeout(KernelRequest::Shutdown, SHUTDOWN_GRACE);
+        }
+        if let Ok(mut child) = child.lock() {
+            let _ = child.kill();
+            // Reap it, or every restart and closed notebook leaves a zombie.
+            let _ = child.wait();
         }
-        let envelope = serde_json::from_str::<IpcEnvelope>(line.trim())?;
-        Ok(envelope)
     }
 }
 
-pub struct NotebookRuntimes {
-    go: KernelProcess,
-    ruby: KernelProcess,
-    python: KernelProcess,
+fn split_reply(reply: ClientResult<KernelResponse>) -> KernelReply {
+    match reply {
+        Ok(response) => Ok(Ok(response)),
+        Err(ClientError::Kernel(err)) => Ok(Err(err)),
+        Err(err) => Err(err.into()),
+    }
 }
 
-impl NotebookRuntimes {
-    fn for_language_mut(&mut self, language: &str) -> Result<&mut KernelProcess> {
-        match language {
-            "go" => Ok(&mut self.go),
-            "ruby" => Ok(&mut self.ruby),
-            "python" => Ok(&mut self.python),
-            _ => Err(anyhow!("unsupported language '{language}'")),
+/// Asks a freshly connected kernel who it is and refuses it if it speaks
+/// another protocol version. Kernels predating the handshake answer
+/// `unknown_method`.
+fn hello(client: &KernelClient, token: Option<String>) -> Result<KernelInfo> {
+    let params = HelloParams {
+        token,
+        ..HelloParams::default()
+    };
+    let info = match client.call_timeout(KernelRequest::Hello(params), HELLO_TIMEOUT) {
+        Ok(KernelResponse::Hello(info)) => info,
+        Ok(other) => return Err(anyhow!("unexpected hello reply: {other:?}")),
+        Err(ClientError::Kernel(err)) if err.code == "unknown_method" => {
+            return Err(anyhow!("kernel predates the protocol handshake; update the bundled kernel"))
         }
+        Err(ClientError::Timeout { .. }) => return Err(anyhow!("kernel did not answer the handshake")),
+        Err(err) => return Err(err.into()),
+    };
+    info.check_compatible()?;
+    Ok(info)
+}
+
+enum KernelCommand {
+    Execute {
+        code: String,
+        on_output: OutputSink,
+        reply: oneshot::Sender<Result<Execution>>,
+    },
+    Request {
+        request: KernelRequest,
+        reply: oneshot::Sender<KernelReply>,
+    },
+    Info {
+        reply: oneshot::Sender<Result<KernelInfo>>,
+    },
+    Restart {
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+    /// The running execution finished; start the next queued one.
+    Executed,
+}
+
+/// A cell waiting for the one before it to finish.
+struct QueuedExecution {
+    code: String,
+    on_output: OutputSink,
+    reply: oneshot::Sender<Result<Execution>>,
+}
+
+/// Address of one kernel's actor task. Cheap to clone; every method only
+/// sends a message, so callers never block each other.
+#[derive(Clone)]
+pub struct KernelHandle {
+    tx: mpsc::UnboundedSender<KernelCommand>,
+}
+
+impl KernelHandle {
+    fn start(source: KernelSource) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tauri::async_runtime::spawn(run_kernel(source, tx.downgrade(), rx));
+        Self { tx }
+    }
+
+    async fn ask<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> KernelCommand) -> Result<T> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(command(reply))
+            .map_err(|_| anyhow!("kernel has shut down"))?;
+        rx.await.map_err(|_| anyhow!("kernel task stopped"))
     }
+
+    async fn request(&self, request: KernelRequest) -> KernelReply {
+        self.ask(|reply| KernelCommand::Request { request, reply }).await?
+    }
+
+    pub async fn execute(
+        &self,
+        code: &str,
+        on_output: impl FnMut(StreamName, &str) + Send + 'static,
+    ) -> Result<Execution> {
+        let code = code.to_string();
+        let on_output: OutputSink = Box::new(on_output);
+        self.ask(|reply| KernelCommand::Execute { code, on_output, reply }).await?
+    }
+
+    pub async fn interrupt(&self) -> Result<KernelResponse> {
+        Ok(self.request(KernelRequest::Interrupt).await??)
+    }
+
+    /// Replaces the kernel process with a fresh one, or reconnects to an
+    /// attached kernel. Executions still running in the old process fail with
+    /// a disconnect.
+    pub async fn restart(&self) -> Result<()> {
+        self.ask(|reply| KernelCommand::Restart { reply }).await?
+    }
+
+    pub async fn complete(&self, code: &str, cursor_pos: usize) -> Result<KernelResponse> {
+        let params = CompleteParams {
+            code: code.to_string(),
+            cursor_pos,
+        };
+        Ok(self.request(KernelRequest::Complete(params)).await??)
+    }
+
+    pub async fn inspect(&self, code: &str, cursor_pos: usize) -> Result<KernelResponse> {
+        let params = InspectParams {
+            code: code.to_string(),
+            cursor_pos,
+        };
+        Ok(self.request(KernelRequest::Inspect(params)).await??)
+    }
+
+    pub async fn info(&self) -> Result<KernelInfo> {
+        self.ask(|reply| KernelCommand::Info { reply }).await?
+    }
+
+    async fn shutdown(&self) {
+        let _ = self.ask(|reply| KernelCommand::Shutdown { reply }).await;
+    }
+}
+
+/// Owns one kernel process or connection. Cells run one at a time, in the
+/// order they were sent, so each cell's output is its own. Other requests are
+/// handed to blocking workers as soon as they arrive, so an interrupt or
+/// completion never queues behind an execute; only (re)spawning the process
+/// holds up this kernel's mailbox.
+async fn run_kernel(
+    source: KernelSource,
+    mailbox: mpsc::WeakUnboundedSender<KernelCommand>,
+    mut rx: mpsc::UnboundedReceiver<KernelCommand>,
+) {
+    let mut process: Option<Arc<KernelProcess>> = None;
+    let mut queued: VecDeque<QueuedExecution> = VecDeque::new();
+    let mut executing = false;
+    while let Some(command) = rx.recv().await {
+        match command {
+            KernelCommand::Execute { code, on_output, reply } => {
+                queued.push_back(QueuedExecution { code, on_output, reply });
+                if !executing {
+                    executing = start_execution(&mut queued, &mut process, &source, &mailbox).await;
+                }
+            }
+            KernelCommand::Executed => {
+                executing = start_execution(&mut queued, &mut process, &source, &mailbox).await;
+            }
+            // Interrupting a kernel that is not running has nothing to stop;
+            // starting one just to interrupt it would hold up the mailbox.
+            KernelCommand::Request {
+                request: KernelRequest::Interrupt,
+                reply,
+            } if live_process(&process).is_none() => {
+                let _ = reply.send(Err(anyhow!("kernel not running")));
+            }
+            KernelCommand::Request { request, reply } => match ensure_process(&mut process, &source).await {
+                Ok(kernel) => {
+                    task::spawn_blocking(move || {
+                        let _ = reply.send(kernel.call(request));
+                    });
+                }
+                Err(err) => {
+                    let _ = reply.send(Err(err));
+                }
+            },
+            KernelCommand::Info { reply } => {
+                let info = ensure_process(&mut process, &source)
+                    .await
+                    .map(|kernel| kernel.info.clone());
+                let _ = reply.send(info);
+            }
+            KernelCommand::Restart { reply } => {
+                stop_process(process.take()).await;
+                let _ = reply.send(ensure_process(&mut process, &source).await.map(|_| ()));
+            }
+            KernelCommand::Shutdown { reply } => {
+                stop_process(process.take()).await;
+                let _ = reply.send(());
+                break;
+            }
+        }
+    }
+    stop_process(process).await;
+}
+
+/// Hands the next queued cell to a blocking worker, which reports back with
+/// [`KernelCommand::Executed`]. Returns whether one is running now.
+async fn start_execution(
+    queued: &mut VecDeque<QueuedExecution>,
+    process: &mut Option<Arc<KernelProcess>>,
+    source: &KernelSource,
+    mailbox: &mpsc::WeakUnboundedSender<KernelCommand>,
+) -> bool {
+    while let Some(QueuedExecution { code, on_output, reply }) = queued.pop_front() {
+        match ensure_process(process, source).await {
+            Ok(kernel) => {
+                let mailbox = mailbox.clone();
+                task::spawn_blocking(move || {
+                    let _ = reply.send(kernel.execute(code, on_output));
+                    if let Some(mailbox) = mailbox.upgrade() {
+                        let _ = mailbox.send(KernelCommand::Executed);
+                    }
+                });
+                return true;
+            }
+            Err(err) => {
+                let _ = reply.send(Err(err));
+            }
+        }
+    }
+    false
 }
 
+/// The running process, spawning (or reconnecting) a new one if there is none
+/// or it went away.
+async fn ensure_process(
+    process: &mut Option<Arc<KernelProcess>>,
+    source: &KernelSource,
+) -> Result<Arc<KernelProcess>> {
+    if let Some(kernel) = live_process(process) {
+        return Ok(kernel);
+    }
+    stop_process(process.take()).await;
+    let source = source.clone();
+    let kernel = Arc::new(task::spawn_blocking(move || KernelProcess::start(&source)).await??);
+    *process = Some(Arc::clone(&kernel));
+    Ok(kernel)
+}
+
+fn live_process(process: &Option<Arc<KernelProcess>>) -> Option<Arc<KernelProcess>> {
+    process.as_ref().filter(|kernel| kernel.is_alive()).cloned()
+}
+
+async fn stop_process(process: Option<Arc<KernelProcess>>) {
+    if let Some(kernel) = process {
+        let _ = task::spawn_blocking(move || kernel.shutdown()).await;
+    }
+}
+
+/// Hands out one actor per notebook and language. The map is only locked
+/// for lookups, never while a kernel is doing work.
 pub struct KernelManager {
-    notebooks: HashMap<String, NotebookRuntimes>,
+    kernels: Mutex<HashMap<(String, String), KernelHandle>>,
     go_kernel: KernelLaunch,
     ruby_kernel: KernelLaunch,
     python_kernel: KernelLaunch,
@@ -88,64 +459,90 @@ pub struct KernelManager {
 impl KernelManager {
     pub fn new(go_kernel: KernelLaunch, ruby_kernel: KernelLaunch, python_kernel: KernelLaunch) -> Self {
         Self {
-            notebooks: HashMap::new(),
+            kernels: Mutex::new(HashMap::new()),
             go_kernel,
             ruby_kernel,
             python_kernel,
         }
     }
 
-    fn ensure_notebook(&mut self, notebook_id: &str) -> Result<&mut NotebookRuntimes> {
-        if !self.notebooks.contains_key(notebook_id) {
-            let go = KernelProcess::spawn(&self.go_kernel)?;
-            let ruby = KernelProcess::spawn(&self.ruby_kernel)?;
-            let python = KernelProcess::spawn(&self.python_kernel)?;
-            self.notebooks
-                .insert(notebook_id.to_string(), NotebookRuntimes { go, ruby, python });
+    fn launch_for(&self, language: &str) -> Result<&KernelLaunch> {
+        match language {
+            "go" => Ok(&self.go_kernel),
+            "ruby" => Ok(&self.ruby_kernel),
+            "python" => Ok(&self.python_kernel),
+            _ => Err(anyhow!("unsupported language '{language}'")),
         }
-        self.notebooks
-            .get_mut(notebook_id)
-            .ok_or_else(|| anyhow!("notebook runtime not found"))
-    }
-
-    pub fn execute(&mut self, notebook_id: &str, language: &str, code: &str) -> Result<IpcEnvelope> {
-        let runtime = self.ensure_notebook(notebook_id)?;
-        let kernel = runtime.for_language_mut(language)?;
-        let req = IpcRequest {
-            id: uuid::Uuid::new_v4().to_string(),
-            method: "execute".to_string(),
-            params: json!({ "code": code }),
-        };
-        kernel.call(&req)
     }
 
-    pub fn interrupt(&mut self, notebook_id: &str, language: &str) -> Result<IpcEnvelope> {
-        let runtime = self.ensure_notebook(notebook_id)?;
-        let kernel = runtime.for_language_mut(language)?;
-        let req = IpcRequest {
-            id: uuid::Uuid::new_v4().to_string(),
-            method: "interrupt".to_string(),
-            params: json!({}),
+    /// The notebook's kernel for `language`. The process itself starts on the
+    /// first request sent to it.
+    pub fn kernel(&self, notebook_id: &str, language: &str) -> Result<KernelHandle> {
+        let launch = self.launch_for(language)?;
+        let mut kernels = self
+            .kernels
+            .lock()
+            .map_err(|_| anyhow!("kernel registry lock poisoned"))?;
+        let handle = kernels
+            .entry((notebook_id.to_string(), language.to_string()))
+            .or_insert_with(|| KernelHandle::start(KernelSource::Spawn(launch.clone())));
+        Ok(handle.clone())
+    }
+
+    /// Uses the kernel listening at `address` as the notebook's kernel for
+    /// `language`, replacing the one it had. Fails, leaving the current kernel
+    /// in place, if nothing answers there or it runs another language.
+    pub async fn attach(&self, notebook_id: &str, language: &str, address: KernelAddress) -> Result<KernelInfo> {
+        self.launch_for(language)?;
+        let handle = KernelHandle::start(KernelSource::Attach(address));
+        let info = match handle.info().await {
+            Ok(info) if info.language == language => info,
+            Ok(info) => {
+                handle.shutdown().await;
+                return Err(anyhow!("attached kernel runs {}, not {language}", info.language));
+            }
+            Err(err) => {
+                handle.shutdown().await;
+                return Err(err);
+            }
         };
-        kernel.call(&req)
+        let previous = self
+            .kernels
+            .lock()
+            .map_err(|_| anyhow!("kernel registry lock poisoned"))?
+            .insert((notebook_id.to_string(), language.to_string()), handle);
+        if let Some(previous) = previous {
+            previous.shutdown().await;
+        }
+        Ok(info)
     }
 
-    pub fn restart(&mut self, notebook_id: &str, language: &str) -> Result<IpcEnvelope> {
-        let runtime = self.ensure_notebook(notebook_id)?;
-        let kernel = runtime.for_language_mut(language)?;
-        let req = IpcRequest {
-            id: uuid::Uuid::new_v4().to_string(),
-            method: "restart".to_string(),
-            params: json!({}),
+    /// Disconnects or stops the notebook's kernel for `language`; the next
+    /// request starts the bundled kernel again.
+    pub async fn detach(&self, notebook_id: &str, language: &str) {
+        let handle = match self.kernels.lock() {
+            Ok(mut kernels) => kernels.remove(&(notebook_id.to_string(), language.to_string())),
+            Err(_) => return,
         };
-        kernel.call(&req)
+        if let Some(handle) = handle {
+            handle.shutdown().await;
+        }
     }
 
-    pub fn shutdown_notebook(&mut self, notebook_id: &str) {
-        if let Some(mut rt) = self.notebooks.remove(notebook_id) {
-            let _ = rt.go.child.kill();
-            let _ = rt.ruby.child.kill();
-            let _ = rt.python.child.kill();
+    pub async fn shutdown_notebook(&self, notebook_id: &str) {
+        let handles = match self.kernels.lock() {
+            Ok(mut kernels) => {
+                let keys = kernels
+                    .keys()
+                    .filter(|(notebook, _)| notebook == notebook_id)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                keys.into_iter().filter_map(|key| kernels.remove(&key)).collect::<Vec<_>>()
+            }
+            Err(_) => return,
+        };
+        for handle in handles {
+            handle.shutdown().await;
         }
     }
 }
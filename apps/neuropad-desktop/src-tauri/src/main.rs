@@ -1,20 +1,42 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod autosave;
+mod cell_edit;
 mod kernel_manager;
+mod open_notebooks;
+mod workspaces;
 
+use autosave::AutosaveQueue;
 use chrono::Utc;
-use kernel_manager::{KernelLaunch, KernelManager};
+use cell_edit::{edited_by, EditResult};
+use kernel_manager::{KernelHandle, KernelLaunch, KernelManager};
+use neuropad_core::diff::{diff_notebooks, NotebookDiff};
 use neuropad_core::ipynb;
-use neuropad_core::{Cell, CellOutput, CellOutputKind, CellStatus, MetadataStore, Notebook};
+use neuropad_core::paths;
+use neuropad_core::strip;
+use neuropad_core::{
+    CanonicalOptions, Cell, CellExecution, CellOutput, CellOutputKind, CellRun, CellRunRecord, CellSearchHit,
+    CellStatus, CellTag, CellType, FileStamp, IndexQuery, IndexedNotebook, LazyCell, LazyNotebook, MetadataStore,
+    Notebook, NotebookVersion, RecentNotebook, RecoveryJournal, RecoverySession,
+    SaveOptions, SchemaViolation, StripOptions, WorkspaceWatcher,
+};
+use neuropad_ipc::{KernelAddress, KernelInfo, KernelResponse, StreamName};
+use open_notebooks::{OpenNotebooks, OpenedLazyNotebook, OpenedNotebook, Pages};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use tauri::{Manager, State};
+use tauri::{AppHandle, Emitter, Manager, State};
 use uuid::Uuid;
 
 struct AppState {
-    kernels: Mutex<KernelManager>,
+    kernels: KernelManager,
+    notebooks: OpenNotebooks,
     metadata: Mutex<MetadataStore>,
+    file_stamps: Mutex<HashMap<String, FileStamp>>,
+    journal: RecoveryJournal,
+    autosave: AutosaveQueue,
+    workspace_watcher: Mutex<Option<WorkspaceWatcher>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -27,6 +49,54 @@ struct Ack {
     ok: bool,
 }
 
+/// A cell in a page: everything but its outputs, which the UI shows as a
+/// placeholder until it fetches them.
+#[derive(Debug, Serialize)]
+struct CellRow<'a> {
+    id: Uuid,
+    #[serde(rename = "type")]
+    cell_type: &'a CellType,
+    language: &'a Option<String>,
+    source: &'a str,
+    execution: &'a CellExecution,
+    tags: &'a BTreeSet<CellTag>,
+    metadata: &'a serde_json::Map<String, serde_json::Value>,
+    has_outputs: bool,
+    outputs_size: u64,
+}
+
+impl<'a> From<&'a LazyCell> for CellRow<'a> {
+    fn from(cell: &'a LazyCell) -> Self {
+        Self {
+            id: cell.id,
+            cell_type: &cell.cell_type,
+            language: &cell.language,
+            source: &cell.source,
+            execution: &cell.execution,
+            tags: &cell.tags,
+            metadata: &cell.metadata,
+            has_outputs: cell.has_outputs(),
+            outputs_size: cell.outputs_size(),
+        }
+    }
+}
+
+impl<'a> From<&'a Cell> for CellRow<'a> {
+    fn from(cell: &'a Cell) -> Self {
+        Self {
+            id: cell.id,
+            cell_type: &cell.cell_type,
+            language: &cell.language,
+            source: &cell.source,
+            execution: &cell.execution,
+            tags: &cell.tags,
+            metadata: &cell.metadata,
+            has_outputs: !cell.outputs.is_empty(),
+            outputs_size: serde_json::to_string(&cell.outputs).map_or(0, |json| json.len() as u64),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ExecutionTicket {
     notebook_id: String,
@@ -34,112 +104,666 @@ struct ExecutionTicket {
     status: String,
 }
 
+/// Payload of the `cell_stream` event: a piece of output a running cell just
+/// printed.
+#[derive(Debug, Clone, Serialize)]
+struct CellStream {
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    kind: CellOutputKind,
+    text: String,
+}
+
+fn stream_kind(name: StreamName) -> CellOutputKind {
+    match name {
+        StreamName::Stdout => CellOutputKind::Stdout,
+        StreamName::Stderr => CellOutputKind::Stderr,
+    }
+}
+
+fn is_npadz(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".npadz")
+}
+
+fn load_notebook(path: &str) -> Result<Notebook, String> {
+    if is_npadz(path) {
+        Notebook::load_npadz(path)
+    } else {
+        Notebook::load_npad(path)
+    }
+    .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
-fn notebook_new(title: String) -> Result<Notebook, String> {
-    Ok(Notebook::new(&title))
+fn notebook_new(title: String, state: State<AppState>) -> Result<OpenedNotebook, String> {
+    state.notebooks.open(Notebook::new(&title), None)
 }
 
 #[tauri::command]
-fn notebook_open(path: String, state: State<AppState>) -> Result<Notebook, String> {
-    let notebook = Notebook::load_npad(&path).map_err(|e| e.to_string())?;
+fn notebook_open(path: String, state: State<AppState>) -> Result<OpenedNotebook, String> {
+    let (notebook, stamp) = if is_npadz(&path) {
+        let notebook = Notebook::load_npadz(&path).map_err(|e| e.to_string())?;
+        let stamp = FileStamp::of_file(&path)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("{path} disappeared while loading"))?;
+        (notebook, stamp)
+    } else {
+        Notebook::load_npad_stamped(&path).map_err(|e| e.to_string())?
+    };
+    state
+        .file_stamps
+        .lock()
+        .map_err(|_| "file stamp lock poisoned".to_string())?
+        .insert(path.clone(), stamp);
     state
         .metadata
         .lock()
         .map_err(|_| "metadata lock poisoned".to_string())?
         .mark_recent_open(&path, &Utc::now().to_rfc3339())
         .map_err(|e| e.to_string())?;
-    Ok(notebook)
+    state.notebooks.open(notebook, Some(path))
+}
+
+/// Forgets an open notebook and stops its kernels. Unsaved changes are lost,
+/// including their recovery snapshot.
+#[tauri::command]
+async fn notebook_close(notebook_id: Uuid, state: State<'_, AppState>) -> Result<Ack, String> {
+    state.notebooks.close(notebook_id)?;
+    state
+        .autosave
+        .discard(notebook_id, &state.journal, &state.metadata)
+        .map_err(|e| e.to_string())?;
+    state.kernels.shutdown_notebook(&notebook_id.to_string()).await;
+    Ok(Ack { ok: true })
 }
 
+/// Saves an open notebook to `path`, or to where it was opened from or last
+/// saved to, and drops its recovery snapshot.
 #[tauri::command]
-fn notebook_save(path: String, mut notebook: Notebook, state: State<AppState>) -> Result<SaveResult, String> {
-    notebook.save_npad(&path).map_err(|e| e.to_string())?;
+fn notebook_save(
+    notebook_id: Uuid,
+    path: Option<String>,
+    force: Option<bool>,
+    canonical: Option<CanonicalOptions>,
+    state: State<AppState>,
+) -> Result<SaveResult, String> {
+    save_open(notebook_id, path, force, canonical, &state)
+}
+
+fn save_open(
+    notebook_id: Uuid,
+    path: Option<String>,
+    force: Option<bool>,
+    canonical: Option<CanonicalOptions>,
+    state: &AppState,
+) -> Result<SaveResult, String> {
+    let path = state.notebooks.with(notebook_id, |open| {
+        let path = path
+            .or_else(|| open.path.clone())
+            .ok_or_else(|| "notebook has never been saved; choose a path".to_string())?;
+        save_notebook(&path, &mut open.notebook, force, canonical, state)?;
+        open.path = Some(path.clone());
+        open.dirty = false;
+        Ok(path)
+    })?;
+    state
+        .autosave
+        .discard(notebook_id, &state.journal, &state.metadata)
+        .map_err(|e| e.to_string())?;
+    Ok(SaveResult { path })
+}
+
+fn save_notebook(
+    path: &str,
+    notebook: &mut Notebook,
+    force: Option<bool>,
+    canonical: Option<CanonicalOptions>,
+    state: &AppState,
+) -> Result<(), String> {
+    let expected = if force.unwrap_or(false) {
+        None
+    } else {
+        state
+            .file_stamps
+            .lock()
+            .map_err(|_| "file stamp lock poisoned".to_string())?
+            .get(path)
+            .cloned()
+    };
+    let options = SaveOptions {
+        backup: true,
+        expected,
+        canonical,
+    };
+    let stamp = if is_npadz(path) {
+        notebook.save_npadz_with(path, &options)
+    } else {
+        notebook.save_npad_with(path, &options)
+    }
+    .map_err(|e| e.to_string())?;
     state
+        .file_stamps
+        .lock()
+        .map_err(|_| "file stamp lock poisoned".to_string())?
+        .insert(path.to_string(), stamp);
+    let metadata = state
         .metadata
         .lock()
-        .map_err(|_| "metadata lock poisoned".to_string())?
+        .map_err(|_| "metadata lock poisoned".to_string())?;
+    metadata
         .upsert_notebook_index(
-            &path,
+            path,
             &notebook.metadata.title,
             &notebook.metadata.updated_at.to_rfc3339(),
         )
         .map_err(|e| e.to_string())?;
-    Ok(SaveResult { path })
+    metadata
+        .index_notebook_cells(path, notebook)
+        .map_err(|e| e.to_string())?;
+    // The file is saved; a missing version is not worth failing over.
+    if let Err(err) = metadata.snapshot_notebook(path, notebook, Utc::now()) {
+        log::warn!("recording a version of {path} failed: {err}");
+    }
+    Ok(())
 }
 
 #[tauri::command]
-fn cell_execute(
-    notebook_id: String,
-    cell_id: String,
-    language: String,
-    code: String,
+fn notebook_search(
+    query: String,
+    limit: Option<usize>,
     state: State<AppState>,
-) -> Result<(ExecutionTicket, Vec<CellOutput>), String> {
-    let started = std::time::Instant::now();
-    let envelope = state
-        .kernels
+) -> Result<Vec<CellSearchHit>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .search_cells(&query, limit.unwrap_or(50))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn notebook_recent(limit: Option<usize>, state: State<AppState>) -> Result<Vec<RecentNotebook>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .list_recent(limit.unwrap_or(20))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn notebook_library(
+    query: Option<IndexQuery>,
+    state: State<AppState>,
+) -> Result<Vec<IndexedNotebook>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .list_notebook_index(&query.unwrap_or_default())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn notebook_forget(path: String, state: State<AppState>) -> Result<Ack, String> {
+    state
+        .metadata
         .lock()
-        .map_err(|_| "kernel lock poisoned".to_string())?
-        .execute(&notebook_id, &language, &code)
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .remove_notebook(&path)
         .map_err(|e| e.to_string())?;
+    Ok(Ack { ok: true })
+}
 
-    let mut outputs = vec![];
-    if let Some(err) = envelope.error {
-        outputs.push(CellOutput {
-            kind: CellOutputKind::Error,
-            mime: "text/plain".to_string(),
-            data: format!("{}: {}", err.code, err.message),
-            created_at: Utc::now(),
-        });
-    } else if let Some(result) = envelope.result {
-        outputs.push(CellOutput {
-            kind: CellOutputKind::Result,
+#[tauri::command]
+fn notebook_prune_missing(state: State<AppState>) -> Result<Vec<String>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .prune_missing()
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn notebook_versions(path: String, state: State<AppState>) -> Result<Vec<NotebookVersion>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .list_versions(&path)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn notebook_version_diff(
+    version_id: i64,
+    notebook_id: Uuid,
+    state: State<AppState>,
+) -> Result<NotebookDiff, String> {
+    state.notebooks.with(notebook_id, |open| {
+        state
+            .metadata
+            .lock()
+            .map_err(|_| "metadata lock poisoned".to_string())?
+            .diff_version(version_id, &open.notebook)
+            .map_err(|e| e.to_string())
+    })
+}
+
+#[tauri::command]
+fn notebook_version_restore(
+    version_id: i64,
+    cell_id: Option<Uuid>,
+    notebook_id: Uuid,
+    state: State<AppState>,
+) -> Result<EditResult, String> {
+    state.notebooks.edit(notebook_id, |open| {
+        let metadata = state
+            .metadata
+            .lock()
+            .map_err(|_| "metadata lock poisoned".to_string())?;
+        let notebook = &mut open.notebook;
+        match cell_id {
+            Some(cell_id) => metadata.restore_version_cell(version_id, cell_id, notebook),
+            None => metadata.restore_version(version_id, notebook),
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(edited_by(notebook, notebook.history().last_done()))
+    })
+}
+
+#[tauri::command]
+fn notebook_recover(state: State<AppState>) -> Result<Vec<RecoverySession>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .list_recovery_sessions()
+        .map_err(|e| e.to_string())
+}
+
+/// Reopens a recovered notebook under its session id and original path. It
+/// stays unsaved, and its snapshot is kept until it is saved or closed.
+#[tauri::command]
+fn notebook_recover_restore(session_id: Uuid, state: State<AppState>) -> Result<OpenedNotebook, String> {
+    let session = state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .list_recovery_sessions()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|session| session.session_id == session_id.to_string())
+        .ok_or_else(|| format!("recovery session {session_id} not found"))?;
+    let notebook = state
+        .journal
+        .read_snapshot(session_id)
+        .map_err(|e| e.to_string())?;
+    if let Some(path) = &session.notebook_path {
+        // Saving over the file is what the user asked for by restoring.
+        let stamp = FileStamp::of_file(path).map_err(|e| e.to_string())?;
+        let mut stamps = state
+            .file_stamps
+            .lock()
+            .map_err(|_| "file stamp lock poisoned".to_string())?;
+        match stamp {
+            Some(stamp) => stamps.insert(path.clone(), stamp),
+            None => stamps.remove(path),
+        };
+    }
+    state
+        .notebooks
+        .open_as(session_id, notebook, session.notebook_path, true)
+}
+
+#[tauri::command]
+fn notebook_recover_discard(session_id: Uuid, state: State<AppState>) -> Result<Ack, String> {
+    state
+        .autosave
+        .discard(session_id, &state.journal, &state.metadata)
+        .map_err(|e| e.to_string())?;
+    Ok(Ack { ok: true })
+}
+
+/// Opens a large `.npad` for paging: cells arrive a page at a time and
+/// outputs on request. Editing, running or saving it loads it in full.
+#[tauri::command]
+fn notebook_open_lazy(path: String, state: State<AppState>) -> Result<OpenedLazyNotebook, String> {
+    if is_npadz(&path) {
+        return Err("only .npad notebooks can be opened for paging".to_string());
+    }
+    let (notebook, stamp) = LazyNotebook::open_stamped(&path).map_err(|e| e.to_string())?;
+    state
+        .file_stamps
+        .lock()
+        .map_err(|_| "file stamp lock poisoned".to_string())?
+        .insert(path.clone(), stamp.clone());
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .mark_recent_open(&path, &Utc::now().to_rfc3339())
+        .map_err(|e| e.to_string())?;
+    state.notebooks.open_lazy(notebook, stamp, path)
+}
+
+#[tauri::command]
+fn notebook_cells_page(
+    notebook_id: Uuid,
+    offset: usize,
+    limit: usize,
+    state: State<AppState>,
+) -> Result<serde_json::Value, String> {
+    state.notebooks.with_pages(notebook_id, |pages| {
+        let rows = match pages {
+            Pages::Lazy(notebook) => notebook.page(offset, limit).iter().map(CellRow::from).collect::<Vec<_>>(),
+            Pages::Loaded(notebook) => notebook
+                .cells
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(CellRow::from)
+                .collect(),
+        };
+        serde_json::to_value(rows).map_err(|e| e.to_string())
+    })
+}
+
+#[tauri::command]
+fn notebook_cell_outputs(notebook_id: Uuid, index: usize, state: State<AppState>) -> Result<Vec<CellOutput>, String> {
+    state.notebooks.with_pages(notebook_id, |pages| match pages {
+        Pages::Lazy(notebook) => notebook.outputs(index).map_err(|e| e.to_string()),
+        Pages::Loaded(notebook) => notebook
+            .cells
+            .get(index)
+            .map(|cell| cell.outputs.clone())
+            .ok_or_else(|| format!("cell index {index} out of range")),
+    })
+}
+
+#[tauri::command]
+fn notebook_diff(old_path: String, new_path: String) -> Result<NotebookDiff, String> {
+    Ok(diff_notebooks(&load_notebook(&old_path)?, &load_notebook(&new_path)?))
+}
+
+#[tauri::command]
+fn notebook_diff_text(old_path: String, new_path: String) -> Result<String, String> {
+    Ok(diff_notebooks(&load_notebook(&old_path)?, &load_notebook(&new_path)?).to_string())
+}
+
+#[tauri::command]
+fn notebook_validate(path: String) -> Result<Vec<SchemaViolation>, String> {
+    Notebook::check_schema(&path).map_err(|e| e.to_string())
+}
+
+/// Writes a copy of an open notebook without its outputs to `path`, e.g. to
+/// share or commit it. The open notebook and its file keep their outputs.
+#[tauri::command]
+fn notebook_strip(
+    path: String,
+    notebook_id: Uuid,
+    options: StripOptions,
+    state: State<AppState>,
+) -> Result<SaveResult, String> {
+    if is_npadz(&path) || strip::is_ipynb(Path::new(&path)) {
+        return Err("stripped copies are written as .npad".to_string());
+    }
+    state.notebooks.with(notebook_id, |open| {
+        if open.path.as_deref() == Some(path.as_str()) {
+            return Err("choose another path; stripping the notebook's own file would discard its outputs".to_string());
+        }
+        strip::write_stripped(&open.notebook, &path, &options).map_err(|e| e.to_string())
+    })?;
+    Ok(SaveResult { path })
+}
+
+fn kernel_for(state: &AppState, notebook_id: &str, language: &str) -> Result<KernelHandle, String> {
+    state.kernels.kernel(notebook_id, language).map_err(|e| e.to_string())
+}
+
+/// Runs a cell of an open notebook in the notebook's kernel for its language
+/// and stores the outputs in the cell. What the cell prints is also sent as
+/// `cell_stream` events while it runs.
+#[tauri::command]
+async fn cell_execute(
+    notebook_id: Uuid,
+    cell_id: Uuid,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(ExecutionTicket, Vec<CellOutput>), String> {
+    let (notebook_path, language, code) = state.notebooks.with(notebook_id, |open| {
+        let cell = open
+            .notebook
+            .cell(cell_id)
+            .ok_or_else(|| format!("cell {cell_id} not found"))?;
+        let language = cell
+            .language
+            .clone()
+            .ok_or_else(|| format!("cell {cell_id} is not a code cell"))?;
+        Ok((open.path.clone(), language, cell.source.clone()))
+    })?;
+    let kernel_key = notebook_id.to_string();
+    let started_at = Utc::now();
+    let execution = kernel_for(&state, &kernel_key, &language)?
+        .execute(&code, move |name, text| {
+            let event = CellStream {
+                notebook_id,
+                cell_id,
+                kind: stream_kind(name),
+                text: text.to_string(),
+            };
+            if let Err(err) = app.emit("cell_stream", event) {
+                log::warn!("failed to send cell output: {err}");
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut outputs = execution
+        .streams
+        .into_iter()
+        .map(|(name, text)| CellOutput {
+            kind: stream_kind(name),
             mime: "text/plain".to_string(),
-            data: result.to_string(),
+            data: text,
             created_at: Utc::now(),
-        });
+        })
+        .collect::<Vec<_>>();
+    let mut status = CellStatus::Ok;
+    match execution.reply {
+        Err(err) => {
+            status = CellStatus::Error;
+            outputs.push(CellOutput {
+                kind: CellOutputKind::Error,
+                mime: "text/plain".to_string(),
+                data: err.to_string(),
+                created_at: Utc::now(),
+            });
+        }
+        Ok(KernelResponse::Execute { data }) => {
+            outputs.extend(data.into_iter().map(|(mime, data)| CellOutput {
+                kind: CellOutputKind::Result,
+                mime,
+                data,
+                created_at: Utc::now(),
+            }));
+        }
+        Ok(_) => {}
+    }
+    let finished_at = Utc::now();
+
+    state.notebooks.edit(notebook_id, |open| {
+        // The cell may have been deleted while it ran.
+        let Some(count) = open.notebook.cell(cell_id).map(|cell| cell.execution.count) else {
+            return Ok(());
+        };
+        let execution = CellExecution {
+            count: count + 1,
+            status: status.clone(),
+            duration_ms: (finished_at - started_at).num_milliseconds().max(0) as u64,
+        };
+        open.notebook
+            .record_run(cell_id, outputs.clone(), execution)
+            .map_err(|e| e.to_string())
+    })?;
+
+    let cell_id = cell_id.to_string();
+    // Run history is kept per file, so runs of a notebook never saved are not
+    // recorded. Failing to record one does not fail the run.
+    if let Some(notebook_path) = &notebook_path {
+        let recorded = state
+            .metadata
+            .lock()
+            .map_err(|_| "metadata lock poisoned".to_string())
+            .and_then(|metadata| {
+                metadata
+                    .record_cell_run(&CellRunRecord {
+                        notebook_path,
+                        cell_id: &cell_id,
+                        language: &language,
+                        source: &code,
+                        started_at,
+                        finished_at,
+                        status: status.clone(),
+                        outputs: &outputs,
+                    })
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(err) = recorded {
+            log::warn!("recording a run of cell {cell_id} in {notebook_path} failed: {err}");
+        }
     }
 
     let ticket = ExecutionTicket {
-        notebook_id,
+        notebook_id: kernel_key,
         cell_id,
-        status: "ok".to_string(),
+        status: status.as_str().to_string(),
     };
-
-    let _duration = started.elapsed();
     Ok((ticket, outputs))
 }
 
 #[tauri::command]
-fn kernel_interrupt(notebook_id: String, language: String, state: State<AppState>) -> Result<Ack, String> {
+fn cell_run_history(
+    notebook_path: String,
+    cell_id: String,
+    limit: Option<usize>,
+    state: State<AppState>,
+) -> Result<Vec<CellRun>, String> {
     state
-        .kernels
+        .metadata
         .lock()
-        .map_err(|_| "kernel lock poisoned".to_string())?
-        .interrupt(&notebook_id, &language)
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .list_cell_runs(&notebook_path, &cell_id, limit.unwrap_or(20))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn cell_run_restore(notebook_id: Uuid, run_id: i64, state: State<AppState>) -> Result<EditResult, String> {
+    state.notebooks.edit(notebook_id, |open| {
+        let notebook = &mut open.notebook;
+        state
+            .metadata
+            .lock()
+            .map_err(|_| "metadata lock poisoned".to_string())?
+            .restore_cell_run(notebook, run_id)
+            .map_err(|e| e.to_string())?;
+        Ok(edited_by(notebook, notebook.history().last_done()))
+    })
+}
+
+#[tauri::command]
+async fn kernel_interrupt(notebook_id: String, language: String, state: State<'_, AppState>) -> Result<Ack, String> {
+    kernel_for(&state, &notebook_id, &language)?
+        .interrupt()
+        .await
         .map_err(|e| e.to_string())?;
     Ok(Ack { ok: true })
 }
 
 #[tauri::command]
-fn kernel_restart(notebook_id: String, language: String, state: State<AppState>) -> Result<Ack, String> {
+async fn kernel_restart(notebook_id: String, language: String, state: State<'_, AppState>) -> Result<Ack, String> {
+    kernel_for(&state, &notebook_id, &language)?
+        .restart()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Ack { ok: true })
+}
+
+#[tauri::command]
+async fn kernel_info(
+    notebook_id: String,
+    language: String,
+    state: State<'_, AppState>,
+) -> Result<KernelInfo, String> {
+    kernel_for(&state, &notebook_id, &language)?
+        .info()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Connects the notebook to a kernel started outside NeuroPad, e.g.
+/// `tcp://127.0.0.1:9000?token=...` or `unix:///run/neuropad/python.sock`.
+#[tauri::command]
+async fn kernel_attach(
+    notebook_id: String,
+    language: String,
+    address: String,
+    state: State<'_, AppState>,
+) -> Result<KernelInfo, String> {
+    let address = address.parse::<KernelAddress>().map_err(|e| e.to_string())?;
     state
         .kernels
-        .lock()
-        .map_err(|_| "kernel lock poisoned".to_string())?
-        .restart(&notebook_id, &language)
-        .map_err(|e| e.to_string())?;
+        .attach(&notebook_id, &language, address)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn kernel_detach(notebook_id: String, language: String, state: State<'_, AppState>) -> Result<Ack, String> {
+    state.kernels.detach(&notebook_id, &language).await;
     Ok(Ack { ok: true })
 }
 
 #[tauri::command]
-fn import_ipynb(path: String) -> Result<Notebook, String> {
-    ipynb::import_ipynb(path).map_err(|e| e.to_string())
+async fn kernel_complete(
+    notebook_id: String,
+    language: String,
+    code: String,
+    cursor_pos: usize,
+    state: State<'_, AppState>,
+) -> Result<KernelResponse, String> {
+    kernel_for(&state, &notebook_id, &language)?
+        .complete(&code, cursor_pos)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn kernel_inspect(
+    notebook_id: String,
+    language: String,
+    code: String,
+    cursor_pos: usize,
+    state: State<'_, AppState>,
+) -> Result<KernelResponse, String> {
+    kernel_for(&state, &notebook_id, &language)?
+        .inspect(&code, cursor_pos)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn import_ipynb(path: String, state: State<AppState>) -> Result<OpenedNotebook, String> {
+    let notebook = ipynb::import_ipynb(path).map_err(|e| e.to_string())?;
+    state.notebooks.open(notebook, None)
 }
 
 #[tauri::command]
-fn export_ipynb(path: String, notebook: Notebook) -> Result<SaveResult, String> {
-    ipynb::export_ipynb(&notebook, &path).map_err(|e| e.to_string())?;
+fn export_ipynb(path: String, notebook_id: Uuid, state: State<AppState>) -> Result<SaveResult, String> {
+    state.notebooks.with(notebook_id, |open| {
+        ipynb::export_ipynb(&open.notebook, &path).map_err(|e| e.to_string())
+    })?;
     Ok(SaveResult { path })
 }
 
@@ -220,6 +844,7 @@ fn pick_ruby_launch(app: &tauri::AppHandle) -> KernelLaunch {
 
 fn main() {
     tauri::Builder::default()
+        .plugin(tauri_plugin_log::Builder::new().build())
         .setup(|app| {
             let go_kernel_path = resolve_first_existing_resource(
                 app.handle(),
@@ -234,10 +859,24 @@ fn main() {
                 ],
             );
             let python_executable = pick_python_executable(app.handle());
-            let metadata = MetadataStore::open("neuropad.sqlite").expect("failed to initialize metadata store");
+            // Besides picking the directory, this moves a neuropad.sqlite and
+            // neuropad-recovery that older versions left in the working
+            // directory into it, and refuses to start if that database is
+            // still in use; see `paths::import_legacy_data`.
+            let data_dir = paths::resolve_data_dir(app.path().app_data_dir().ok())
+                .expect("failed to resolve data directory");
+            let metadata = MetadataStore::open_in(&data_dir).expect("failed to initialize metadata store");
+            let journal = RecoveryJournal::open(data_dir.join(paths::RECOVERY_DIR))
+                .expect("failed to initialize recovery journal");
+            let workspace_roots = metadata
+                .list_workspaces()
+                .expect("failed to list workspaces")
+                .into_iter()
+                .map(|workspace| PathBuf::from(workspace.root))
+                .collect::<Vec<_>>();
 
             let state = AppState {
-                kernels: Mutex::new(KernelManager::new(
+                kernels: KernelManager::new(
                     KernelLaunch {
                         executable: go_kernel_path,
                         args: vec![],
@@ -247,22 +886,74 @@ fn main() {
                         executable: PathBuf::from(python_executable),
                         args: vec![python_kernel_script.to_string_lossy().to_string()],
                     },
-                )),
+                ),
+                notebooks: OpenNotebooks::default(),
                 metadata: Mutex::new(metadata),
+                file_stamps: Mutex::new(HashMap::new()),
+                journal,
+                autosave: AutosaveQueue::default(),
+                workspace_watcher: Mutex::new(None),
             };
             app.manage(state);
+            workspaces::start_watcher(app.handle(), &workspace_roots);
+            autosave::spawn_worker(app.handle().clone());
+            workspaces::spawn_startup_scan(app.handle().clone());
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             notebook_new,
             notebook_open,
+            notebook_close,
             notebook_save,
+            notebook_validate,
+            notebook_strip,
+            notebook_search,
+            notebook_recent,
+            notebook_library,
+            notebook_forget,
+            notebook_prune_missing,
+            notebook_versions,
+            notebook_version_diff,
+            notebook_version_restore,
+            notebook_diff,
+            notebook_diff_text,
+            notebook_open_lazy,
+            notebook_cells_page,
+            notebook_cell_outputs,
+            notebook_recover,
+            notebook_recover_restore,
+            notebook_recover_discard,
             cell_execute,
+            cell_run_history,
+            cell_run_restore,
             kernel_interrupt,
             kernel_restart,
+            kernel_info,
+            kernel_attach,
+            kernel_detach,
+            kernel_complete,
+            kernel_inspect,
             import_ipynb,
             export_ipynb,
-            ai_generate_cell
+            ai_generate_cell,
+            cell_edit::cell_insert,
+            cell_edit::cell_move,
+            cell_edit::cell_delete,
+            cell_edit::cell_duplicate,
+            cell_edit::cell_change_type,
+            cell_edit::cell_split,
+            cell_edit::cell_merge,
+            cell_edit::cell_set_source,
+            cell_edit::cell_tag,
+            cell_edit::cell_set_metadata,
+            cell_edit::cell_clear_outputs,
+            cell_edit::notebook_undo,
+            cell_edit::notebook_redo,
+            workspaces::workspace_add,
+            workspaces::workspace_list,
+            workspaces::workspace_files,
+            workspaces::workspace_rescan,
+            workspaces::workspace_remove
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
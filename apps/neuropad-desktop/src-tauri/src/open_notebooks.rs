@@ -0,0 +1,203 @@
+use neuropad_core::{FileStamp, LazyNotebook, Notebook, NotebookMetadata};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// A notebook the UI has open. The backend's copy is the one that is edited,
+/// executed and saved; the UI is only sent what changed.
+pub struct OpenNotebook {
+    pub notebook: Notebook,
+    /// Where it was opened from or last saved to; `None` until first saved.
+    pub path: Option<String>,
+    /// Changed since it was last autosaved.
+    pub dirty: bool,
+    /// Set while the notebook is only open for paging; `notebook` holds no
+    /// cells until it is loaded in full.
+    lazy: Option<(LazyNotebook, FileStamp)>,
+}
+
+impl OpenNotebook {
+    /// Loads a paged notebook in full, refusing if the file changed since it
+    /// was opened because the outputs are read back from it.
+    fn load_in_full(&mut self) -> Result<(), String> {
+        let Some((lazy, stamp)) = self.lazy.take() else {
+            return Ok(());
+        };
+        let path = self.path.clone().unwrap_or_default();
+        if FileStamp::of_file(&path).map_err(|e| e.to_string())? != Some(stamp.clone()) {
+            self.lazy = Some((lazy, stamp));
+            return Err(format!("{path} changed on disk since it was opened; open it again"));
+        }
+        self.notebook = lazy.into_notebook().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// What `notebook_new`, `notebook_open` and friends return: the notebook and
+/// the id every later command addresses it by.
+#[derive(Debug, Serialize)]
+pub struct OpenedNotebook {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub notebook: Notebook,
+}
+
+/// What `notebook_open_lazy` returns: everything but the cells, which are
+/// fetched a page at a time.
+#[derive(Debug, Serialize)]
+pub struct OpenedLazyNotebook {
+    pub id: Uuid,
+    pub version: String,
+    pub metadata: NotebookMetadata,
+    pub cell_count: usize,
+}
+
+/// A notebook as seen by the paging commands.
+pub enum Pages<'a> {
+    Lazy(&'a LazyNotebook),
+    Loaded(&'a Notebook),
+}
+
+/// Open notebooks by id. The map is only locked for lookups; each notebook
+/// has a lock of its own, so work on one never waits for another.
+#[derive(Default)]
+pub struct OpenNotebooks {
+    notebooks: Mutex<HashMap<Uuid, Arc<Mutex<OpenNotebook>>>>,
+}
+
+impl OpenNotebooks {
+    pub fn open(&self, notebook: Notebook, path: Option<String>) -> Result<OpenedNotebook, String> {
+        self.open_as(Uuid::new_v4(), notebook, path, false)
+    }
+
+    /// Opens the notebook under a given id, e.g. the recovery session it was
+    /// restored from, so its autosaves keep going to that session.
+    pub fn open_as(
+        &self,
+        id: Uuid,
+        notebook: Notebook,
+        path: Option<String>,
+        dirty: bool,
+    ) -> Result<OpenedNotebook, String> {
+        let opened = OpenedNotebook {
+            id,
+            notebook: notebook.clone(),
+        };
+        self.notebooks
+            .lock()
+            .map_err(|_| "open notebooks lock poisoned".to_string())?
+            .insert(id, Arc::new(Mutex::new(OpenNotebook { notebook, path, dirty, lazy: None })));
+        Ok(opened)
+    }
+
+    /// Opens a notebook for paging. The first command that needs more than
+    /// pages and outputs loads it in full.
+    pub fn open_lazy(
+        &self,
+        lazy: LazyNotebook,
+        stamp: FileStamp,
+        path: String,
+    ) -> Result<OpenedLazyNotebook, String> {
+        let id = Uuid::new_v4();
+        let opened = OpenedLazyNotebook {
+            id,
+            version: lazy.version.clone(),
+            metadata: lazy.metadata.clone(),
+            cell_count: lazy.len(),
+        };
+        let mut notebook = Notebook::new(&lazy.metadata.title);
+        notebook.version = lazy.version.clone();
+        notebook.metadata = lazy.metadata.clone();
+        let open = OpenNotebook {
+            notebook,
+            path: Some(path),
+            dirty: false,
+            lazy: Some((lazy, stamp)),
+        };
+        self.notebooks
+            .lock()
+            .map_err(|_| "open notebooks lock poisoned".to_string())?
+            .insert(id, Arc::new(Mutex::new(open)));
+        Ok(opened)
+    }
+
+    pub fn close(&self, id: Uuid) -> Result<(), String> {
+        self.notebooks
+            .lock()
+            .map_err(|_| "open notebooks lock poisoned".to_string())?
+            .remove(&id);
+        Ok(())
+    }
+
+    fn get(&self, id: Uuid) -> Result<Arc<Mutex<OpenNotebook>>, String> {
+        self.notebooks
+            .lock()
+            .map_err(|_| "open notebooks lock poisoned".to_string())?
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| format!("notebook {id} is not open"))
+    }
+
+    /// Runs `f` on the notebook while holding its lock, loading it in full
+    /// first if it was opened for paging.
+    pub fn with<T>(
+        &self,
+        id: Uuid,
+        f: impl FnOnce(&mut OpenNotebook) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let open = self.get(id)?;
+        let mut open = open.lock().map_err(|_| "notebook lock poisoned".to_string())?;
+        open.load_in_full()?;
+        f(&mut open)
+    }
+
+    /// Runs `f` on the paging view of a notebook, or on the notebook itself
+    /// once it is loaded in full.
+    pub fn with_pages<T>(
+        &self,
+        id: Uuid,
+        f: impl FnOnce(Pages) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let open = self.get(id)?;
+        let open = open.lock().map_err(|_| "notebook lock poisoned".to_string())?;
+        match &open.lazy {
+            Some((lazy, _)) => f(Pages::Lazy(lazy)),
+            None => f(Pages::Loaded(&open.notebook)),
+        }
+    }
+
+    /// Like [`OpenNotebooks::with`], marking the notebook dirty if `f` succeeds.
+    pub fn edit<T>(
+        &self,
+        id: Uuid,
+        f: impl FnOnce(&mut OpenNotebook) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.with(id, |open| {
+            let result = f(open)?;
+            open.dirty = true;
+            Ok(result)
+        })
+    }
+
+    /// Copies of every notebook changed since the last call, with their ids
+    /// and paths, clearing their dirty flags.
+    pub fn take_dirty(&self) -> Result<Vec<(Uuid, Option<String>, Notebook)>, String> {
+        let notebooks = self
+            .notebooks
+            .lock()
+            .map_err(|_| "open notebooks lock poisoned".to_string())?
+            .iter()
+            .map(|(id, open)| (*id, open.clone()))
+            .collect::<Vec<_>>();
+        let mut dirty = vec![];
+        for (id, open) in notebooks {
+            let mut open = open.lock().map_err(|_| "notebook lock poisoned".to_string())?;
+            if open.dirty {
+                open.dirty = false;
+                dirty.push((id, open.path.clone(), open.notebook.clone()));
+            }
+        }
+        Ok(dirty)
+    }
+}
@@ -0,0 +1,160 @@
+use neuropad_core::{MetadataStore, ScanReport, Workspace, WorkspaceFile, WorkspaceScan, WorkspaceWatcher};
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+use tauri::{AppHandle, Manager, State};
+
+use crate::AppState;
+
+/// Starts watching every registered workspace and keeps the watcher in
+/// `AppState`, which must be managed by then: events arriving right away look
+/// it up. Changed notebooks are re-indexed from the watcher's own thread;
+/// failures are only logged.
+pub fn start_watcher(app: &AppHandle, roots: &[PathBuf]) {
+    let handle = app.clone();
+    let watcher = WorkspaceWatcher::start(roots, move |paths| {
+        let state = handle.state::<AppState>();
+        if let Err(err) = rescan_paths(&state, &paths) {
+            log::warn!("workspace rescan failed: {err}");
+        }
+    });
+    let watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            log::warn!("workspace watcher unavailable: {err}");
+            return;
+        }
+    };
+    match app.state::<AppState>().workspace_watcher.lock() {
+        Ok(mut slot) => *slot = Some(watcher),
+        Err(_) => log::warn!("workspace watcher lock poisoned"),
+    }
+}
+
+/// Catches up on changes made while the app was closed.
+pub fn spawn_startup_scan(app: AppHandle) {
+    std::thread::spawn(move || {
+        let state = app.state::<AppState>();
+        let workspaces = match lock_metadata(&state).and_then(|metadata| {
+            metadata.list_workspaces().map_err(|e| e.to_string())
+        }) {
+            Ok(workspaces) => workspaces,
+            Err(err) => {
+                log::warn!("listing workspaces failed: {err}");
+                return;
+            }
+        };
+        for workspace in workspaces {
+            if let Err(err) = scan_workspace(&state, &workspace) {
+                log::warn!("scan of workspace {} failed: {err}", workspace.root);
+            }
+        }
+    });
+}
+
+fn lock_metadata(state: &AppState) -> Result<MutexGuard<'_, MetadataStore>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())
+}
+
+/// Walks and parses with the metadata store unlocked; it is only held to
+/// look up what is indexed and to write the results.
+fn scan_workspace(state: &AppState, workspace: &Workspace) -> Result<ScanReport, String> {
+    let (workspaces, known) = {
+        let metadata = lock_metadata(state)?;
+        (
+            metadata.list_workspaces().map_err(|e| e.to_string())?,
+            metadata.workspace_mtimes(workspace.id).map_err(|e| e.to_string())?,
+        )
+    };
+    let scan = WorkspaceScan::of_workspace(workspace, &workspaces, &known);
+    lock_metadata(state)?
+        .apply_workspace_scan(scan)
+        .map_err(|e| e.to_string())
+}
+
+fn rescan_paths(state: &AppState, paths: &[PathBuf]) -> Result<ScanReport, String> {
+    let (workspaces, known) = {
+        let metadata = lock_metadata(state)?;
+        (
+            metadata.list_workspaces().map_err(|e| e.to_string())?,
+            metadata.indexed_mtimes().map_err(|e| e.to_string())?,
+        )
+    };
+    let scan = WorkspaceScan::of_paths(&workspaces, &known, paths);
+    lock_metadata(state)?
+        .apply_workspace_scan(scan)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn workspace_add(root: String, state: State<AppState>) -> Result<(Workspace, ScanReport), String> {
+    let workspace = lock_metadata(&state)?.add_workspace(&root).map_err(|e| e.to_string())?;
+    let report = scan_workspace(&state, &workspace)?;
+    if let Some(watcher) = state
+        .workspace_watcher
+        .lock()
+        .map_err(|_| "workspace watcher lock poisoned".to_string())?
+        .as_mut()
+    {
+        watcher.watch(&workspace.root).map_err(|e| e.to_string())?;
+    }
+    Ok((workspace, report))
+}
+
+#[tauri::command]
+pub fn workspace_list(state: State<AppState>) -> Result<Vec<Workspace>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .list_workspaces()
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn workspace_files(workspace_id: i64, state: State<AppState>) -> Result<Vec<WorkspaceFile>, String> {
+    state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?
+        .workspace_files(workspace_id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn workspace_rescan(workspace_id: i64, state: State<AppState>) -> Result<ScanReport, String> {
+    let workspace = lock_metadata(&state)?.workspace(workspace_id).map_err(|e| e.to_string())?;
+    scan_workspace(&state, &workspace)
+}
+
+#[tauri::command]
+pub fn workspace_remove(workspace_id: i64, state: State<AppState>) -> Result<(), String> {
+    let metadata = state
+        .metadata
+        .lock()
+        .map_err(|_| "metadata lock poisoned".to_string())?;
+    let workspace = metadata.workspace(workspace_id).map_err(|e| e.to_string())?;
+    metadata.remove_workspace(workspace_id).map_err(|e| e.to_string())?;
+    // An enclosing workspace still watches the root, and its files.
+    let enclosed = metadata
+        .list_workspaces()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .any(|outer| Path::new(&workspace.root).starts_with(&outer.root));
+    drop(metadata);
+    if enclosed {
+        return Ok(());
+    }
+    if let Some(watcher) = state
+        .workspace_watcher
+        .lock()
+        .map_err(|_| "workspace watcher lock poisoned".to_string())?
+        .as_mut()
+    {
+        // The root may already be gone, in which case nothing is watched anyway.
+        let _ = watcher.unwatch(&workspace.root);
+    }
+    Ok(())
+}
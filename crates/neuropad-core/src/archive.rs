@@ -0,0 +1,211 @@
+use crate::digest::sha256_hex;
+use crate::storage::{self, FileStamp, SaveOptions};
+use crate::{CoreError, CoreResult, Notebook};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+pub const NPADZ_MIMETYPE: &str = "application/x-neuropad+zip";
+pub const NOTEBOOK_ENTRY: &str = "notebook.json";
+pub const BLOB_DIR: &str = "blobs/";
+pub const ATTACHMENT_DIR: &str = "attachments/";
+
+/// Text outputs up to this size stay inline in `notebook.json`.
+pub const INLINE_OUTPUT_LIMIT: usize = 4 * 1024;
+
+/// Entries larger than this are refused when reading an archive.
+pub const MAX_ENTRY_SIZE: u64 = 512 * 1024 * 1024;
+/// Sizes in the zip header are not trusted beyond this for pre-allocation.
+const MAX_ENTRY_PREALLOC: u64 = 1024 * 1024;
+
+const BLOB_PREFIX: &str = "sha256:";
+const ATTACHMENT_SCHEME: &str = "attachment:sha256:";
+/// Per-cell manifest of the attachment references the archive wrote into its
+/// source, so text that merely looks like one is left alone on load.
+const ATTACHMENT_MANIFEST: &str = "attachments";
+
+/// Writes a `.npadz` container: a stored `mimetype` entry, `notebook.json`
+/// with large or binary outputs replaced by `blob` references, and
+/// content-addressed `blobs/` and `attachments/` entries. Each cell whose
+/// source references attachments lists where in an `attachments` manifest.
+pub fn save_npadz<P: AsRef<Path>>(
+    notebook: &Notebook,
+    path: P,
+    options: &SaveOptions,
+) -> CoreResult<FileStamp> {
+    notebook.validate()?;
+    let mut root = serde_json::to_value(notebook)?;
+    let mut blobs = BTreeMap::new();
+    let mut attachments = BTreeMap::new();
+    for cell in cells_mut(&mut root)? {
+        if let Some(Value::String(source)) = cell.get_mut("source") {
+            let (extracted, manifest) = extract_attachments(source, &mut attachments);
+            *source = extracted;
+            if !manifest.is_empty() {
+                cell[ATTACHMENT_MANIFEST] = Value::Array(manifest);
+            }
+        }
+        let Some(outputs) = cell.get_mut("outputs").and_then(Value::as_array_mut) else {
+            continue;
+        };
+        for output in outputs {
+            let mime = output.get("mime").and_then(Value::as_str).unwrap_or_default();
+            let Some(data) = output.get("data").and_then(Value::as_str) else {
+                continue;
+            };
+            if !should_externalize(mime, data) {
+                continue;
+            }
+            let hash = sha256_hex(data.as_bytes());
+            blobs.insert(hash.clone(), data.as_bytes().to_vec());
+            output["data"] = Value::String(String::new());
+            output["blob"] = Value::String(format!("{BLOB_PREFIX}{hash}"));
+        }
+    }
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    writer.start_file("mimetype", stored)?;
+    writer.write_all(NPADZ_MIMETYPE.as_bytes())?;
+    writer.start_file(NOTEBOOK_ENTRY, deflated)?;
+    writer.write_all(serde_json::to_string_pretty(&root)?.as_bytes())?;
+    for (hash, bytes) in &blobs {
+        writer.start_file(format!("{BLOB_DIR}{hash}"), deflated)?;
+        writer.write_all(bytes)?;
+    }
+    for (hash, bytes) in &attachments {
+        writer.start_file(format!("{ATTACHMENT_DIR}{hash}"), deflated)?;
+        writer.write_all(bytes)?;
+    }
+    let bytes = writer.finish()?.into_inner();
+    storage::write_atomic(path, &bytes, options)
+}
+
+pub fn load_npadz<P: AsRef<Path>>(path: P) -> CoreResult<Notebook> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mimetype = read_entry(&mut archive, "mimetype")?;
+    if mimetype != NPADZ_MIMETYPE.as_bytes() {
+        return Err(CoreError::Validation("not a .npadz archive".to_string()));
+    }
+    let mut root: Value = serde_json::from_slice(&read_entry(&mut archive, NOTEBOOK_ENTRY)?)?;
+    for cell in cells_mut(&mut root)? {
+        let manifest = cell.as_object_mut().and_then(|cell| cell.remove(ATTACHMENT_MANIFEST));
+        if let (Some(manifest), Some(Value::String(source))) = (manifest, cell.get_mut("source")) {
+            *source = resolve_attachments(source, &manifest, &mut archive)?;
+        }
+        let Some(outputs) = cell.get_mut("outputs").and_then(Value::as_array_mut) else {
+            continue;
+        };
+        for output in outputs {
+            let Some(object) = output.as_object_mut() else {
+                continue;
+            };
+            let Some(Value::String(reference)) = object.remove("blob") else {
+                continue;
+            };
+            let hash = reference.strip_prefix(BLOB_PREFIX).ok_or_else(|| {
+                CoreError::Validation(format!("unsupported blob reference '{reference}'"))
+            })?;
+            let bytes = read_verified(&mut archive, &format!("{BLOB_DIR}{hash}"), hash)?;
+            let data = String::from_utf8(bytes)
+                .map_err(|_| CoreError::Validation(format!("blob {hash} is not utf-8")))?;
+            object.insert("data".to_string(), Value::String(data));
+        }
+    }
+
+    let notebook: Notebook = serde_json::from_value(root)?;
+    notebook.validate()?;
+    Ok(notebook)
+}
+
+fn cells_mut(root: &mut Value) -> CoreResult<&mut Vec<Value>> {
+    root.get_mut("cells")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| CoreError::Validation("notebook.json missing cells array".to_string()))
+}
+
+fn should_externalize(mime: &str, data: &str) -> bool {
+    data.len() > INLINE_OUTPUT_LIMIT || (!mime.starts_with("text/") && !data.is_empty())
+}
+
+/// Moves inline `](data:...)` markdown images into attachments, returning the
+/// rewritten source and the manifest of `{ "at": byte offset, "hash" }` for
+/// each reference written.
+fn extract_attachments(source: &str, attachments: &mut BTreeMap<String, Vec<u8>>) -> (String, Vec<Value>) {
+    let mut result = String::with_capacity(source.len());
+    let mut manifest = vec![];
+    let mut rest = source;
+    while let Some(start) = rest.find("](data:") {
+        let uri_start = start + 2;
+        let Some(len) = rest[uri_start..].find(')') else {
+            break;
+        };
+        let uri = &rest[uri_start..uri_start + len];
+        let hash = sha256_hex(uri.as_bytes());
+        attachments.insert(hash.clone(), uri.as_bytes().to_vec());
+        result.push_str(&rest[..uri_start]);
+        manifest.push(serde_json::json!({ "at": result.len(), "hash": hash }));
+        result.push_str(ATTACHMENT_SCHEME);
+        result.push_str(&hash);
+        rest = &rest[uri_start + len..];
+    }
+    result.push_str(rest);
+    (result, manifest)
+}
+
+/// Replaces the references listed in `manifest`, and only those, with the
+/// attachments they point at.
+fn resolve_attachments<R: Read + std::io::Seek>(
+    source: &str,
+    manifest: &Value,
+    archive: &mut ZipArchive<R>,
+) -> CoreResult<String> {
+    let invalid = || CoreError::Validation("invalid attachment manifest".to_string());
+    let mut result = String::with_capacity(source.len());
+    let mut copied = 0;
+    for entry in manifest.as_array().ok_or_else(invalid)? {
+        let at = entry.get("at").and_then(Value::as_u64).ok_or_else(invalid)? as usize;
+        let hash = entry.get("hash").and_then(Value::as_str).ok_or_else(invalid)?;
+        let reference = format!("{ATTACHMENT_SCHEME}{hash}");
+        if at < copied || source.get(at..).is_none_or(|rest| !rest.starts_with(&reference)) {
+            return Err(CoreError::Validation(format!("attachment {hash} is not where the manifest says")));
+        }
+        let bytes = read_verified(archive, &format!("{ATTACHMENT_DIR}{hash}"), hash)?;
+        result.push_str(&source[copied..at]);
+        result.push_str(&String::from_utf8_lossy(&bytes));
+        copied = at + reference.len();
+    }
+    result.push_str(&source[copied..]);
+    Ok(result)
+}
+
+fn read_entry<R: Read + std::io::Seek>(archive: &mut ZipArchive<R>, name: &str) -> CoreResult<Vec<u8>> {
+    let mut entry = archive
+        .by_name(name)
+        .map_err(|_| CoreError::Validation(format!("archive missing entry '{name}'")))?;
+    let mut bytes = Vec::with_capacity(entry.size().min(MAX_ENTRY_PREALLOC) as usize);
+    entry.by_ref().take(MAX_ENTRY_SIZE + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_ENTRY_SIZE {
+        return Err(CoreError::Validation(format!(
+            "archive entry '{name}' is larger than {MAX_ENTRY_SIZE} bytes"
+        )));
+    }
+    Ok(bytes)
+}
+
+fn read_verified<R: Read + std::io::Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+    hash: &str,
+) -> CoreResult<Vec<u8>> {
+    let bytes = read_entry(archive, name)?;
+    if sha256_hex(&bytes) != hash {
+        return Err(CoreError::Validation(format!("archive entry '{name}' is corrupt")));
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,113 @@
+//! Git integration for `.npad` notebooks.
+//!
+//! Register the merge driver and clean filter with:
+//!
+//! ```text
+//! git config merge.neuropad.driver "neuropad-git merge %O %A %B"
+//! git config filter.neuropad.clean "neuropad-git clean %f"
+//! echo "*.npad merge=neuropad filter=neuropad" >> .gitattributes
+//! ```
+
+use neuropad_core::strip::{is_ipynb, strip_ipynb_str, strip_npad_str};
+use neuropad_core::{merge_notebooks, CanonicalOptions, CellTag, CoreResult, Notebook, SaveOptions, StripOptions};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage:
+  neuropad-git merge <base> <ours> <theirs>
+  neuropad-git clean [<path>] [--keep-tag <tag>]... [--keep-mime <mime>]...";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["merge", base, ours, theirs] => merge(Path::new(base), Path::new(ours), Path::new(theirs)),
+        ["clean", rest @ ..] => match parse_clean_args(rest) {
+            Some((path, options)) => clean(path, &options),
+            None => return usage(),
+        },
+        _ => return usage(),
+    };
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("neuropad-git: {err}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::from(2)
+}
+
+/// Merges in place into `ours`, as git expects from a merge driver. Exits
+/// with 1 when conflict cells were written so git marks the file unmerged.
+/// The result is written canonically and keeps the merged `updated_at`.
+fn merge(base: &Path, ours: &Path, theirs: &Path) -> CoreResult<ExitCode> {
+    let mut outcome = merge_notebooks(
+        &load_base(base)?,
+        &Notebook::load_npad(ours)?,
+        &Notebook::load_npad(theirs)?,
+    );
+    outcome.notebook.save_npad_with(
+        ours,
+        &SaveOptions {
+            canonical: Some(CanonicalOptions::default()),
+            ..SaveOptions::default()
+        },
+    )?;
+
+    for conflict in &outcome.conflicts {
+        match conflict.cell_id {
+            Some(id) => eprintln!("conflict in cell {id}: {}", conflict.message),
+            None => eprintln!("conflict: {}", conflict.message),
+        }
+    }
+    Ok(if outcome.is_clean() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Git passes an empty base when both sides added the file; merge that as
+/// a notebook without cells.
+fn load_base(path: &Path) -> CoreResult<Notebook> {
+    if fs::read(path)?.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Notebook::new(""));
+    }
+    Notebook::load_npad(path)
+}
+
+fn parse_clean_args<'a>(args: &[&'a str]) -> Option<(Option<&'a str>, StripOptions)> {
+    let mut path = None;
+    let mut options = StripOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--keep-tag" => {
+                options.keep_tags.insert(CellTag::from(*args.next()?));
+            }
+            "--keep-mime" => options.keep_mimes.push(args.next()?.to_string()),
+            other if path.is_none() && !other.starts_with("--") => path = Some(other),
+            _ => return None,
+        }
+    }
+    Some((path, options))
+}
+
+/// Git clean filter: reads the notebook on stdin and writes it stripped to
+/// stdout. The path is only used to tell `.ipynb` from `.npad`.
+fn clean(path: Option<&str>, options: &StripOptions) -> CoreResult<ExitCode> {
+    let mut data = String::new();
+    std::io::stdin().read_to_string(&mut data)?;
+    let stripped = match path {
+        Some(path) if is_ipynb(Path::new(path)) => strip_ipynb_str(&data, options)?,
+        _ => strip_npad_str(&data, options)?,
+    };
+    std::io::stdout().write_all(stripped.as_bytes())?;
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,157 @@
+use crate::{CoreResult, Notebook};
+use chrono::{DateTime, Utc};
+use serde::ser::{SerializeMap, SerializeSeq, Serializer};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Settings for the diff-friendly `.npad` layout: keys sorted, sources split
+/// into line arrays and `updated_at` left alone unless the caller touches it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CanonicalOptions {
+    /// Drop output `created_at` and execution `duration_ms`.
+    #[serde(default)]
+    pub strip_timestamps: bool,
+    /// Drop all outputs and reset execution state.
+    #[serde(default)]
+    pub strip_outputs: bool,
+}
+
+impl CanonicalOptions {
+    pub fn strip_all() -> Self {
+        Self {
+            strip_timestamps: true,
+            strip_outputs: true,
+        }
+    }
+}
+
+/// Renders a notebook in canonical form. Two notebooks with the same content
+/// always produce byte-identical output.
+pub fn to_canonical_string(notebook: &Notebook, options: &CanonicalOptions) -> CoreResult<String> {
+    let mut value = serde_json::to_value(notebook)?;
+    for cell in value
+        .get_mut("cells")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+    {
+        canonicalize_cell(cell, options);
+    }
+    let mut data = serde_json::to_string_pretty(&SortedKeys(&value))?;
+    data.push('\n');
+    Ok(data)
+}
+
+/// Serializes a value with every object's keys in sorted order, whatever
+/// order the map itself keeps (serde_json's `preserve_order` keeps insertion
+/// order).
+struct SortedKeys<'a>(&'a Value);
+
+impl Serialize for SortedKeys<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Object(object) => {
+                let mut entries = object.iter().collect::<Vec<_>>();
+                entries.sort_unstable_by_key(|(key, _)| *key);
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, &SortedKeys(value))?;
+                }
+                map.end()
+            }
+            Value::Array(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(&SortedKeys(item))?;
+                }
+                seq.end()
+            }
+            other => other.serialize(serializer),
+        }
+    }
+}
+
+fn canonicalize_cell(cell: &mut Value, options: &CanonicalOptions) {
+    let lines = cell.get("source").and_then(Value::as_str).map(|s| Value::from(split_source(s)));
+    if let Some(lines) = lines {
+        cell["source"] = lines;
+    }
+    if options.strip_outputs {
+        cell["outputs"] = Value::Array(vec![]);
+        cell["execution"] = serde_json::json!({ "count": 0, "status": "idle", "duration_ms": 0 });
+    }
+    if options.strip_timestamps {
+        for output in cell
+            .get_mut("outputs")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten()
+        {
+            if let Some(output) = output.as_object_mut() {
+                output.remove("created_at");
+            }
+        }
+        if let Some(execution) = cell.get_mut("execution").and_then(Value::as_object_mut) {
+            execution.remove("duration_ms");
+        }
+    }
+}
+
+/// Splits a source into lines that keep their `\n`, the layout `.ipynb` uses,
+/// so concatenating the array restores the original text exactly.
+pub fn split_source(source: &str) -> Vec<&str> {
+    source.split_inclusive('\n').collect()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SourceRepr {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+/// Accepts a cell source written either as one string or as a line array.
+pub(crate) fn deserialize_source<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match SourceRepr::deserialize(deserializer)? {
+        SourceRepr::Text(text) => text,
+        SourceRepr::Lines(lines) => lines.concat(),
+    })
+}
+
+/// Stand-in for timestamps stripped by [`CanonicalOptions::strip_timestamps`].
+pub(crate) fn stripped_timestamp() -> DateTime<Utc> {
+    DateTime::<Utc>::UNIX_EPOCH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_source_round_trips() {
+        for source in ["", "one line", "a\nb\n", "a\n\nb", "\n"] {
+            assert_eq!(split_source(source).concat(), source);
+        }
+        assert_eq!(split_source("a\nb"), vec!["a\n", "b"]);
+    }
+
+    #[test]
+    fn object_keys_are_sorted_at_every_level() {
+        let mut nb = Notebook::new("keys");
+        nb.add_code_cell("go", "x");
+        let data = to_canonical_string(&nb, &CanonicalOptions::default()).expect("canonical");
+        let position = |key: &str| data.find(&format!("\"{key}\":")).expect(key);
+        let in_order = |keys: &[&str]| keys.windows(2).all(|pair| position(pair[0]) < position(pair[1]));
+        assert!(in_order(&["cells", "metadata", "version"]));
+        assert!(in_order(&["created_at", "kernel_policy", "title", "updated_at"]));
+        assert!(in_order(&["execution", "id", "language", "outputs", "source", "type"]));
+
+        let mut object = serde_json::Map::new();
+        for key in ["b", "c", "a"] {
+            object.insert(key.to_string(), Value::from(1));
+        }
+        let value = Value::Array(vec![Value::Object(object)]);
+        let sorted = serde_json::to_string(&SortedKeys(&value)).expect("sorted");
+        assert_eq!(sorted, r#"[{"a":1,"b":1,"c":1}]"#);
+    }
+}
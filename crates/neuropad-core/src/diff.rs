@@ -0,0 +1,543 @@
+use crate::{Cell, CellOutput, CellOutputKind, CellTag, CellType, Notebook};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FieldChange<T> {
+    pub old: T,
+    pub new: T,
+}
+
+impl<T: PartialEq> FieldChange<T> {
+    fn between(old: T, new: T) -> Option<Self> {
+        (old != new).then_some(Self { old, new })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "op", content = "line", rename_all = "snake_case")]
+pub enum LineDiff {
+    Equal(String),
+    Delete(String),
+    Insert(String),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CellChangeKind {
+    Added,
+    Removed,
+    Modified,
+    Unchanged,
+}
+
+/// An output reduced to the fields that matter for review; `created_at` is dropped.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OutputSnapshot {
+    pub kind: CellOutputKind,
+    pub mime: String,
+    pub data: String,
+}
+
+impl From<&CellOutput> for OutputSnapshot {
+    fn from(output: &CellOutput) -> Self {
+        Self {
+            kind: output.kind.clone(),
+            mime: output.mime.clone(),
+            data: output.data.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CellDiff {
+    pub id: Uuid,
+    pub kind: CellChangeKind,
+    pub old_index: Option<usize>,
+    pub new_index: Option<usize>,
+    /// The cell kept its identity but changed position relative to its neighbours.
+    pub moved: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cell_type: Option<FieldChange<CellType>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<FieldChange<Option<String>>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source: Vec<LineDiff>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<FieldChange<Vec<OutputSnapshot>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execution_count: Option<FieldChange<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<FieldChange<BTreeSet<CellTag>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FieldChange<Map<String, Value>>>,
+}
+
+/// Differences between two notebooks, matched by cell `id`. Volatile fields
+/// (`updated_at`, output `created_at`, execution `duration_ms`) are ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotebookDiff {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<FieldChange<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<FieldChange<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_policy: Option<FieldChange<String>>,
+    pub cells: Vec<CellDiff>,
+}
+
+impl NotebookDiff {
+    pub fn is_empty(&self) -> bool {
+        self.version.is_none()
+            && self.title.is_none()
+            && self.kernel_policy.is_none()
+            && self.cells.iter().all(|c| c.kind == CellChangeKind::Unchanged && !c.moved)
+    }
+
+    /// Cells that were added, removed, modified or moved.
+    pub fn changed_cells(&self) -> impl Iterator<Item = &CellDiff> {
+        self.cells
+            .iter()
+            .filter(|c| c.kind != CellChangeKind::Unchanged || c.moved)
+    }
+}
+
+pub fn diff_notebooks(old: &Notebook, new: &Notebook) -> NotebookDiff {
+    let old_index = index_by_id(&old.cells);
+    let new_index = index_by_id(&new.cells);
+
+    let old_common = old
+        .cells
+        .iter()
+        .map(|c| c.id)
+        .filter(|id| new_index.contains_key(id))
+        .collect::<Vec<_>>();
+    let new_common = new
+        .cells
+        .iter()
+        .map(|c| c.id)
+        .filter(|id| old_index.contains_key(id))
+        .collect::<Vec<_>>();
+    let stable = lcs_pairs(&old_common, &new_common)
+        .into_iter()
+        .map(|(i, _)| old_common[i])
+        .collect::<HashSet<_>>();
+
+    // Removed cells are listed right after the surviving cell that preceded them.
+    let mut removals: HashMap<Option<Uuid>, Vec<CellDiff>> = HashMap::new();
+    let mut anchor = None;
+    for (position, cell) in old.cells.iter().enumerate() {
+        if new_index.contains_key(&cell.id) {
+            anchor = Some(cell.id);
+        } else {
+            removals.entry(anchor).or_default().push(removed_cell(cell, position));
+        }
+    }
+
+    let mut cells = removals.remove(&None).unwrap_or_default();
+    for (position, cell) in new.cells.iter().enumerate() {
+        match old_index.get(&cell.id) {
+            Some(&old_position) => {
+                let mut diff = compare_cells(&old.cells[old_position], cell, old_position, position);
+                diff.moved = !stable.contains(&cell.id);
+                cells.push(diff);
+                cells.extend(removals.remove(&Some(cell.id)).unwrap_or_default());
+            }
+            None => cells.push(added_cell(cell, position)),
+        }
+    }
+
+    NotebookDiff {
+        version: FieldChange::between(old.version.clone(), new.version.clone()),
+        title: FieldChange::between(old.metadata.title.clone(), new.metadata.title.clone()),
+        kernel_policy: FieldChange::between(
+            old.metadata.kernel_policy.clone(),
+            new.metadata.kernel_policy.clone(),
+        ),
+        cells,
+    }
+}
+
+fn index_by_id(cells: &[Cell]) -> HashMap<Uuid, usize> {
+    cells.iter().enumerate().map(|(i, c)| (c.id, i)).collect()
+}
+
+fn blank(cell: &Cell, kind: CellChangeKind, old_index: Option<usize>, new_index: Option<usize>) -> CellDiff {
+    CellDiff {
+        id: cell.id,
+        kind,
+        old_index,
+        new_index,
+        moved: false,
+        cell_type: None,
+        language: None,
+        source: vec![],
+        outputs: None,
+        execution_count: None,
+        tags: None,
+        metadata: None,
+    }
+}
+
+fn added_cell(cell: &Cell, index: usize) -> CellDiff {
+    let mut diff = blank(cell, CellChangeKind::Added, None, Some(index));
+    diff.source = diff_lines("", &cell.source);
+    diff
+}
+
+fn removed_cell(cell: &Cell, index: usize) -> CellDiff {
+    let mut diff = blank(cell, CellChangeKind::Removed, Some(index), None);
+    diff.source = diff_lines(&cell.source, "");
+    diff
+}
+
+fn compare_cells(old: &Cell, new: &Cell, old_index: usize, new_index: usize) -> CellDiff {
+    let mut diff = blank(new, CellChangeKind::Unchanged, Some(old_index), Some(new_index));
+    diff.cell_type = FieldChange::between(old.cell_type.clone(), new.cell_type.clone());
+    diff.language = FieldChange::between(old.language.clone(), new.language.clone());
+    if old.source != new.source {
+        diff.source = diff_lines(&old.source, &new.source);
+    }
+    diff.outputs = FieldChange::between(snapshots(&old.outputs), snapshots(&new.outputs));
+    diff.execution_count = FieldChange::between(old.execution.count, new.execution.count);
+    diff.tags = FieldChange::between(old.tags.clone(), new.tags.clone());
+    diff.metadata = FieldChange::between(old.metadata.clone(), new.metadata.clone());
+
+    let modified = diff.cell_type.is_some()
+        || diff.language.is_some()
+        || !diff.source.is_empty()
+        || diff.outputs.is_some()
+        || diff.execution_count.is_some()
+        || diff.tags.is_some()
+        || diff.metadata.is_some();
+    if modified {
+        diff.kind = CellChangeKind::Modified;
+    }
+    diff
+}
+
+pub(crate) fn snapshots(outputs: &[CellOutput]) -> Vec<OutputSnapshot> {
+    outputs.iter().map(OutputSnapshot::from).collect()
+}
+
+/// Line-level diff of two texts based on their longest common subsequence.
+/// Lines are split on `\n` only, so a text ending in a newline has a final
+/// empty line and adding or dropping that newline (or a `\r`) shows up.
+pub fn diff_lines(old: &str, new: &str) -> Vec<LineDiff> {
+    let (old_lines, new_lines) = (split_lines(old), split_lines(new));
+    let pairs = lcs_pairs(&old_lines, &new_lines);
+
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    for (oi, nj) in pairs.into_iter().chain(std::iter::once((old_lines.len(), new_lines.len()))) {
+        result.extend(old_lines[i..oi].iter().map(|l| LineDiff::Delete(l.to_string())));
+        result.extend(new_lines[j..nj].iter().map(|l| LineDiff::Insert(l.to_string())));
+        if oi < old_lines.len() && nj < new_lines.len() {
+            result.push(LineDiff::Equal(old_lines[oi].to_string()));
+        }
+        i = oi + 1;
+        j = nj + 1;
+    }
+    result
+}
+
+fn split_lines(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        vec![]
+    } else {
+        text.split('\n').collect()
+    }
+}
+
+/// Index pairs of a longest common subsequence, found with Myers' linear
+/// space algorithm: O((n + m) · d) time for d differing items and O(n + m)
+/// memory, so large mostly-equal inputs stay cheap.
+pub(crate) fn lcs_pairs<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let mut pairs = vec![];
+    collect_pairs(a, b, 0, 0, &mut pairs);
+    pairs
+}
+
+/// Appends the pairs of `a` and `b`, offset by where they start in the
+/// original sequences, in ascending order.
+fn collect_pairs<T: PartialEq>(a: &[T], b: &[T], a_start: usize, b_start: usize, pairs: &mut Vec<(usize, usize)>) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    pairs.extend((0..prefix).map(|k| (a_start + k, b_start + k)));
+    if let Some((x, y)) = middle_snake(a_mid, b_mid) {
+        let (a_mid_start, b_mid_start) = (a_start + prefix, b_start + prefix);
+        collect_pairs(&a_mid[..x], &b_mid[..y], a_mid_start, b_mid_start, pairs);
+        collect_pairs(&a_mid[x..], &b_mid[y..], a_mid_start + x, b_mid_start + y, pairs);
+    }
+    let (a_end, b_end) = (a_start + a.len(), b_start + b.len());
+    pairs.extend((0..suffix).map(|k| (a_end - suffix + k, b_end - suffix + k)));
+}
+
+/// Where a shortest edit script from `a` to `b` can be split in two, found by
+/// searching forwards from the start and backwards from the end until the
+/// paths overlap. `None` when there is nothing to split: one side is empty,
+/// so every item is simply deleted or inserted.
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> Option<(usize, usize)> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d + 1;
+    // Furthest x reached on each diagonal k = x - y, forwards and (counting
+    // from the end) backwards.
+    let mut forward = vec![-1isize; 2 * offset as usize + 1];
+    let mut backward = forward.clone();
+    forward[offset as usize + 1] = 0;
+    backward[offset as usize + 1] = 0;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    // Diagonals whose paths have left the graph are skipped from then on.
+    let (mut forward_lo, mut forward_hi, mut backward_lo, mut backward_hi) = (0, 0, 0, 0);
+
+    for d in 0..=max_d {
+        let mut k = -d + forward_lo;
+        while k <= d - forward_hi {
+            let at = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && forward[at - 1] < forward[at + 1]) {
+                forward[at + 1]
+            } else {
+                forward[at - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[at] = x;
+            if x > n {
+                forward_hi += 2;
+            } else if y > m {
+                forward_lo += 2;
+            } else if odd {
+                let other = offset + delta - k;
+                if (0..backward.len() as isize).contains(&other)
+                    && backward[other as usize] != -1
+                    && x >= n - backward[other as usize]
+                {
+                    return Some((x as usize, y as usize));
+                }
+            }
+            k += 2;
+        }
+
+        let mut k = -d + backward_lo;
+        while k <= d - backward_hi {
+            let at = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && backward[at - 1] < backward[at + 1]) {
+                backward[at + 1]
+            } else {
+                backward[at - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[at] = x;
+            if x > n {
+                backward_hi += 2;
+            } else if y > m {
+                backward_lo += 2;
+            } else if !odd {
+                let other = offset + delta - k;
+                if (0..forward.len() as isize).contains(&other) && forward[other as usize] != -1 {
+                    let forward_x = forward[other as usize];
+                    if forward_x >= n - x {
+                        return Some((forward_x as usize, (forward_x - (other - offset)) as usize));
+                    }
+                }
+            }
+            k += 2;
+        }
+    }
+    // The searches always meet by d = (n + m) / 2.
+    None
+}
+
+impl fmt::Display for NotebookDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(change) = &self.version {
+            writeln!(f, "version: {:?} -> {:?}", change.old, change.new)?;
+        }
+        if let Some(change) = &self.title {
+            writeln!(f, "title: {:?} -> {:?}", change.old, change.new)?;
+        }
+        if let Some(change) = &self.kernel_policy {
+            writeln!(f, "kernel_policy: {:?} -> {:?}", change.old, change.new)?;
+        }
+        for cell in self.changed_cells() {
+            let (marker, label) = match cell.kind {
+                CellChangeKind::Added => ('+', "added"),
+                CellChangeKind::Removed => ('-', "removed"),
+                CellChangeKind::Modified => ('~', "modified"),
+                CellChangeKind::Unchanged => ('~', "unchanged"),
+            };
+            write!(f, "{marker} cell {} {label}", cell.id)?;
+            match (cell.old_index, cell.new_index) {
+                (Some(old), Some(new)) if cell.moved => write!(f, ", moved {old} -> {new}")?,
+                (_, Some(new)) if cell.kind == CellChangeKind::Added => write!(f, " at {new}")?,
+                (Some(old), _) if cell.kind == CellChangeKind::Removed => write!(f, " from {old}")?,
+                _ => {}
+            }
+            writeln!(f)?;
+            if let Some(change) = &cell.cell_type {
+                writeln!(f, "    type: {:?} -> {:?}", change.old, change.new)?;
+            }
+            if let Some(change) = &cell.language {
+                writeln!(f, "    language: {:?} -> {:?}", change.old, change.new)?;
+            }
+            for line in &cell.source {
+                match line {
+                    LineDiff::Equal(text) => writeln!(f, "      {text}")?,
+                    LineDiff::Delete(text) => writeln!(f, "    - {text}")?,
+                    LineDiff::Insert(text) => writeln!(f, "    + {text}")?,
+                }
+            }
+            if let Some(change) = &cell.outputs {
+                writeln!(f, "    outputs: {} -> {} entries", change.old.len(), change.new.len())?;
+            }
+            if let Some(change) = &cell.execution_count {
+                writeln!(f, "    execution count: {} -> {}", change.old, change.new)?;
+            }
+            if let Some(change) = &cell.tags {
+                let names = |tags: &BTreeSet<CellTag>| tags.iter().map(CellTag::as_str).collect::<Vec<_>>().join(", ");
+                writeln!(f, "    tags: [{}] -> [{}]", names(&change.old), names(&change.new))?;
+            }
+            if cell.metadata.is_some() {
+                writeln!(f, "    metadata changed")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn identical_after_volatile_changes() {
+        let mut old = Notebook::new("diff");
+        old.add_code_cell("go", "fmt.Println(1)");
+        old.cells[0].outputs.push(CellOutput {
+            kind: CellOutputKind::Stdout,
+            mime: "text/plain".to_string(),
+            data: "1\n".to_string(),
+            created_at: Utc::now(),
+        });
+        let mut new = old.clone();
+        new.touch();
+        new.metadata.updated_at += Duration::hours(1);
+        new.cells[0].outputs[0].created_at += Duration::hours(1);
+        new.cells[0].execution.duration_ms = 999;
+
+        let diff = diff_notebooks(&old, &new);
+        assert!(diff.is_empty(), "{diff}");
+        assert_eq!(diff.to_string(), "");
+    }
+
+    #[test]
+    fn reports_added_removed_moved_and_edited_cells() {
+        let mut old = Notebook::new("diff");
+        let a = old.add_markdown_cell("# A");
+        let b = old.add_code_cell("python", "x = 1\ny = 2\nprint(x)");
+        let c = old.add_code_cell("python", "gone()");
+        let d = old.add_markdown_cell("end");
+
+        let mut new = old.clone();
+        new.delete_cell(c).expect("delete");
+        new.move_cell(d, 0).expect("move");
+        new.set_cell_source(b, "x = 1\ny = 3\nprint(x)").expect("edit");
+        let e = new.add_code_cell("ruby", "puts 1");
+
+        let diff = diff_notebooks(&old, &new);
+        let find = |id| diff.cells.iter().find(|c| c.id == id).expect("cell in diff");
+        assert_eq!(find(a).kind, CellChangeKind::Unchanged);
+        assert!(!find(a).moved);
+        assert!(find(d).moved);
+        assert_eq!(find(c).kind, CellChangeKind::Removed);
+        assert_eq!(find(e).kind, CellChangeKind::Added);
+        assert_eq!(
+            find(b).source,
+            vec![
+                LineDiff::Equal("x = 1".to_string()),
+                LineDiff::Delete("y = 2".to_string()),
+                LineDiff::Insert("y = 3".to_string()),
+                LineDiff::Equal("print(x)".to_string()),
+            ]
+        );
+
+        let json = serde_json::to_value(&diff).expect("json");
+        assert!(json["cells"].as_array().unwrap().iter().any(|c| c["kind"] == "removed"));
+        let text = diff.to_string();
+        assert!(text.contains(&format!("- cell {c} removed from 2")));
+        assert!(text.contains("    + y = 3"));
+    }
+
+    #[test]
+    fn shows_trailing_newline_changes() {
+        assert_eq!(
+            diff_lines("a\nb", "a\nb\n"),
+            vec![
+                LineDiff::Equal("a".to_string()),
+                LineDiff::Equal("b".to_string()),
+                LineDiff::Insert(String::new()),
+            ]
+        );
+        assert_eq!(diff_lines("", "x"), vec![LineDiff::Insert("x".to_string())]);
+        assert!(diff_lines("a\r\n", "a\n").contains(&LineDiff::Delete("a\r".to_string())));
+    }
+
+    #[test]
+    fn lcs_pairs_are_a_longest_common_subsequence() {
+        fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+            let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+            for i in 0..a.len() {
+                for j in 0..b.len() {
+                    table[i + 1][j + 1] = if a[i] == b[j] {
+                        table[i][j] + 1
+                    } else {
+                        table[i][j + 1].max(table[i + 1][j])
+                    };
+                }
+            }
+            table[a.len()][b.len()]
+        }
+
+        let mut seed = 7u32;
+        let mut next = move |bound: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) % bound
+        };
+        for _ in 0..500 {
+            let a = (0..next(12)).map(|_| b'a' + next(3) as u8).collect::<Vec<_>>();
+            let b = (0..next(12)).map(|_| b'a' + next(3) as u8).collect::<Vec<_>>();
+            let pairs = lcs_pairs(&a, &b);
+            assert_eq!(pairs.len(), lcs_len(&a, &b), "{a:?} {b:?}");
+            assert!(pairs.iter().all(|&(i, j)| a[i] == b[j]));
+            assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+        }
+
+        let long = (0..20_000).map(|i| i % 97).collect::<Vec<_>>();
+        let mut edited = long.clone();
+        edited[10_000] = 1_000;
+        assert_eq!(lcs_pairs(&long, &edited).len(), long.len() - 1);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::history::EditOp;
+use crate::notebook::validate_cell_kind;
+use crate::{
+    Cell, CellExecution, CellOutput, CellStatus, CellTag, CellType, CoreError, CoreResult, Notebook, NotebookMetadata,
+};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Cell-level editing operations addressed by cell id. Each one validates the
+/// cells it touches, records an undoable [`EditOp`] and bumps `updated_at`.
+impl Notebook {
+    pub fn position(&self, id: Uuid) -> Option<usize> {
+        self.cells.iter().position(|cell| cell.id == id)
+    }
+
+    fn index_of(&self, id: Uuid) -> CoreResult<usize> {
+        self.position(id).ok_or(CoreError::CellNotFound(id))
+    }
+
+    pub fn cell(&self, id: Uuid) -> Option<&Cell> {
+        self.cells.iter().find(|cell| cell.id == id)
+    }
+
+    pub fn cell_mut(&mut self, id: Uuid) -> Option<&mut Cell> {
+        self.cells.iter_mut().find(|cell| cell.id == id)
+    }
+
+    pub fn insert_cell_at(&mut self, index: usize, cell: Cell) -> CoreResult<Uuid> {
+        validate_cell_kind(cell.id, &cell.cell_type, cell.language.as_deref())?;
+        if index > self.cells.len() {
+            return Err(CoreError::Validation(format!(
+                "insert index {index} out of range for {} cells",
+                self.cells.len()
+            )));
+        }
+        if self.cell(cell.id).is_some() {
+            return Err(CoreError::Validation(format!("cell {} already exists", cell.id)));
+        }
+        let id = cell.id;
+        self.apply_edit(EditOp::InsertCell { index, cell })?;
+        Ok(id)
+    }
+
+    pub fn insert_cell_before(&mut self, anchor: Uuid, cell: Cell) -> CoreResult<Uuid> {
+        let index = self.index_of(anchor)?;
+        self.insert_cell_at(index, cell)
+    }
+
+    pub fn insert_cell_after(&mut self, anchor: Uuid, cell: Cell) -> CoreResult<Uuid> {
+        let index = self.index_of(anchor)?;
+        self.insert_cell_at(index + 1, cell)
+    }
+
+    /// Moves a cell so that it ends up at `to_index` in the resulting order.
+    pub fn move_cell(&mut self, id: Uuid, to_index: usize) -> CoreResult<()> {
+        let from = self.index_of(id)?;
+        if to_index >= self.cells.len() {
+            return Err(CoreError::Validation(format!(
+                "move index {to_index} out of range for {} cells",
+                self.cells.len()
+            )));
+        }
+        self.apply_edit(EditOp::MoveCell { from, to: to_index })
+    }
+
+    pub fn delete_cell(&mut self, id: Uuid) -> CoreResult<Cell> {
+        let index = self.index_of(id)?;
+        let cell = self.cells[index].clone();
+        self.apply_edit(EditOp::DeleteCell {
+            index,
+            cell: cell.clone(),
+        })?;
+        Ok(cell)
+    }
+
+    /// Inserts a copy of the cell, outputs included, directly after it.
+    pub fn duplicate_cell(&mut self, id: Uuid) -> CoreResult<Uuid> {
+        let index = self.index_of(id)?;
+        let mut copy = self.cells[index].clone();
+        copy.id = Uuid::new_v4();
+        self.insert_cell_at(index + 1, copy)
+    }
+
+    /// Switches a cell between markdown and code or changes its language.
+    /// Outputs and execution state are reset since they no longer apply.
+    pub fn change_cell_type(&mut self, id: Uuid, cell_type: CellType, language: Option<String>) -> CoreResult<()> {
+        let index = self.index_of(id)?;
+        validate_cell_kind(id, &cell_type, language.as_deref())?;
+        let before = self.cells[index].clone();
+        if before.cell_type == cell_type && before.language == language {
+            return Ok(());
+        }
+        let after = Cell {
+            cell_type,
+            language,
+            outputs: vec![],
+            execution: CellExecution::idle(),
+            ..before.clone()
+        };
+        self.apply_edit(replacement(index, before, after))
+    }
+
+    /// Splits a cell at `cursor`, counted in characters. The head keeps the
+    /// cell's id and outputs; the tail becomes a new cell returned by id. A
+    /// line break at the cursor is dropped, so [`merge_with_next`](Self::merge_with_next)
+    /// restores the original source.
+    pub fn split_cell(&mut self, id: Uuid, cursor: usize) -> CoreResult<Uuid> {
+        let index = self.index_of(id)?;
+        let before = self.cells[index].clone();
+        let mut head = before.clone();
+        let char_count = head.source.chars().count();
+        if cursor == 0 || cursor >= char_count {
+            return Err(CoreError::Validation(format!(
+                "cannot split cell {id} at {cursor}: cursor must fall inside its {char_count} characters"
+            )));
+        }
+        let byte = head
+            .source
+            .char_indices()
+            .nth(cursor)
+            .map(|(byte, _)| byte)
+            .unwrap_or(head.source.len());
+        let mut tail = head.source.split_off(byte);
+        if head.source.ends_with('\n') {
+            head.source.pop();
+        } else if tail.starts_with('\n') {
+            tail.remove(0);
+        }
+        let new_cell = Cell {
+            id: Uuid::new_v4(),
+            cell_type: head.cell_type.clone(),
+            language: head.language.clone(),
+            source: tail,
+            outputs: vec![],
+            execution: CellExecution::idle(),
+            tags: head.tags.clone(),
+            metadata: head.metadata.clone(),
+        };
+        let new_id = new_cell.id;
+        self.apply_edit(EditOp::Batch {
+            ops: vec![
+                EditOp::ReplaceCell {
+                    index,
+                    before,
+                    after: head,
+                },
+                EditOp::InsertCell {
+                    index: index + 1,
+                    cell: new_cell,
+                },
+            ],
+        })?;
+        Ok(new_id)
+    }
+
+    /// Merges the cell with the one following it. Both must have the same type
+    /// and language; sources are joined by a newline, even when one of them is
+    /// empty, and outputs concatenated.
+    pub fn merge_with_next(&mut self, id: Uuid) -> CoreResult<()> {
+        let index = self.index_of(id)?;
+        let Some(next) = self.cells.get(index + 1) else {
+            return Err(CoreError::Validation(format!("cell {id} has no following cell to merge")));
+        };
+        let first = &self.cells[index];
+        if first.cell_type != next.cell_type || first.language != next.language {
+            return Err(CoreError::Validation(format!(
+                "cannot merge cell {id} with {}: type or language differs",
+                next.id
+            )));
+        }
+        let next = next.clone();
+        let before = first.clone();
+        let mut merged = before.clone();
+        merged.source.push('\n');
+        merged.source.push_str(&next.source);
+        merged.outputs.extend(next.outputs.iter().cloned());
+        self.apply_edit(EditOp::Batch {
+            ops: vec![
+                EditOp::DeleteCell {
+                    index: index + 1,
+                    cell: next,
+                },
+                replacement(index, before, merged),
+            ],
+        })
+    }
+
+    /// Replaces a cell's source. Rapid successive edits to the same cell
+    /// coalesce into a single undo step.
+    pub fn set_cell_source(&mut self, id: Uuid, source: impl Into<String>) -> CoreResult<()> {
+        let cell = self.cell(id).ok_or(CoreError::CellNotFound(id))?;
+        let source = source.into();
+        if cell.source == source {
+            return Ok(());
+        }
+        let before = cell.source.clone();
+        self.apply_edit(EditOp::EditSource {
+            cell_id: id,
+            before,
+            after: source,
+        })
+    }
+
+    /// Adds a tag; returns `false` if the cell already had it.
+    pub fn tag_cell(&mut self, id: Uuid, tag: CellTag) -> CoreResult<bool> {
+        self.replace_cell_with(id, |cell| cell.tags.insert(tag))
+    }
+
+    /// Removes a tag; returns `false` if the cell did not have it.
+    pub fn untag_cell(&mut self, id: Uuid, tag: &CellTag) -> CoreResult<bool> {
+        self.replace_cell_with(id, |cell| cell.tags.remove(tag))
+    }
+
+    /// Sets a free-form metadata entry, or removes it when `value` is `None`.
+    pub fn set_cell_metadata(&mut self, id: Uuid, key: &str, value: Option<Value>) -> CoreResult<()> {
+        self.replace_cell_with(id, |cell| match value {
+            Some(value) => cell.metadata.insert(key.to_string(), value.clone()).as_ref() != Some(&value),
+            None => cell.metadata.remove(key).is_some(),
+        })?;
+        Ok(())
+    }
+
+    /// Runs `change` on a copy of the cell and records it as one edit if it
+    /// reports a modification.
+    fn replace_cell_with(&mut self, id: Uuid, change: impl FnOnce(&mut Cell) -> bool) -> CoreResult<bool> {
+        let index = self.index_of(id)?;
+        let before = self.cells[index].clone();
+        let mut after = before.clone();
+        if !change(&mut after) {
+            return Ok(false);
+        }
+        self.apply_edit(EditOp::ReplaceCell { index, before, after })?;
+        Ok(true)
+    }
+
+    /// Swaps in a new version of the cell with the same id, outputs included.
+    pub fn replace_cell(&mut self, cell: Cell) -> CoreResult<()> {
+        validate_cell_kind(cell.id, &cell.cell_type, cell.language.as_deref())?;
+        let index = self.index_of(cell.id)?;
+        let before = self.cells[index].clone();
+        self.apply_edit(replacement(index, before, cell))
+    }
+
+    /// Replaces every cell at once as one undoable step.
+    pub fn replace_cells(&mut self, cells: Vec<Cell>) -> CoreResult<()> {
+        let ops = self.replace_cells_ops(cells)?;
+        self.apply_edit(EditOp::Batch { ops })
+    }
+
+    /// Replaces every cell and the metadata at once, e.g. to restore an
+    /// earlier version, as one undoable step. `updated_at` is bumped as by any
+    /// other edit.
+    pub fn replace_contents(&mut self, cells: Vec<Cell>, metadata: NotebookMetadata) -> CoreResult<()> {
+        let mut ops = self.replace_cells_ops(cells)?;
+        ops.push(EditOp::EditMetadata {
+            before: self.metadata.clone(),
+            after: metadata,
+        });
+        self.apply_edit(EditOp::Batch { ops })
+    }
+
+    fn replace_cells_ops(&self, cells: Vec<Cell>) -> CoreResult<Vec<EditOp>> {
+        for cell in &cells {
+            validate_cell_kind(cell.id, &cell.cell_type, cell.language.as_deref())?;
+        }
+        let removals = self
+            .cells
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, cell)| EditOp::DeleteCell {
+                index,
+                cell: cell.clone(),
+            });
+        let inserts = cells
+            .into_iter()
+            .enumerate()
+            .map(|(index, cell)| EditOp::InsertCell { index, cell });
+        Ok(removals.chain(inserts).collect())
+    }
+
+    /// Replaces a cell's outputs and execution state, e.g. to restore an earlier run.
+    pub fn set_outputs(&mut self, id: Uuid, outputs: Vec<CellOutput>, execution: CellExecution) -> CoreResult<()> {
+        let cell = self.cell(id).ok_or(CoreError::CellNotFound(id))?;
+        self.apply_edit(EditOp::EditOutputs {
+            cell_id: id,
+            before: cell.outputs.clone(),
+            before_execution: cell.execution.clone(),
+            after: outputs,
+            after_execution: execution,
+        })
+    }
+
+    /// Stores what a run of the cell produced. Runs are not edits, so unlike
+    /// [`set_outputs`](Self::set_outputs) this is not an undo step.
+    pub fn record_run(&mut self, id: Uuid, outputs: Vec<CellOutput>, execution: CellExecution) -> CoreResult<()> {
+        let cell = self.cell_mut(id).ok_or(CoreError::CellNotFound(id))?;
+        cell.outputs = outputs;
+        cell.execution = execution;
+        self.touch();
+        Ok(())
+    }
+
+    /// Clears a cell's outputs and resets its execution state.
+    pub fn clear_outputs(&mut self, id: Uuid) -> CoreResult<()> {
+        self.set_outputs(id, vec![], CellExecution::idle())
+    }
+
+    pub fn clear_all_outputs(&mut self) -> CoreResult<()> {
+        let ops = self
+            .cells
+            .iter()
+            .filter(|cell| !cell.outputs.is_empty() || cell.execution.count > 0)
+            .map(|cell| EditOp::EditOutputs {
+                cell_id: cell.id,
+                before: cell.outputs.clone(),
+                before_execution: cell.execution.clone(),
+                after: vec![],
+                after_execution: CellExecution::idle(),
+            })
+            .collect::<Vec<_>>();
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.apply_edit(EditOp::Batch { ops })
+    }
+}
+
+/// The edit turning `before` into `after`. Outputs are only part of it when
+/// they differ, so undoing it keeps whatever a later run produced otherwise.
+fn replacement(index: usize, before: Cell, after: Cell) -> EditOp {
+    if before.outputs == after.outputs && before.execution == after.execution {
+        return EditOp::ReplaceCell { index, before, after };
+    }
+    let outputs = EditOp::EditOutputs {
+        cell_id: after.id,
+        before: before.outputs.clone(),
+        before_execution: before.execution.clone(),
+        after: after.outputs.clone(),
+        after_execution: after.execution.clone(),
+    };
+    EditOp::Batch {
+        ops: vec![EditOp::ReplaceCell { index, before, after }, outputs],
+    }
+}
+
+impl CellExecution {
+    pub fn idle() -> Self {
+        Self {
+            count: 0,
+            status: CellStatus::Idle,
+            duration_ms: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(nb: &Notebook) -> Vec<&str> {
+        nb.cells.iter().map(|c| c.source.as_str()).collect()
+    }
+
+    #[test]
+    fn inserts_moves_and_deletes_by_id() {
+        let mut nb = Notebook::new("edit");
+        let a = nb.add_markdown_cell("a");
+        let c = nb.add_markdown_cell("c");
+        nb.insert_cell_after(a, Cell::new_markdown("b")).expect("insert");
+        nb.insert_cell_before(a, Cell::new_code("go", "start")).expect("insert");
+        assert_eq!(sources(&nb), vec!["start", "a", "b", "c"]);
+
+        nb.move_cell(c, 0).expect("move");
+        assert_eq!(sources(&nb), vec!["c", "start", "a", "b"]);
+
+        let removed = nb.delete_cell(a).expect("delete");
+        assert_eq!(removed.source, "a");
+        assert!(matches!(nb.delete_cell(a), Err(CoreError::CellNotFound(id)) if id == a));
+        assert!(nb.insert_cell_after(c, Cell::new_code("cobol", "")).is_err());
+    }
+
+    #[test]
+    fn duplicates_and_changes_type() {
+        let mut nb = Notebook::new("edit");
+        let id = nb.add_code_cell("go", "x := 1");
+        let copy = nb.duplicate_cell(id).expect("duplicate");
+        assert_ne!(copy, id);
+        assert_eq!(nb.cells[1].source, "x := 1");
+
+        nb.change_cell_type(copy, CellType::Code, Some("ruby".to_string())).expect("language");
+        assert_eq!(nb.cell(copy).unwrap().language.as_deref(), Some("ruby"));
+        assert!(nb.change_cell_type(copy, CellType::Markdown, Some("go".to_string())).is_err());
+        nb.change_cell_type(copy, CellType::Markdown, None).expect("to markdown");
+        assert!(nb.validate().is_ok());
+    }
+
+    #[test]
+    fn splits_and_merges_round_trip() {
+        let mut nb = Notebook::new("edit");
+        let id = nb.add_code_cell("python", "a = 1\nb = 2\nprint(a + b)");
+        let tail = nb.split_cell(id, 5).expect("split");
+        assert_eq!(sources(&nb), vec!["a = 1", "b = 2\nprint(a + b)"]);
+        assert_eq!(nb.cell(tail).unwrap().language.as_deref(), Some("python"));
+        assert!(nb.split_cell(id, 0).is_err());
+
+        nb.merge_with_next(id).expect("merge");
+        assert_eq!(sources(&nb), vec!["a = 1\nb = 2\nprint(a + b)"]);
+        assert!(nb.merge_with_next(id).is_err());
+    }
+
+    #[test]
+    fn split_next_to_a_line_break_merges_back_exactly() {
+        for source in ["a\n\nb", "a\n", "\na", "x\r\ny\n"] {
+            for cursor in 1..source.chars().count() {
+                let mut nb = Notebook::new("edit");
+                let id = nb.add_markdown_cell(source);
+                nb.split_cell(id, cursor).expect("split");
+                nb.merge_with_next(id).expect("merge");
+                let chars = source.chars().collect::<Vec<_>>();
+                if chars[cursor - 1] == '\n' || chars[cursor] == '\n' {
+                    assert_eq!(sources(&nb), vec![source], "{source:?} split at {cursor}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tags_and_metadata_are_undoable() {
+        let mut nb = Notebook::new("edit");
+        let params = nb.add_code_cell("python", "alpha = 0.1");
+        nb.add_code_cell("python", "train(alpha)");
+        assert!(nb.tag_cell(params, CellTag::Parameters).expect("tag"));
+        assert!(!nb.tag_cell(params, CellTag::Parameters).expect("tag again"));
+        nb.set_cell_metadata(params, "owner", Some(Value::from("ml-team"))).expect("metadata");
+
+        let tagged = nb.cells_with_tag(&CellTag::Parameters).map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(tagged, vec![params]);
+        assert_eq!(nb.cells_without_tag(&CellTag::Parameters).count(), 1);
+
+        nb.undo().expect("undo metadata");
+        assert!(nb.cell(params).unwrap().metadata.is_empty());
+        nb.undo().expect("undo tag");
+        assert_eq!(nb.cells_with_tag(&CellTag::Parameters).count(), 0);
+    }
+}
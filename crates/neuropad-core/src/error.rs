@@ -1,3 +1,4 @@
+use crate::schema::SchemaViolation;
 use thiserror::Error;
 
 pub type CoreResult<T> = Result<T, CoreError>;
@@ -10,6 +11,24 @@ pub enum CoreError {
     Json(#[from] serde_json::Error),
     #[error("sqlite error: {0}")]
     Sqlite(#[from] rusqlite::Error),
+    #[error("archive error: {0}")]
+    Archive(#[from] zip::result::ZipError),
+    #[error("file watch error: {0}")]
+    Watch(#[from] notify::Error),
     #[error("validation error: {0}")]
     Validation(String),
+    #[error("cell not found: {0}")]
+    CellNotFound(uuid::Uuid),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("schema validation failed: {}", join_violations(.0))]
+    Schema(Vec<SchemaViolation>),
+}
+
+fn join_violations(violations: &[SchemaViolation]) -> String {
+    violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
 }
@@ -0,0 +1,441 @@
+use crate::{Cell, CellExecution, CellOutput, CoreError, CoreResult, Notebook, NotebookMetadata};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const DEFAULT_HISTORY_LIMIT: usize = 200;
+
+/// Consecutive source edits to the same cell within this window undo as one step.
+pub const COALESCE_WINDOW_MS: i64 = 1_000;
+
+/// An invertible notebook edit. Index-based operations record the cell they
+/// expect so a stale history fails loudly instead of editing the wrong cell.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum EditOp {
+    InsertCell {
+        index: usize,
+        cell: Cell,
+    },
+    DeleteCell {
+        index: usize,
+        cell: Cell,
+    },
+    MoveCell {
+        from: usize,
+        to: usize,
+    },
+    /// Swaps everything but the cell's outputs and execution state, which
+    /// only [`EditOutputs`](EditOp::EditOutputs) changes, so undoing a tag or
+    /// type change never rolls back a run made after it.
+    ReplaceCell {
+        index: usize,
+        before: Cell,
+        after: Cell,
+    },
+    EditSource {
+        cell_id: Uuid,
+        before: String,
+        after: String,
+    },
+    EditOutputs {
+        cell_id: Uuid,
+        before: Vec<CellOutput>,
+        before_execution: CellExecution,
+        after: Vec<CellOutput>,
+        after_execution: CellExecution,
+    },
+    /// Everything in the notebook's metadata but `updated_at`, which the edit
+    /// itself bumps.
+    EditMetadata {
+        before: NotebookMetadata,
+        after: NotebookMetadata,
+    },
+    Batch {
+        ops: Vec<EditOp>,
+    },
+}
+
+impl EditOp {
+    pub fn inverse(&self) -> EditOp {
+        match self.clone() {
+            EditOp::InsertCell { index, cell } => EditOp::DeleteCell { index, cell },
+            EditOp::DeleteCell { index, cell } => EditOp::InsertCell { index, cell },
+            EditOp::MoveCell { from, to } => EditOp::MoveCell { from: to, to: from },
+            EditOp::ReplaceCell { index, before, after } => EditOp::ReplaceCell {
+                index,
+                before: after,
+                after: before,
+            },
+            EditOp::EditSource { cell_id, before, after } => EditOp::EditSource {
+                cell_id,
+                before: after,
+                after: before,
+            },
+            EditOp::EditOutputs {
+                cell_id,
+                before,
+                before_execution,
+                after,
+                after_execution,
+            } => EditOp::EditOutputs {
+                cell_id,
+                before: after,
+                before_execution: after_execution,
+                after: before,
+                after_execution: before_execution,
+            },
+            EditOp::EditMetadata { before, after } => EditOp::EditMetadata {
+                before: after,
+                after: before,
+            },
+            EditOp::Batch { ops } => EditOp::Batch {
+                ops: ops.iter().rev().map(EditOp::inverse).collect(),
+            },
+        }
+    }
+
+    /// Every cell the operation inserts, deletes or modifies.
+    pub fn cell_ids(&self) -> Vec<Uuid> {
+        match self {
+            EditOp::InsertCell { cell, .. } | EditOp::DeleteCell { cell, .. } => vec![cell.id],
+            EditOp::MoveCell { .. } | EditOp::EditMetadata { .. } => vec![],
+            EditOp::ReplaceCell { after, .. } => vec![after.id],
+            EditOp::EditSource { cell_id, .. } | EditOp::EditOutputs { cell_id, .. } => vec![*cell_id],
+            EditOp::Batch { ops } => ops.iter().flat_map(EditOp::cell_ids).collect(),
+        }
+    }
+
+    /// Whether the operation adds, removes or moves cells.
+    pub fn reorders(&self) -> bool {
+        match self {
+            EditOp::InsertCell { .. } | EditOp::DeleteCell { .. } | EditOp::MoveCell { .. } => true,
+            EditOp::ReplaceCell { .. }
+            | EditOp::EditSource { .. }
+            | EditOp::EditOutputs { .. }
+            | EditOp::EditMetadata { .. } => false,
+            EditOp::Batch { ops } => ops.iter().any(EditOp::reorders),
+        }
+    }
+
+    /// Applies the operation without recording it.
+    pub(crate) fn apply(&self, notebook: &mut Notebook) -> CoreResult<()> {
+        match self {
+            EditOp::InsertCell { index, cell } => {
+                if *index > notebook.cells.len() {
+                    return Err(stale(format!("insert index {index} out of range")));
+                }
+                notebook.cells.insert(*index, cell.clone());
+            }
+            EditOp::DeleteCell { index, cell } => {
+                expect_cell_at(notebook, *index, cell.id)?;
+                notebook.cells.remove(*index);
+            }
+            EditOp::MoveCell { from, to } => {
+                if *from >= notebook.cells.len() || *to >= notebook.cells.len() {
+                    return Err(stale(format!("move {from} -> {to} out of range")));
+                }
+                let cell = notebook.cells.remove(*from);
+                notebook.cells.insert(*to, cell);
+            }
+            EditOp::ReplaceCell { index, before, after } => {
+                expect_cell_at(notebook, *index, before.id)?;
+                let current = &mut notebook.cells[*index];
+                *current = Cell {
+                    outputs: std::mem::take(&mut current.outputs),
+                    execution: current.execution.clone(),
+                    ..after.clone()
+                };
+            }
+            EditOp::EditSource { cell_id, after, .. } => {
+                let cell = notebook.cell_mut(*cell_id).ok_or(CoreError::CellNotFound(*cell_id))?;
+                cell.source = after.clone();
+            }
+            EditOp::EditOutputs {
+                cell_id,
+                after,
+                after_execution,
+                ..
+            } => {
+                let cell = notebook.cell_mut(*cell_id).ok_or(CoreError::CellNotFound(*cell_id))?;
+                cell.outputs = after.clone();
+                cell.execution = after_execution.clone();
+            }
+            EditOp::EditMetadata { after, .. } => {
+                notebook.metadata = NotebookMetadata {
+                    updated_at: notebook.metadata.updated_at,
+                    ..after.clone()
+                };
+            }
+            EditOp::Batch { ops } => {
+                let snapshot = (notebook.cells.clone(), notebook.metadata.clone());
+                for op in ops {
+                    if let Err(err) = op.apply(notebook) {
+                        (notebook.cells, notebook.metadata) = snapshot;
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn expect_cell_at(notebook: &Notebook, index: usize, id: Uuid) -> CoreResult<()> {
+    match notebook.cells.get(index) {
+        Some(cell) if cell.id == id => Ok(()),
+        _ => Err(stale(format!("expected cell {id} at index {index}"))),
+    }
+}
+
+fn stale(message: String) -> CoreError {
+    CoreError::Validation(format!("edit history out of sync with notebook: {message}"))
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub op: EditOp,
+    pub at: DateTime<Utc>,
+}
+
+/// Bounded undo/redo stacks of [`EditOp`]s.
+#[derive(Debug, Clone)]
+pub struct History {
+    undo: Vec<HistoryEntry>,
+    redo: Vec<HistoryEntry>,
+    limit: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::with_limit(DEFAULT_HISTORY_LIMIT)
+    }
+}
+
+impl History {
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            undo: vec![],
+            redo: vec![],
+            limit,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty() && self.redo.is_empty()
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// The edit the next undo reverts.
+    pub fn last_done(&self) -> Option<&EditOp> {
+        self.undo.last().map(|entry| &entry.op)
+    }
+
+    /// The edit the next redo reapplies.
+    pub fn last_undone(&self) -> Option<&EditOp> {
+        self.redo.last().map(|entry| &entry.op)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    pub(crate) fn record(&mut self, op: EditOp) {
+        let now = Utc::now();
+        self.redo.clear();
+        if let (
+            EditOp::EditSource { cell_id, after, .. },
+            Some(HistoryEntry {
+                op: EditOp::EditSource {
+                    cell_id: last_id,
+                    after: last_after,
+                    ..
+                },
+                at,
+            }),
+        ) = (&op, self.undo.last_mut())
+        {
+            if cell_id == last_id && now - *at < Duration::milliseconds(COALESCE_WINDOW_MS) {
+                *last_after = after.clone();
+                *at = now;
+                return;
+            }
+        }
+        self.undo.push(HistoryEntry { op, at: now });
+        if self.undo.len() > self.limit {
+            let overflow = self.undo.len() - self.limit;
+            self.undo.drain(..overflow);
+        }
+    }
+}
+
+impl Notebook {
+    /// This session's undo and redo stacks.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Applies an edit, records it for undo and bumps `updated_at`. Callers
+    /// validate the cells it introduces; see the public methods in `edit.rs`.
+    pub(crate) fn apply_edit(&mut self, op: EditOp) -> CoreResult<()> {
+        op.apply(self)?;
+        self.history.record(op);
+        self.touch();
+        Ok(())
+    }
+
+    /// Reverts the most recent edit. Returns `false` when there is nothing to undo.
+    pub fn undo(&mut self) -> CoreResult<bool> {
+        let Some(entry) = self.history.undo.pop() else {
+            return Ok(false);
+        };
+        if let Err(err) = entry.op.inverse().apply(self) {
+            self.history.undo.push(entry);
+            return Err(err);
+        }
+        self.history.redo.push(entry);
+        self.touch();
+        Ok(true)
+    }
+
+    /// Reapplies the most recently undone edit. Returns `false` when there is nothing to redo.
+    pub fn redo(&mut self) -> CoreResult<bool> {
+        let Some(entry) = self.history.redo.pop() else {
+            return Ok(false);
+        };
+        if let Err(err) = entry.op.apply(self) {
+            self.history.redo.push(entry);
+            return Err(err);
+        }
+        self.history.undo.push(entry);
+        self.touch();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CellOutputKind, CellStatus, CellTag};
+
+    fn sources(nb: &Notebook) -> Vec<&str> {
+        nb.cells.iter().map(|c| c.source.as_str()).collect()
+    }
+
+    #[test]
+    fn undoes_and_redoes_structural_edits() {
+        let mut nb = Notebook::new("history");
+        let a = nb.add_markdown_cell("a");
+        nb.history.clear();
+
+        let b = nb.insert_cell_after(a, Cell::new_code("go", "b")).expect("insert");
+        nb.move_cell(b, 0).expect("move");
+        nb.delete_cell(a).expect("delete");
+        assert_eq!(sources(&nb), vec!["b"]);
+
+        assert!(nb.undo().expect("undo delete"));
+        assert_eq!(sources(&nb), vec!["b", "a"]);
+        assert!(nb.undo().expect("undo move"));
+        assert!(nb.undo().expect("undo insert"));
+        assert_eq!(sources(&nb), vec!["a"]);
+        assert!(!nb.undo().expect("nothing left"));
+
+        assert!(nb.redo().expect("redo insert"));
+        assert!(nb.redo().expect("redo move"));
+        assert_eq!(sources(&nb), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn coalesces_typing_and_is_never_serialized() {
+        let mut nb = Notebook::new("history");
+        let id = nb.add_code_cell("python", "");
+        for text in ["p", "pr", "pri", "print(1)"] {
+            nb.set_cell_source(id, text).expect("type");
+        }
+        let json = serde_json::to_value(&nb).expect("serialize");
+        assert!(json.get("history").is_none());
+        let mut reloaded: Notebook = serde_json::from_value(json).expect("reload");
+        assert!(!reloaded.undo().expect("nothing to undo after reload"));
+
+        assert!(nb.undo().expect("undo typing"));
+        assert_eq!(nb.cells[0].source, "");
+        assert!(nb.undo().expect("undo add"));
+        assert!(nb.cells.is_empty());
+    }
+
+    #[test]
+    fn restores_cleared_outputs_and_bounds_stack() {
+        let mut nb = Notebook::new("history");
+        let id = nb.add_code_cell("ruby", "1 + 1");
+        nb.cells[0].outputs.push(CellOutput {
+            kind: CellOutputKind::Result,
+            mime: "text/plain".to_string(),
+            data: "2".to_string(),
+            created_at: Utc::now(),
+        });
+        nb.clear_outputs(id).expect("clear");
+        assert!(nb.cells[0].outputs.is_empty());
+        nb.undo().expect("undo clear");
+        assert_eq!(nb.cells[0].outputs[0].data, "2");
+
+        nb.history = History::with_limit(3);
+        for i in 0..5 {
+            nb.add_markdown_cell(format!("{i}"));
+        }
+        let mut undone = 0;
+        while nb.undo().expect("undo") {
+            undone += 1;
+        }
+        assert_eq!(undone, 3);
+    }
+
+    #[test]
+    fn runs_and_clones_leave_history_alone() {
+        let mut nb = Notebook::new("history");
+        let id = nb.add_code_cell("go", "fmt.Println(1)");
+        let execution = CellExecution {
+            count: 1,
+            status: CellStatus::Ok,
+            duration_ms: 3,
+        };
+        nb.record_run(id, vec![], execution).expect("run");
+        assert_eq!(nb.cells[0].execution.count, 1);
+        assert!(matches!(nb.history().last_done(), Some(EditOp::InsertCell { .. })));
+
+        assert!(nb.clone().history().is_empty());
+    }
+
+    #[test]
+    fn undoing_a_tag_keeps_a_later_run() {
+        let mut nb = Notebook::new("history");
+        let id = nb.add_code_cell("python", "print(1)");
+        nb.tag_cell(id, CellTag::Skip).expect("tag");
+        let output = CellOutput {
+            kind: CellOutputKind::Stdout,
+            mime: "text/plain".to_string(),
+            data: "1\n".to_string(),
+            created_at: Utc::now(),
+        };
+        let execution = CellExecution {
+            count: 1,
+            status: CellStatus::Ok,
+            duration_ms: 3,
+        };
+        nb.record_run(id, vec![output.clone()], execution.clone()).expect("run");
+
+        nb.undo().expect("undo tag");
+        assert!(nb.cells[0].tags.is_empty());
+        assert_eq!(nb.cells[0].outputs, vec![output]);
+        assert_eq!(nb.cells[0].execution, execution);
+        nb.redo().expect("redo tag");
+        assert_eq!(nb.cells[0].execution, execution);
+    }
+}
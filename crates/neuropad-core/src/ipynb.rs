@@ -1,7 +1,9 @@
-use crate::notebook::{Cell, CellOutput, CellOutputKind, CellStatus, CellType, Notebook};
+use crate::notebook::{Cell, CellOutput, CellOutputKind, CellStatus, CellTag, CellType, Notebook};
+use crate::storage::{self, FileStamp, SaveOptions};
 use crate::{CoreError, CoreResult};
 use chrono::Utc;
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
 use std::fs;
 use std::path::Path;
 use uuid::Uuid;
@@ -28,7 +30,9 @@ pub fn import_ipynb<P: AsRef<Path>>(path: P) -> CoreResult<Notebook> {
             .unwrap_or_default();
         let source = source_from_ipynb(raw_cell.get("source"));
         if cell_type == "markdown" {
-            notebook.cells.push(Cell::new_markdown(source));
+            let mut cell = Cell::new_markdown(source);
+            apply_cell_metadata_from_ipynb(&mut cell, raw_cell);
+            notebook.cells.push(cell);
             continue;
         }
 
@@ -51,6 +55,7 @@ pub fn import_ipynb<P: AsRef<Path>>(path: P) -> CoreResult<Notebook> {
             if cell.execution.count > 0 {
                 cell.execution.status = CellStatus::Ok;
             }
+            apply_cell_metadata_from_ipynb(&mut cell, raw_cell);
             notebook.cells.push(cell);
         }
     }
@@ -60,6 +65,15 @@ pub fn import_ipynb<P: AsRef<Path>>(path: P) -> CoreResult<Notebook> {
 }
 
 pub fn export_ipynb<P: AsRef<Path>>(notebook: &Notebook, path: P) -> CoreResult<()> {
+    export_ipynb_with(notebook, path, &SaveOptions::default())?;
+    Ok(())
+}
+
+pub fn export_ipynb_with<P: AsRef<Path>>(
+    notebook: &Notebook,
+    path: P,
+    options: &SaveOptions,
+) -> CoreResult<FileStamp> {
     notebook.validate()?;
     let cells = notebook
         .cells
@@ -67,7 +81,7 @@ pub fn export_ipynb<P: AsRef<Path>>(notebook: &Notebook, path: P) -> CoreResult<
         .map(|cell| match cell.cell_type {
             CellType::Markdown => json!({
                 "cell_type": "markdown",
-                "metadata": {},
+                "metadata": cell_metadata_to_ipynb(cell),
                 "source": split_lines(&cell.source),
             }),
             CellType::Code => {
@@ -75,9 +89,7 @@ pub fn export_ipynb<P: AsRef<Path>>(notebook: &Notebook, path: P) -> CoreResult<
                 json!({
                     "cell_type": "code",
                     "execution_count": cell.execution.count,
-                    "metadata": {
-                        "language": cell.language.clone().unwrap_or_else(|| "go".to_string())
-                    },
+                    "metadata": cell_metadata_to_ipynb(cell),
                     "source": split_lines(&cell.source),
                     "outputs": outputs
                 })
@@ -96,8 +108,8 @@ pub fn export_ipynb<P: AsRef<Path>>(notebook: &Notebook, path: P) -> CoreResult<
         "nbformat": 4,
         "nbformat_minor": 5
     });
-    fs::write(path, serde_json::to_string_pretty(&root)?)?;
-    Ok(())
+    let data = serde_json::to_string_pretty(&root)?;
+    storage::write_atomic(path, data.as_bytes(), options)
 }
 
 fn detect_language(cell: &Value) -> Option<String> {
@@ -109,6 +121,39 @@ fn detect_language(cell: &Value) -> Option<String> {
     }
 }
 
+/// Splits ipynb cell metadata into `metadata.tags` and the free-form map,
+/// dropping the `language` key that NeuroPad manages itself.
+fn apply_cell_metadata_from_ipynb(cell: &mut Cell, raw_cell: &Value) {
+    let Some(metadata) = raw_cell.get("metadata").and_then(Value::as_object) else {
+        return;
+    };
+    cell.tags = metadata
+        .get("tags")
+        .and_then(Value::as_array)
+        .map(|tags| tags.iter().filter_map(Value::as_str).map(CellTag::from).collect())
+        .unwrap_or_else(BTreeSet::new);
+    cell.metadata = metadata
+        .iter()
+        .filter(|(key, _)| key.as_str() != "tags" && key.as_str() != "language")
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+}
+
+fn cell_metadata_to_ipynb(cell: &Cell) -> Value {
+    let mut metadata = cell.metadata.clone();
+    if cell.cell_type == CellType::Code {
+        metadata.insert(
+            "language".to_string(),
+            Value::String(cell.language.clone().unwrap_or_else(|| "go".to_string())),
+        );
+    }
+    if !cell.tags.is_empty() {
+        let tags = cell.tags.iter().map(|tag| Value::String(tag.as_str().to_string()));
+        metadata.insert("tags".to_string(), Value::Array(tags.collect()));
+    }
+    Value::Object(metadata)
+}
+
 fn source_from_ipynb(source: Option<&Value>) -> String {
     match source {
         Some(Value::String(s)) => s.clone(),
@@ -217,5 +262,7 @@ pub fn new_code_cell_for_import(language: &str, source: &str) -> Cell {
             status: CellStatus::Idle,
             duration_ms: 0,
         },
+        tags: BTreeSet::new(),
+        metadata: Map::new(),
     }
 }
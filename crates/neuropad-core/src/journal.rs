@@ -0,0 +1,43 @@
+use crate::storage::{self, SaveOptions};
+use crate::{CoreResult, Notebook};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Directory of crash-recovery snapshots, one file per editing session.
+pub struct RecoveryJournal {
+    dir: PathBuf,
+}
+
+impl RecoveryJournal {
+    pub fn open<P: AsRef<Path>>(dir: P) -> CoreResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn snapshot_path(&self, session_id: Uuid) -> PathBuf {
+        self.dir.join(format!("{session_id}.npad"))
+    }
+
+    /// Writes the notebook as-is: unsaved work is kept even if it fails validation.
+    pub fn write_snapshot(&self, session_id: Uuid, notebook: &Notebook) -> CoreResult<PathBuf> {
+        let path = self.snapshot_path(session_id);
+        let data = serde_json::to_string(notebook)?;
+        storage::write_atomic(&path, data.as_bytes(), &SaveOptions::default())?;
+        Ok(path)
+    }
+
+    pub fn read_snapshot(&self, session_id: Uuid) -> CoreResult<Notebook> {
+        let data = fs::read_to_string(self.snapshot_path(session_id))?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    pub fn remove_snapshot(&self, session_id: Uuid) -> CoreResult<()> {
+        match fs::remove_file(self.snapshot_path(session_id)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
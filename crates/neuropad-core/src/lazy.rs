@@ -0,0 +1,458 @@
+use crate::digest::HashingReader;
+use crate::history::History;
+use crate::notebook::validate_cell_kind;
+use crate::storage::FileStamp;
+use crate::{
+    Cell, CellExecution, CellOutput, CellTag, CellType, CoreError, CoreResult, Notebook, NotebookMetadata,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Where a cell's outputs array sits in the file and how many entries it
+/// has. The outputs themselves are skipped while parsing.
+#[derive(Debug, Clone, Copy, Default)]
+struct OutputsAt {
+    start: u64,
+    end: u64,
+    count: usize,
+}
+
+/// An object or array the [`OutputsScanner`] is inside of.
+struct Frame {
+    is_object: bool,
+    /// The last key read, for objects.
+    key: Option<String>,
+    expect_key: bool,
+    /// Values started directly inside, for arrays.
+    elements: usize,
+    /// Whether this is the outputs array of a cell.
+    outputs: bool,
+}
+
+/// Follows the JSON structure of every byte read through it, independently
+/// of the parser consuming them, and notes where each entry of the top-level
+/// `cells` array has its `outputs` array.
+struct OutputsScanner<R> {
+    inner: R,
+    position: u64,
+    stack: Vec<Frame>,
+    in_string: bool,
+    escaped: bool,
+    in_scalar: bool,
+    /// Raw bytes of the key being read, quotes included.
+    key: Option<Vec<u8>>,
+    cells: Vec<OutputsAt>,
+}
+
+impl<R> OutputsScanner<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            position: 0,
+            stack: vec![],
+            in_string: false,
+            escaped: false,
+            in_scalar: false,
+            key: None,
+            cells: vec![],
+        }
+    }
+
+    /// The reader and, in order, where the outputs of each cell are.
+    fn finish(self) -> (R, Vec<OutputsAt>) {
+        (self.inner, self.cells)
+    }
+
+    /// Whether the innermost frames are the top-level `cells` array and,
+    /// `depth` levels below it, a cell.
+    fn in_cells(&self, depth: usize) -> bool {
+        self.stack.len() == 2 + depth
+            && self.stack[0].is_object
+            && self.stack[0].key.as_deref() == Some("cells")
+            && !self.stack[1].is_object
+    }
+
+    fn scan(&mut self, byte: u8) {
+        if self.in_string {
+            if let Some(key) = &mut self.key {
+                key.push(byte);
+            }
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+                if let Some(key) = self.key.take() {
+                    let key = serde_json::from_slice::<String>(&key)
+                        .unwrap_or_else(|_| String::from_utf8_lossy(&key).into_owned());
+                    if let Some(frame) = self.stack.last_mut() {
+                        frame.key = Some(key);
+                    }
+                }
+            }
+            return;
+        }
+        let is_scalar = !matches!(byte, b'{' | b'}' | b'[' | b']' | b',' | b':' | b'"') && !byte.is_ascii_whitespace();
+        if !is_scalar {
+            self.in_scalar = false;
+        }
+        match byte {
+            b'"' if self.stack.last().is_some_and(|frame| frame.is_object && frame.expect_key) => {
+                self.in_string = true;
+                self.key = Some(vec![byte]);
+            }
+            b'"' => {
+                self.start_value();
+                self.in_string = true;
+            }
+            b'{' | b'[' => {
+                self.start_value();
+                let outputs = byte == b'[' && self.in_cells(1) && self.stack[2].key.as_deref() == Some("outputs");
+                if outputs {
+                    if let Some(cell) = self.cells.last_mut() {
+                        cell.start = self.position;
+                    }
+                }
+                self.stack.push(Frame {
+                    is_object: byte == b'{',
+                    key: None,
+                    expect_key: byte == b'{',
+                    elements: 0,
+                    outputs,
+                });
+            }
+            b'}' | b']' => {
+                if let Some(frame) = self.stack.pop() {
+                    if frame.outputs {
+                        if let Some(cell) = self.cells.last_mut() {
+                            cell.end = self.position + 1;
+                            cell.count = frame.elements;
+                        }
+                    }
+                }
+            }
+            b',' => {
+                if let Some(frame) = self.stack.last_mut() {
+                    frame.expect_key = frame.is_object;
+                }
+            }
+            b':' => {
+                if let Some(frame) = self.stack.last_mut() {
+                    frame.expect_key = false;
+                }
+            }
+            _ if is_scalar && !self.in_scalar => {
+                self.start_value();
+                self.in_scalar = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn start_value(&mut self) {
+        if self.in_cells(0) {
+            self.cells.push(OutputsAt::default());
+        }
+        if let Some(frame) = self.stack.last_mut() {
+            if !frame.is_object {
+                frame.elements += 1;
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for OutputsScanner<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for &byte in &buf[..n] {
+            self.scan(byte);
+            self.position += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// Length and mtime of the file as opened, checked before reading outputs
+/// back so a file changed since is not misread.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Opened {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Opened {
+    fn of(file: &File) -> io::Result<Self> {
+        let meta = file.metadata()?;
+        Ok(Self {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+/// A cell whose outputs stay on disk until requested.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LazyCell {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub cell_type: CellType,
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(deserialize_with = "crate::canonical::deserialize_source")]
+    pub source: String,
+    #[serde(skip)]
+    outputs: OutputsAt,
+    pub execution: CellExecution,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<CellTag>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
+}
+
+impl LazyCell {
+    pub fn has_outputs(&self) -> bool {
+        self.outputs.count > 0
+    }
+
+    /// Size of the deferred output payload in bytes, without reading it.
+    pub fn outputs_size(&self) -> u64 {
+        self.outputs.end - self.outputs.start
+    }
+
+    fn into_cell(self, outputs: Vec<CellOutput>) -> Cell {
+        Cell {
+            id: self.id,
+            cell_type: self.cell_type,
+            language: self.language,
+            source: self.source,
+            outputs,
+            execution: self.execution,
+            tags: self.tags,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// A `.npad` notebook opened for paging. One streaming pass parses sources
+/// and metadata and notes where each cell's outputs are; output payloads are
+/// read back from the file only when a cell's outputs are asked for.
+#[derive(Debug, Deserialize)]
+pub struct LazyNotebook {
+    pub version: String,
+    pub metadata: NotebookMetadata,
+    cells: Vec<LazyCell>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    opened: Opened,
+}
+
+impl LazyNotebook {
+    pub fn open<P: AsRef<Path>>(path: P) -> CoreResult<Self> {
+        Ok(Self::open_stamped(path)?.0)
+    }
+
+    /// Also returns the stamp of the file as parsed, for saving over it
+    /// later. Reading outputs back is refused once its length or mtime differ.
+    pub fn open_stamped<P: AsRef<Path>>(path: P) -> CoreResult<(Self, FileStamp)> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let opened = Opened::of(&file)?;
+        let mut scanner = OutputsScanner::new(HashingReader::new(BufReader::new(file)));
+        let mut notebook: Self = serde_json::from_reader(&mut scanner)?;
+        let (reader, outputs) = scanner.finish();
+        let (len, sha256) = reader.finish();
+        if outputs.len() != notebook.cells.len() {
+            return Err(CoreError::Validation(format!(
+                "found {} cells but {} outputs positions",
+                notebook.cells.len(),
+                outputs.len()
+            )));
+        }
+        for (cell, outputs) in notebook.cells.iter_mut().zip(outputs) {
+            cell.outputs = outputs;
+        }
+        if notebook.version.trim().is_empty() {
+            return Err(CoreError::Validation("version cannot be empty".to_string()));
+        }
+        for cell in &notebook.cells {
+            validate_cell_kind(cell.id, &cell.cell_type, cell.language.as_deref())?;
+        }
+        notebook.path = path.to_path_buf();
+        notebook.opened = opened;
+        Ok((notebook, FileStamp { len, sha256 }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn cell(&self, index: usize) -> Option<&LazyCell> {
+        self.cells.get(index)
+    }
+
+    pub fn position(&self, id: Uuid) -> Option<usize> {
+        self.cells.iter().position(|cell| cell.id == id)
+    }
+
+    /// Returns up to `limit` cells starting at `offset`; empty past the end.
+    pub fn page(&self, offset: usize, limit: usize) -> &[LazyCell] {
+        let start = offset.min(self.cells.len());
+        let end = start.saturating_add(limit).min(self.cells.len());
+        &self.cells[start..end]
+    }
+
+    pub fn outputs(&self, index: usize) -> CoreResult<Vec<CellOutput>> {
+        let cell = self
+            .cells
+            .get(index)
+            .ok_or_else(|| CoreError::Validation(format!("cell index {index} out of range")))?;
+        if !cell.has_outputs() {
+            return Ok(vec![]);
+        }
+        read_outputs(&mut self.reopen()?, &cell.outputs)
+    }
+
+    pub fn into_notebook(self) -> CoreResult<Notebook> {
+        let mut file = self.reopen()?;
+        let cells = self
+            .cells
+            .into_iter()
+            .map(|cell| {
+                let outputs = if cell.has_outputs() {
+                    read_outputs(&mut file, &cell.outputs)?
+                } else {
+                    vec![]
+                };
+                Ok(cell.into_cell(outputs))
+            })
+            .collect::<CoreResult<Vec<_>>>()?;
+        Ok(Notebook {
+            version: self.version,
+            metadata: self.metadata,
+            cells,
+            history: History::default(),
+        })
+    }
+
+    /// Opens the file again to read outputs back, refusing with
+    /// [`CoreError::Conflict`] if it is no longer the one parsed.
+    fn reopen(&self) -> CoreResult<File> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(CoreError::Conflict(format!(
+                    "{} was removed since it was opened",
+                    self.path.display()
+                )))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if Opened::of(&file)? != self.opened {
+            return Err(CoreError::Conflict(format!(
+                "{} was modified on disk since it was opened",
+                self.path.display()
+            )));
+        }
+        Ok(file)
+    }
+}
+
+fn read_outputs(file: &mut File, at: &OutputsAt) -> CoreResult<Vec<CellOutput>> {
+    file.seek(SeekFrom::Start(at.start))?;
+    let mut bytes = vec![];
+    file.take(at.end - at.start).read_to_end(&mut bytes)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sample() -> String {
+        let mut nb = Notebook::new("lazy");
+        for i in 0..5 {
+            nb.add_code_cell("go", format!("fmt.Println({i})"));
+        }
+        nb.cells[3].outputs.push(CellOutput {
+            kind: crate::CellOutputKind::Stdout,
+            mime: "text/plain".to_string(),
+            data: "3\n".to_string(),
+            created_at: chrono::Utc::now(),
+        });
+        serde_json::to_string_pretty(&nb).expect("serialize")
+    }
+
+    #[test]
+    fn pages_cells_and_defers_outputs() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("lazy.npad");
+        fs::write(&path, sample()).expect("write");
+
+        let (lazy, stamp) = LazyNotebook::open_stamped(&path).expect("open");
+        assert_eq!(Some(stamp), FileStamp::of_file(&path).expect("stamp"));
+        assert_eq!(lazy.len(), 5);
+        assert_eq!(lazy.page(3, 10).len(), 2);
+        assert!(lazy.page(9, 10).is_empty());
+        assert!(!lazy.cell(0).unwrap().has_outputs());
+        assert!(lazy.cell(3).unwrap().has_outputs());
+        assert!(lazy.cell(3).unwrap().outputs_size() > lazy.cell(0).unwrap().outputs_size());
+        assert_eq!(lazy.outputs(3).expect("outputs")[0].data, "3\n");
+
+        let nb = lazy.into_notebook().expect("materialize");
+        assert_eq!(nb.cells[4].source, "fmt.Println(4)");
+        assert_eq!(nb.cells[3].outputs[0].data, "3\n");
+    }
+
+    #[test]
+    fn malformed_outputs_only_fail_when_requested() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("bogus.npad");
+        fs::write(&path, sample().replacen("\"kind\": \"stdout\"", "\"kind\": \"bogus\"", 1)).expect("write");
+
+        let lazy = LazyNotebook::open(&path).expect("sources still parse");
+        assert!(lazy.outputs(0).expect("untouched cell").is_empty());
+        assert!(lazy.outputs(3).is_err());
+    }
+
+    #[test]
+    fn finds_outputs_past_escapes_and_odd_whitespace() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("escaped.npad");
+        let json = sample()
+            .replace("fmt.Println(3)", "fmt.Println(\\\"outputs\\\": [\\\\]}\\u00e9\\\\)")
+            .replace("\"outputs\": [", "\"outp\\u0075ts\"\t\r\n :  \n[")
+            .replace("\"execution\"", "\"outputs_note\": [[1, \"]\"], {\"a\": \"}\"}], \"execution\"");
+        fs::write(&path, json).expect("write");
+
+        let lazy = LazyNotebook::open(&path).expect("open");
+        assert_eq!(lazy.cell(3).unwrap().source, "fmt.Println(\"outputs\": [\\]}\u{e9}\\)");
+        assert!(!lazy.cell(2).unwrap().has_outputs());
+        assert_eq!(lazy.outputs(3).expect("outputs")[0].data, "3\n");
+        assert_eq!(lazy.into_notebook().expect("materialize").cells[3].outputs.len(), 1);
+    }
+
+    #[test]
+    fn refuses_to_read_outputs_from_a_changed_file() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("changed.npad");
+        fs::write(&path, sample()).expect("write");
+        let lazy = LazyNotebook::open(&path).expect("open");
+
+        fs::write(&path, format!("  {}", sample())).expect("rewrite");
+        assert!(matches!(lazy.outputs(3), Err(CoreError::Conflict(_))));
+        assert!(matches!(lazy.into_notebook(), Err(CoreError::Conflict(_))));
+    }
+}
@@ -1,11 +1,42 @@
+pub mod archive;
+pub mod canonical;
+pub mod diff;
+pub mod digest;
+pub mod edit;
 pub mod error;
+pub mod history;
 pub mod ipynb;
+pub mod journal;
+pub mod lazy;
+pub mod merge;
 pub mod metadata;
+pub mod migrations;
 pub mod notebook;
+pub mod paths;
+pub mod schema;
+pub mod snapshots;
+pub mod storage;
+pub mod strip;
+pub mod workspace;
 
+pub use canonical::CanonicalOptions;
+pub use diff::{diff_notebooks, NotebookDiff};
 pub use error::{CoreError, CoreResult};
-pub use metadata::MetadataStore;
+pub use history::{EditOp, History};
+pub use journal::RecoveryJournal;
+pub use lazy::{LazyCell, LazyNotebook};
+pub use merge::{merge_notebooks, MergeConflict, MergeOutcome};
+pub use metadata::{
+    CellRun, CellRunRecord, CellSearchHit, IndexQuery, IndexSort, IndexedNotebook, MetadataStore, RecentNotebook,
+    RecoverySession, SnippetPart,
+};
 pub use notebook::{
-    Cell, CellExecution, CellOutput, CellOutputKind, CellStatus, CellType, Notebook,
+    Cell, CellExecution, CellOutput, CellOutputKind, CellStatus, CellTag, CellType, Notebook,
     NotebookMetadata,
 };
+pub use paths::{resolve_data_dir, AppConfig};
+pub use schema::SchemaViolation;
+pub use snapshots::NotebookVersion;
+pub use storage::{FileStamp, SaveOptions};
+pub use strip::{strip_notebook, StripOptions};
+pub use workspace::{ScanReport, Workspace, WorkspaceFile, WorkspaceScan, WorkspaceWatcher};
@@ -0,0 +1,496 @@
+use crate::diff::{lcs_pairs, snapshots};
+use crate::{Cell, CellExecution, CellTag, Notebook};
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    Metadata,
+    CellType,
+    Source,
+    CellMetadata,
+    ModifyDelete,
+    CellOrder,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub kind: ConflictKind,
+    #[serde(default)]
+    pub cell_id: Option<Uuid>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeOutcome {
+    pub notebook: Notebook,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeOutcome {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// One region of a three-way merge: either agreed on or changed differently
+/// by both sides.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Merged<T> {
+    Resolved(Vec<T>),
+    Conflict { ours: Vec<T>, base: Vec<T>, theirs: Vec<T> },
+}
+
+/// Classic diff3: regions between items that all three sequences share are
+/// taken from whichever side changed them, or reported as conflicts.
+pub fn merge_sequences<T: Clone + PartialEq>(base: &[T], ours: &[T], theirs: &[T]) -> Vec<Merged<T>> {
+    let to_ours = lcs_pairs(base, ours).into_iter().collect::<HashMap<_, _>>();
+    let to_theirs = lcs_pairs(base, theirs).into_iter().collect::<HashMap<_, _>>();
+
+    let mut regions = vec![];
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        let anchor = (b..base.len()).find_map(|j| Some((j, *to_ours.get(&j)?, *to_theirs.get(&j)?)));
+        let (bj, oj, tj) = anchor.unwrap_or((base.len(), ours.len(), theirs.len()));
+        push_region(&mut regions, &base[b..bj], &ours[o..oj], &theirs[t..tj]);
+        if anchor.is_none() {
+            break;
+        }
+        push_resolved(&mut regions, std::slice::from_ref(&base[bj]));
+        (b, o, t) = (bj + 1, oj + 1, tj + 1);
+    }
+    regions
+}
+
+fn push_region<T: Clone + PartialEq>(regions: &mut Vec<Merged<T>>, base: &[T], ours: &[T], theirs: &[T]) {
+    if ours == theirs || theirs == base {
+        push_resolved(regions, ours);
+    } else if ours == base {
+        push_resolved(regions, theirs);
+    } else {
+        regions.push(Merged::Conflict {
+            ours: ours.to_vec(),
+            base: base.to_vec(),
+            theirs: theirs.to_vec(),
+        });
+    }
+}
+
+fn push_resolved<T: Clone>(regions: &mut Vec<Merged<T>>, items: &[T]) {
+    if items.is_empty() {
+        return;
+    }
+    if let Some(Merged::Resolved(last)) = regions.last_mut() {
+        last.extend_from_slice(items);
+    } else {
+        regions.push(Merged::Resolved(items.to_vec()));
+    }
+}
+
+/// Picks the side that changed a value; `None` when both changed it differently.
+fn merge_value<T: Clone + PartialEq>(base: &T, ours: &T, theirs: &T) -> Option<T> {
+    if ours == theirs || theirs == base {
+        Some(ours.clone())
+    } else if ours == base {
+        Some(theirs.clone())
+    } else {
+        None
+    }
+}
+
+/// Line-level three-way merge of cell sources. Lines keep their endings, so
+/// CRLF text and a missing final newline survive, and a final newline added
+/// or dropped on one side merges like any other line change. Conflicting
+/// regions are written with git-style markers; the flag reports whether any
+/// occurred.
+pub fn merge_source(base: &str, ours: &str, theirs: &str) -> (String, bool) {
+    if ours == theirs || base == theirs {
+        return (ours.to_string(), false);
+    }
+    if base == ours {
+        return (theirs.to_string(), false);
+    }
+    let split = |text: &str| text.split_inclusive('\n').map(str::to_string).collect::<Vec<_>>();
+    let eol = if ours.contains("\r\n") { "\r\n" } else { "\n" };
+    let regions = merge_sequences(&split(base), &split(ours), &split(theirs));
+    let last = regions.len().saturating_sub(1);
+    let mut merged = String::with_capacity(ours.len());
+    let mut conflicted = false;
+    for (index, region) in regions.into_iter().enumerate() {
+        match region {
+            Merged::Resolved(resolved) => merged.extend(resolved),
+            Merged::Conflict { ours: our_lines, base, theirs } => {
+                conflicted = true;
+                for (marker, lines) in [("<<<<<<< ours", our_lines), ("||||||| base", base), ("=======", theirs)] {
+                    merged.push_str(marker);
+                    merged.push_str(eol);
+                    for line in lines {
+                        merged.push_str(&line);
+                    }
+                    if !merged.ends_with('\n') {
+                        merged.push_str(eol);
+                    }
+                }
+                merged.push_str(">>>>>>> theirs");
+                if index < last || ours.ends_with('\n') {
+                    merged.push_str(eol);
+                }
+            }
+        }
+    }
+    (merged, conflicted)
+}
+
+/// Three-way merge of notebooks keyed by cell id. Non-overlapping edits merge
+/// cleanly; anything that cannot be resolved is kept and tagged
+/// [`CellTag::MergeConflict`] so nothing is silently lost. Cells moved
+/// differently by both sides are reported as a [`ConflictKind::CellOrder`]
+/// conflict, with our order first.
+pub fn merge_notebooks(base: &Notebook, ours: &Notebook, theirs: &Notebook) -> MergeOutcome {
+    let mut conflicts = vec![];
+    let mut notebook = ours.clone();
+    notebook.history.clear();
+
+    macro_rules! merge_field {
+        ($($field:tt)+) => {
+            match merge_value(&base.$($field)+, &ours.$($field)+, &theirs.$($field)+) {
+                Some(value) => notebook.$($field)+ = value,
+                None => conflicts.push(MergeConflict {
+                    kind: ConflictKind::Metadata,
+                    cell_id: None,
+                    message: format!("{} changed on both sides; kept ours", stringify!($($field)+)),
+                }),
+            }
+        };
+    }
+    merge_field!(version);
+    merge_field!(metadata.title);
+    merge_field!(metadata.kernel_policy);
+    notebook.metadata.updated_at = ours.metadata.updated_at.max(theirs.metadata.updated_at);
+
+    let by_id = |nb: &Notebook| nb.cells.iter().map(|c| (c.id, c.clone())).collect::<HashMap<_, _>>();
+    let (base_cells, our_cells, their_cells) = (by_id(base), by_id(ours), by_id(theirs));
+    let ids = |nb: &Notebook| nb.cells.iter().map(|c| c.id).collect::<Vec<_>>();
+
+    // Cells that exist on all three sides; only their relative order can
+    // conflict; insertions and deletions merge by themselves.
+    let everywhere = |region: &[Uuid]| {
+        region
+            .iter()
+            .filter(|id| base_cells.contains_key(id) && our_cells.contains_key(id) && their_cells.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>()
+    };
+    let mut order = vec![];
+    let mut seen = HashSet::new();
+    for region in merge_sequences(&ids(base), &ids(ours), &ids(theirs)) {
+        let candidates = match region {
+            Merged::Resolved(ids) => ids,
+            Merged::Conflict { ours, theirs, .. } => {
+                if everywhere(&ours) != everywhere(&theirs) {
+                    conflicts.push(MergeConflict {
+                        kind: ConflictKind::CellOrder,
+                        cell_id: None,
+                        message: "cells were moved differently on both sides; kept ours, then theirs".to_string(),
+                    });
+                }
+                ours.into_iter().chain(theirs).collect()
+            }
+        };
+        order.extend(candidates.into_iter().filter(|id| seen.insert(*id)));
+    }
+    // Cells one side deleted but the other edited must survive even if the
+    // id-order merge dropped them. They go back after the cell that preceded
+    // them in base, or on the side that added them.
+    for id in ids(ours).into_iter().chain(ids(theirs)) {
+        if !seen.insert(id) {
+            continue;
+        }
+        let reference = if base_cells.contains_key(&id) {
+            ids(base)
+        } else if our_cells.contains_key(&id) {
+            ids(ours)
+        } else {
+            ids(theirs)
+        };
+        let before = reference.iter().position(|other| *other == id).unwrap_or(0);
+        let at = reference[..before]
+            .iter()
+            .rev()
+            .find_map(|other| order.iter().position(|placed| placed == other))
+            .map_or(0, |index| index + 1);
+        order.insert(at, id);
+    }
+
+    notebook.cells = order
+        .into_iter()
+        .filter_map(|id| {
+            merge_cell(
+                base_cells.get(&id),
+                our_cells.get(&id),
+                their_cells.get(&id),
+                &mut conflicts,
+            )
+        })
+        .collect();
+
+    MergeOutcome { notebook, conflicts }
+}
+
+fn merge_cell(
+    base: Option<&Cell>,
+    ours: Option<&Cell>,
+    theirs: Option<&Cell>,
+    conflicts: &mut Vec<MergeConflict>,
+) -> Option<Cell> {
+    match (base, ours, theirs) {
+        (_, None, None) => None,
+        (None, Some(cell), None) | (None, None, Some(cell)) => Some(cell.clone()),
+        (Some(base), Some(cell), None) | (Some(base), None, Some(cell)) => {
+            if same_content(base, cell) {
+                return None;
+            }
+            conflicts.push(MergeConflict {
+                kind: ConflictKind::ModifyDelete,
+                cell_id: Some(cell.id),
+                message: "cell was edited on one side and deleted on the other; kept the edit".to_string(),
+            });
+            let mut kept = cell.clone();
+            kept.tags.insert(CellTag::MergeConflict);
+            Some(kept)
+        }
+        (base, Some(ours), Some(theirs)) => {
+            let empty;
+            let base = match base {
+                Some(base) => base,
+                None => {
+                    empty = Cell {
+                        source: String::new(),
+                        outputs: vec![],
+                        tags: BTreeSet::new(),
+                        metadata: Map::new(),
+                        ..ours.clone()
+                    };
+                    &empty
+                }
+            };
+            Some(merge_both(base, ours, theirs, conflicts))
+        }
+    }
+}
+
+fn merge_both(base: &Cell, ours: &Cell, theirs: &Cell, conflicts: &mut Vec<MergeConflict>) -> Cell {
+    let mut cell = ours.clone();
+    let mut conflicted = false;
+
+    let kind = |c: &Cell| (c.cell_type.clone(), c.language.clone());
+    match merge_value(&kind(base), &kind(ours), &kind(theirs)) {
+        Some((cell_type, language)) => {
+            cell.cell_type = cell_type;
+            cell.language = language;
+        }
+        None => {
+            conflicted = true;
+            conflicts.push(MergeConflict {
+                kind: ConflictKind::CellType,
+                cell_id: Some(cell.id),
+                message: "cell type or language changed on both sides; kept ours".to_string(),
+            });
+        }
+    }
+
+    let (source, source_conflict) = merge_source(&base.source, &ours.source, &theirs.source);
+    cell.source = source;
+    if source_conflict {
+        conflicted = true;
+        conflicts.push(MergeConflict {
+            kind: ConflictKind::Source,
+            cell_id: Some(cell.id),
+            message: "overlapping source edits; see conflict markers".to_string(),
+        });
+    }
+
+    // Outputs follow whichever side ran the cell; if both did, or the source
+    // itself conflicts, none of them describe the merged code.
+    let run = |c: &Cell| (snapshots(&c.outputs), c.execution.count, c.execution.status.clone());
+    let (base_run, our_run, their_run) = (run(base), run(ours), run(theirs));
+    if source_conflict || merge_value(&base_run, &our_run, &their_run).is_none() {
+        cell.outputs.clear();
+        cell.execution = CellExecution::idle();
+    } else if our_run == base_run && their_run != base_run {
+        cell.outputs = theirs.outputs.clone();
+        cell.execution = theirs.execution.clone();
+    }
+
+    let tags = base
+        .tags
+        .iter()
+        .chain(&ours.tags)
+        .chain(&theirs.tags)
+        .filter(|tag| {
+            let has = |c: &Cell| c.tags.contains(*tag);
+            merge_value(&has(base), &has(ours), &has(theirs)).unwrap_or(true)
+        })
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    cell.tags = tags;
+
+    let keys = base
+        .metadata
+        .keys()
+        .chain(ours.metadata.keys())
+        .chain(theirs.metadata.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let mut metadata = Map::new();
+    for key in keys {
+        let get = |c: &Cell| c.metadata.get(&key).cloned();
+        let value = match merge_value(&get(base), &get(ours), &get(theirs)) {
+            Some(value) => value,
+            None => {
+                conflicts.push(MergeConflict {
+                    kind: ConflictKind::CellMetadata,
+                    cell_id: Some(cell.id),
+                    message: format!("metadata key '{key}' changed on both sides; kept ours"),
+                });
+                conflicted = true;
+                get(ours)
+            }
+        };
+        if let Some(value) = value {
+            metadata.insert(key, value);
+        }
+    }
+    cell.metadata = metadata;
+
+    if conflicted {
+        cell.tags.insert(CellTag::MergeConflict);
+    }
+    cell
+}
+
+/// Compares the fields a merge cares about, ignoring timestamps and durations.
+fn same_content(a: &Cell, b: &Cell) -> bool {
+    a.cell_type == b.cell_type
+        && a.language == b.language
+        && a.source == b.source
+        && snapshots(&a.outputs) == snapshots(&b.outputs)
+        && a.execution.count == b.execution.count
+        && a.tags == b.tags
+        && a.metadata == b.metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(nb: &Notebook) -> Vec<&str> {
+        nb.cells.iter().map(|c| c.source.as_str()).collect()
+    }
+
+    #[test]
+    fn merges_non_overlapping_cell_edits() {
+        let mut base = Notebook::new("merge");
+        let a = base.add_code_cell("python", "a = 1\nb = 2\nc = 3");
+        let b = base.add_markdown_cell("notes");
+        let c = base.add_markdown_cell("to delete");
+
+        let mut ours = base.clone();
+        ours.set_cell_source(a, "a = 10\nb = 2\nc = 3").expect("edit");
+        ours.delete_cell(c).expect("delete");
+        let mut theirs = base.clone();
+        theirs.set_cell_source(a, "a = 1\nb = 2\nc = 30").expect("edit");
+        theirs.set_cell_source(b, "better notes").expect("edit");
+        theirs.insert_cell_after(b, Cell::new_code("ruby", "puts 1")).expect("insert");
+
+        let outcome = merge_notebooks(&base, &ours, &theirs);
+        assert!(outcome.is_clean(), "{:?}", outcome.conflicts);
+        assert_eq!(
+            sources(&outcome.notebook),
+            vec!["a = 10\nb = 2\nc = 30", "better notes", "puts 1"]
+        );
+    }
+
+    #[test]
+    fn overlapping_edits_become_conflict_cells() {
+        let mut base = Notebook::new("merge");
+        let a = base.add_code_cell("go", "x := 1");
+        let b = base.add_code_cell("go", "y := 2");
+
+        let mut ours = base.clone();
+        ours.set_cell_source(a, "x := 100").expect("edit");
+        ours.cells[0].outputs.push(crate::CellOutput {
+            kind: crate::CellOutputKind::Stdout,
+            mime: "text/plain".to_string(),
+            data: "100".to_string(),
+            created_at: chrono::Utc::now(),
+        });
+        ours.set_cell_source(b, "y := 3").expect("edit");
+        let mut theirs = base.clone();
+        theirs.set_cell_source(a, "x := 200").expect("edit");
+        theirs.delete_cell(b).expect("delete");
+
+        let outcome = merge_notebooks(&base, &ours, &theirs);
+        let kinds = outcome.conflicts.iter().map(|c| c.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![ConflictKind::Source, ConflictKind::ModifyDelete]);
+
+        let merged = &outcome.notebook;
+        assert!(merged.cells[0].has_tag(&CellTag::MergeConflict));
+        assert!(merged.cells[0].outputs.is_empty());
+        assert_eq!(
+            merged.cells[0].source,
+            "<<<<<<< ours\nx := 100\n||||||| base\nx := 1\n=======\nx := 200\n>>>>>>> theirs"
+        );
+        assert_eq!(merged.cells[1].source, "y := 3");
+        assert!(merged.cells[1].has_tag(&CellTag::MergeConflict));
+    }
+
+    #[test]
+    fn edited_cell_deleted_on_the_other_side_keeps_its_place() {
+        let mut base = Notebook::new("merge");
+        base.add_markdown_cell("a");
+        let b = base.add_markdown_cell("b");
+        base.add_markdown_cell("c");
+
+        let mut ours = base.clone();
+        ours.delete_cell(b).expect("delete");
+        ours.add_markdown_cell("ours");
+        let mut theirs = base.clone();
+        theirs.set_cell_source(b, "b, edited").expect("edit");
+        theirs.add_markdown_cell("theirs");
+
+        let outcome = merge_notebooks(&base, &ours, &theirs);
+        assert_eq!(sources(&outcome.notebook), vec!["a", "b, edited", "c", "ours", "theirs"]);
+        let kinds = outcome.conflicts.iter().map(|c| c.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![ConflictKind::ModifyDelete]);
+    }
+
+    #[test]
+    fn cells_moved_differently_conflict() {
+        let mut base = Notebook::new("merge");
+        let a = base.add_markdown_cell("a");
+        let b = base.add_markdown_cell("b");
+        base.add_markdown_cell("c");
+
+        let mut ours = base.clone();
+        ours.move_cell(b, 2).expect("move");
+        let mut theirs = base.clone();
+        theirs.move_cell(a, 2).expect("move");
+
+        let outcome = merge_notebooks(&base, &ours, &theirs);
+        assert!(outcome.conflicts.iter().any(|c| c.kind == ConflictKind::CellOrder));
+        assert_eq!(outcome.notebook.cells.len(), 3);
+    }
+
+    #[test]
+    fn a_final_newline_merges_like_a_line() {
+        assert_eq!(merge_source("a\nm\nb", "a\nm\nb\n", "x\nm\nb"), ("x\nm\nb\n".to_string(), false));
+        let (merged, conflicted) = merge_source("a\nb", "a\nb\n", "a\nc");
+        assert!(conflicted);
+        assert_eq!(merged, "a\n<<<<<<< ours\nb\n||||||| base\nb\n=======\nc\n>>>>>>> theirs\n");
+    }
+
+}
@@ -1,36 +1,151 @@
-use crate::CoreResult;
-use rusqlite::{params, Connection};
+use crate::digest::sha256_hex;
+use crate::migrations;
+use crate::paths;
+use crate::snapshots;
+use crate::{Cell, CellExecution, CellOutput, CellStatus, CellType, CoreError, CoreResult, Notebook};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
 use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecoverySession {
+    pub session_id: String,
+    pub notebook_path: Option<String>,
+    pub title: String,
+    pub snapshot_path: String,
+    pub saved_at: String,
+}
+
+/// Output text beyond this many bytes per cell is left out of the search index.
+pub const MAX_INDEXED_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// How long a write waits for another process holding the database lock.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Older runs of a cell are dropped once it has this many.
+pub const MAX_RUNS_PER_CELL: usize = 50;
+pub const MAX_RUN_SUMMARY_CHARS: usize = 1024;
+/// Runs whose serialized outputs exceed this keep only their summary.
+pub const MAX_RUN_OUTPUT_BYTES: usize = 256 * 1024;
+
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// A piece of a search snippet; `matched` marks the query hits to highlight.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnippetPart {
+    pub text: String,
+    pub matched: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CellSearchHit {
+    pub notebook_path: String,
+    pub title: Option<String>,
+    pub cell_id: String,
+    pub cell_type: CellType,
+    pub language: Option<String>,
+    pub snippet: Vec<SnippetPart>,
+    pub rank: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecentNotebook {
+    pub path: String,
+    pub title: Option<String>,
+    pub opened_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexedNotebook {
+    pub path: String,
+    pub title: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexSort {
+    Title,
+    #[default]
+    UpdatedAt,
+    Path,
+}
+
+/// Filters for [`MetadataStore::list_notebook_index`]. Dates are RFC 3339
+/// strings, compared the same way they are stored.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexQuery {
+    /// Case-insensitive substring of the title.
+    pub title_contains: Option<String>,
+    pub updated_after: Option<String>,
+    pub updated_before: Option<String>,
+    pub sort: IndexSort,
+    pub descending: bool,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+/// A finished `cell_execute`, as passed to [`MetadataStore::record_cell_run`].
+#[derive(Debug, Clone)]
+pub struct CellRunRecord<'a> {
+    pub notebook_path: &'a str,
+    pub cell_id: &'a str,
+    pub language: &'a str,
+    pub source: &'a str,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub status: CellStatus,
+    pub outputs: &'a [CellOutput],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CellRun {
+    pub id: i64,
+    pub notebook_path: String,
+    pub cell_id: String,
+    pub source_hash: String,
+    pub language: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: u64,
+    pub status: CellStatus,
+    pub output_summary: String,
+    /// Whether full outputs were kept and the run can be restored.
+    pub restorable: bool,
+}
 
 pub struct MetadataStore {
-    conn: Connection,
+    pub(crate) conn: Connection,
 }
 
 impl MetadataStore {
+    /// Opens the store and applies any pending [`migrations`](crate::migrations).
+    /// WAL mode and a busy timeout let several app instances share one database.
     pub fn open<P: AsRef<Path>>(path: P) -> CoreResult<Self> {
-        let conn = Connection::open(path)?;
-        let store = Self { conn };
-        store.init()?;
-        Ok(store)
+        let path = path.as_ref();
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        migrations::migrate(&mut conn, Some(path))?;
+        Ok(Self { conn })
     }
 
-    fn init(&self) -> CoreResult<()> {
-        self.conn.execute_batch(
-            r#"
-            CREATE TABLE IF NOT EXISTS notebook_index (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                path TEXT NOT NULL UNIQUE,
-                title TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS recent_open (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                path TEXT NOT NULL UNIQUE,
-                opened_at TEXT NOT NULL
-            );
-            "#,
-        )?;
-        Ok(())
+    /// Opens the store at [`DATABASE_FILE`](crate::paths::DATABASE_FILE) inside `data_dir`.
+    pub fn open_in<P: AsRef<Path>>(data_dir: P) -> CoreResult<Self> {
+        Self::open(data_dir.as_ref().join(paths::DATABASE_FILE))
+    }
+
+    pub fn journal_mode(&self) -> CoreResult<String> {
+        Ok(self.conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?)
+    }
+
+    pub fn schema_version(&self) -> CoreResult<u32> {
+        migrations::schema_version(&self.conn)
     }
 
     pub fn upsert_notebook_index(
@@ -64,4 +179,441 @@ impl MetadataStore {
         )?;
         Ok(())
     }
+
+    /// Most recently opened first. Titles come from the index when the
+    /// notebook has been saved at least once.
+    pub fn list_recent(&self, limit: usize) -> CoreResult<Vec<RecentNotebook>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT r.path, n.title, r.opened_at
+            FROM recent_open r
+            LEFT JOIN notebook_index n ON n.path = r.path
+            ORDER BY r.opened_at DESC
+            LIMIT ?1
+            "#,
+        )?;
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok(RecentNotebook {
+                path: row.get(0)?,
+                title: row.get(1)?,
+                opened_at: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn list_notebook_index(&self, query: &IndexQuery) -> CoreResult<Vec<IndexedNotebook>> {
+        let column = match query.sort {
+            IndexSort::Title => "title COLLATE NOCASE",
+            IndexSort::UpdatedAt => "updated_at",
+            IndexSort::Path => "path",
+        };
+        let direction = if query.descending { "DESC" } else { "ASC" };
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT path, title, updated_at
+            FROM notebook_index
+            WHERE (?1 IS NULL OR title LIKE '%' || ?1 || '%' ESCAPE '\')
+              AND (?2 IS NULL OR updated_at >= ?2)
+              AND (?3 IS NULL OR updated_at < ?3)
+            ORDER BY {column} {direction}, path ASC
+            LIMIT ?4 OFFSET ?5
+            "#
+        ))?;
+        let title_pattern = query.title_contains.as_deref().map(escape_like);
+        let limit = query.limit.map_or(-1, |limit| limit as i64);
+        let rows = stmt.query_map(
+            params![
+                title_pattern,
+                query.updated_after,
+                query.updated_before,
+                limit,
+                query.offset as i64
+            ],
+            |row| {
+                Ok(IndexedNotebook {
+                    path: row.get(0)?,
+                    title: row.get(1)?,
+                    updated_at: row.get(2)?,
+                })
+            },
+        )?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Forgets a notebook everywhere: library index, recents, search index, run
+    /// history and saved versions.
+    pub fn remove_notebook(&self, path: &str) -> CoreResult<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM notebook_index WHERE path = ?1", params![path])?;
+        tx.execute("DELETE FROM recent_open WHERE path = ?1", params![path])?;
+        delete_indexed_cells(&tx, path)?;
+        tx.execute("DELETE FROM cell_runs WHERE notebook_path = ?1", params![path])?;
+        tx.execute("DELETE FROM notebook_versions WHERE notebook_path = ?1", params![path])?;
+        snapshots::delete_orphan_blobs(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Removes entries whose file no longer exists and returns their paths.
+    pub fn prune_missing(&self) -> CoreResult<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT path FROM notebook_index
+            UNION
+            SELECT path FROM recent_open
+            ORDER BY path
+            "#,
+        )?;
+        let paths = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let missing = paths
+            .into_iter()
+            .filter(|path| !Path::new(path).exists())
+            .collect::<Vec<_>>();
+        for path in &missing {
+            self.remove_notebook(path)?;
+        }
+        Ok(missing)
+    }
+
+    pub fn record_cell_run(&self, run: &CellRunRecord) -> CoreResult<i64> {
+        let outputs = serde_json::to_string(run.outputs)?;
+        let outputs = (outputs.len() <= MAX_RUN_OUTPUT_BYTES).then_some(outputs);
+        let duration_ms = (run.finished_at - run.started_at).num_milliseconds().max(0);
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            r#"
+            INSERT INTO cell_runs(notebook_path, cell_id, source_hash, language, started_at,
+                                  finished_at, duration_ms, status, output_summary, outputs)
+            VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            "#,
+            params![
+                run.notebook_path,
+                run.cell_id,
+                sha256_hex(run.source.as_bytes()),
+                run.language,
+                run.started_at.to_rfc3339(),
+                run.finished_at.to_rfc3339(),
+                duration_ms,
+                run.status.as_str(),
+                output_summary(run.outputs),
+                outputs,
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        tx.execute(
+            r#"
+            DELETE FROM cell_runs
+            WHERE notebook_path = ?1 AND cell_id = ?2 AND id NOT IN (
+                SELECT id FROM cell_runs
+                WHERE notebook_path = ?1 AND cell_id = ?2
+                ORDER BY started_at DESC, id DESC
+                LIMIT ?3
+            )
+            "#,
+            params![run.notebook_path, run.cell_id, MAX_RUNS_PER_CELL as i64],
+        )?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// A cell's runs, newest first.
+    pub fn list_cell_runs(&self, notebook_path: &str, cell_id: &str, limit: usize) -> CoreResult<Vec<CellRun>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{CELL_RUN_COLUMNS} WHERE notebook_path = ?1 AND cell_id = ?2 ORDER BY started_at DESC, id DESC LIMIT ?3"
+        ))?;
+        let rows = stmt.query_map(params![notebook_path, cell_id, limit as i64], cell_run_from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn cell_run(&self, run_id: i64) -> CoreResult<Option<CellRun>> {
+        Ok(self
+            .conn
+            .query_row(
+                &format!("{CELL_RUN_COLUMNS} WHERE id = ?1"),
+                params![run_id],
+                cell_run_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Full outputs of a run; `None` if they were too large to keep.
+    pub fn cell_run_outputs(&self, run_id: i64) -> CoreResult<Option<Vec<CellOutput>>> {
+        let outputs: Option<Option<String>> = self
+            .conn
+            .query_row("SELECT outputs FROM cell_runs WHERE id = ?1", params![run_id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(outputs) = outputs else {
+            return Err(CoreError::Validation(format!("cell run {run_id} not found")));
+        };
+        Ok(outputs.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    /// Puts the outputs of an earlier run back on its cell as an undoable edit.
+    pub fn restore_cell_run(&self, notebook: &mut Notebook, run_id: i64) -> CoreResult<()> {
+        let run = self
+            .cell_run(run_id)?
+            .ok_or_else(|| CoreError::Validation(format!("cell run {run_id} not found")))?;
+        let cell_id = run
+            .cell_id
+            .parse()
+            .map_err(|_| CoreError::Validation(format!("cell run {run_id} has invalid cell id")))?;
+        let outputs = self.cell_run_outputs(run_id)?.ok_or_else(|| {
+            CoreError::Validation(format!("outputs of cell run {run_id} were too large to keep"))
+        })?;
+        let count = notebook
+            .cell(cell_id)
+            .ok_or(CoreError::CellNotFound(cell_id))?
+            .execution
+            .count;
+        let execution = CellExecution {
+            count,
+            status: run.status,
+            duration_ms: run.duration_ms,
+        };
+        notebook.set_outputs(cell_id, outputs, execution)
+    }
+
+    pub fn upsert_recovery_session(&self, session: &RecoverySession) -> CoreResult<()> {
+        self.conn.execute(
+            r#"
+            INSERT INTO recovery_session(session_id, notebook_path, title, snapshot_path, saved_at)
+            VALUES(?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(session_id) DO UPDATE SET
+                notebook_path = excluded.notebook_path,
+                title = excluded.title,
+                snapshot_path = excluded.snapshot_path,
+                saved_at = excluded.saved_at
+            "#,
+            params![
+                session.session_id,
+                session.notebook_path,
+                session.title,
+                session.snapshot_path,
+                session.saved_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_recovery_sessions(&self) -> CoreResult<Vec<RecoverySession>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT session_id, notebook_path, title, snapshot_path, saved_at
+            FROM recovery_session
+            ORDER BY saved_at DESC
+            "#,
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(RecoverySession {
+                session_id: row.get(0)?,
+                notebook_path: row.get(1)?,
+                title: row.get(2)?,
+                snapshot_path: row.get(3)?,
+                saved_at: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn remove_recovery_session(&self, session_id: &str) -> CoreResult<()> {
+        self.conn.execute(
+            "DELETE FROM recovery_session WHERE session_id = ?1",
+            params![session_id],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces every indexed cell of the notebook at `path`.
+    pub fn index_notebook_cells(&self, path: &str, notebook: &Notebook) -> CoreResult<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        delete_indexed_cells(&tx, path)?;
+        {
+            let mut insert = tx.prepare(
+                r#"
+                INSERT INTO cell_fts(path, cell_id, cell_type, language, source, outputs)
+                VALUES(?1, ?2, ?3, ?4, ?5, ?6)
+                "#,
+            )?;
+            let mut map = tx.prepare("INSERT INTO cell_fts_rows(fts_rowid, path) VALUES(?1, ?2)")?;
+            for cell in &notebook.cells {
+                let cell_type = match cell.cell_type {
+                    CellType::Markdown => "markdown",
+                    CellType::Code => "code",
+                };
+                insert.execute(params![
+                    path,
+                    cell.id.to_string(),
+                    cell_type,
+                    cell.language,
+                    cell.source,
+                    indexed_output_text(cell),
+                ])?;
+                map.execute(params![tx.last_insert_rowid(), path])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn remove_notebook_cells(&self, path: &str) -> CoreResult<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        delete_indexed_cells(&tx, path)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Searches cell sources, markdown and text outputs across all indexed
+    /// notebooks. Every word must match; the last one also matches as a prefix.
+    pub fn search_cells(&self, query: &str, limit: usize) -> CoreResult<Vec<CellSearchHit>> {
+        let Some(fts_query) = fts_query(query) else {
+            return Ok(vec![]);
+        };
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT f.path, COALESCE(n.title, w.title), f.cell_id, f.cell_type, f.language,
+                   snippet(cell_fts, -1, char(2), char(3), '…', 16), bm25(cell_fts)
+            FROM cell_fts f
+            LEFT JOIN notebook_index n ON n.path = f.path
+            LEFT JOIN workspace_files w ON w.path = f.path
+            WHERE cell_fts MATCH ?1
+            ORDER BY bm25(cell_fts)
+            LIMIT ?2
+            "#,
+        )?;
+        let rows = stmt.query_map(params![fts_query, limit as i64], |row| {
+            let cell_type: String = row.get(3)?;
+            let snippet: String = row.get(5)?;
+            Ok(CellSearchHit {
+                notebook_path: row.get(0)?,
+                title: row.get(1)?,
+                cell_id: row.get(2)?,
+                cell_type: if cell_type == "markdown" {
+                    CellType::Markdown
+                } else {
+                    CellType::Code
+                },
+                language: row.get(4)?,
+                snippet: split_snippet(&snippet),
+                rank: row.get(6)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+/// Drops the search index rows of the notebook at `path`, found through
+/// `cell_fts_rows` since the FTS table cannot index its `path` column.
+pub(crate) fn delete_indexed_cells(conn: &Connection, path: &str) -> CoreResult<()> {
+    conn.execute(
+        "DELETE FROM cell_fts WHERE rowid IN (SELECT fts_rowid FROM cell_fts_rows WHERE path = ?1)",
+        params![path],
+    )?;
+    conn.execute("DELETE FROM cell_fts_rows WHERE path = ?1", params![path])?;
+    Ok(())
+}
+
+fn indexed_output_text(cell: &Cell) -> String {
+    let mut text = String::new();
+    for output in cell.outputs.iter().filter(|output| output.mime.starts_with("text/")) {
+        if text.len() + output.data.len() > MAX_INDEXED_OUTPUT_BYTES {
+            let mut end = MAX_INDEXED_OUTPUT_BYTES.saturating_sub(text.len()).min(output.data.len());
+            while !output.data.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.push_str(&output.data[..end]);
+            break;
+        }
+        text.push_str(&output.data);
+        text.push('\n');
+    }
+    text
+}
+
+/// Turns free text into an FTS5 query that cannot fail to parse: each word is
+/// quoted as a literal phrase.
+fn fts_query(input: &str) -> Option<String> {
+    let terms = input
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    let mut query = terms.join(" ");
+    if query.is_empty() {
+        return None;
+    }
+    query.push('*');
+    Some(query)
+}
+
+const CELL_RUN_COLUMNS: &str = r#"
+    SELECT id, notebook_path, cell_id, source_hash, language, started_at, finished_at,
+           duration_ms, status, output_summary, outputs IS NOT NULL
+    FROM cell_runs
+"#;
+
+fn cell_run_from_row(row: &rusqlite::Row) -> rusqlite::Result<CellRun> {
+    let status: String = row.get(8)?;
+    Ok(CellRun {
+        id: row.get(0)?,
+        notebook_path: row.get(1)?,
+        cell_id: row.get(2)?,
+        source_hash: row.get(3)?,
+        language: row.get(4)?,
+        started_at: row.get(5)?,
+        finished_at: row.get(6)?,
+        duration_ms: row.get(7)?,
+        status: serde_json::from_value(status.into())
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Text, Box::new(err)))?,
+        output_summary: row.get(9)?,
+        restorable: row.get(10)?,
+    })
+}
+
+fn output_summary(outputs: &[CellOutput]) -> String {
+    let text = outputs
+        .iter()
+        .filter(|output| output.mime.starts_with("text/"))
+        .map(|output| output.data.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if text.chars().count() <= MAX_RUN_SUMMARY_CHARS {
+        return text;
+    }
+    let mut summary = text.chars().take(MAX_RUN_SUMMARY_CHARS).collect::<String>();
+    summary.push('…');
+    summary
+}
+
+fn escape_like(input: &str) -> String {
+    input.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+fn split_snippet(snippet: &str) -> Vec<SnippetPart> {
+    let mut parts = vec![];
+    let mut rest = snippet;
+    while let Some(start) = rest.find(MATCH_START) {
+        let end = rest[start..].find(MATCH_END).map_or(rest.len(), |e| start + e);
+        if start > 0 {
+            parts.push(SnippetPart {
+                text: rest[..start].to_string(),
+                matched: false,
+            });
+        }
+        parts.push(SnippetPart {
+            text: rest[start + MATCH_START.len_utf8()..end].to_string(),
+            matched: true,
+        });
+        rest = rest.get(end + MATCH_END.len_utf8()..).unwrap_or_default();
+    }
+    if !rest.is_empty() {
+        parts.push(SnippetPart {
+            text: rest.to_string(),
+            matched: false,
+        });
+    }
+    parts
 }
@@ -0,0 +1,203 @@
+use crate::{CoreError, CoreResult};
+use rusqlite::{Connection, TransactionBehavior};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// One step of the metadata store schema. A database at `user_version` N has
+/// had the first N migrations applied.
+pub struct Migration {
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Ordered and append-only: never edit a migration that has shipped, add a new one.
+///
+/// The first three steps use `IF NOT EXISTS` because databases created before
+/// versioning report `user_version` 0 with some of these tables already present.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "notebook index and recent opens",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS notebook_index (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT NOT NULL UNIQUE,
+                title TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS recent_open (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT NOT NULL UNIQUE,
+                opened_at TEXT NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        description: "crash recovery sessions",
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS recovery_session (
+                session_id TEXT PRIMARY KEY,
+                notebook_path TEXT,
+                title TEXT NOT NULL,
+                snapshot_path TEXT NOT NULL,
+                saved_at TEXT NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        description: "full-text cell search",
+        sql: r#"
+            CREATE VIRTUAL TABLE IF NOT EXISTS cell_fts USING fts5(
+                path UNINDEXED,
+                cell_id UNINDEXED,
+                cell_type UNINDEXED,
+                language UNINDEXED,
+                source,
+                outputs,
+                tokenize = 'unicode61'
+            );
+        "#,
+    },
+    Migration {
+        description: "indexes for recents and library listing",
+        sql: r#"
+            CREATE INDEX idx_recent_open_opened_at ON recent_open(opened_at);
+            CREATE INDEX idx_notebook_index_updated_at ON notebook_index(updated_at);
+        "#,
+    },
+    Migration {
+        description: "cell execution history",
+        sql: r#"
+            CREATE TABLE cell_runs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                notebook_path TEXT NOT NULL,
+                cell_id TEXT NOT NULL,
+                source_hash TEXT NOT NULL,
+                language TEXT NOT NULL,
+                started_at TEXT NOT NULL,
+                finished_at TEXT NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                output_summary TEXT NOT NULL,
+                outputs TEXT
+            );
+            CREATE INDEX idx_cell_runs_cell ON cell_runs(notebook_path, cell_id, started_at);
+        "#,
+    },
+    Migration {
+        description: "notebook version snapshots",
+        sql: r#"
+            CREATE TABLE snapshot_blobs (
+                hash TEXT PRIMARY KEY,
+                data BLOB NOT NULL
+            );
+            CREATE TABLE notebook_versions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                notebook_path TEXT NOT NULL,
+                saved_at TEXT NOT NULL,
+                title TEXT NOT NULL,
+                header BLOB NOT NULL,
+                cell_hashes TEXT NOT NULL
+            );
+            CREATE INDEX idx_notebook_versions_path ON notebook_versions(notebook_path, saved_at);
+        "#,
+    },
+    Migration {
+        description: "workspaces and their indexed files",
+        sql: r#"
+            CREATE TABLE workspaces (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                root TEXT NOT NULL UNIQUE,
+                name TEXT NOT NULL,
+                added_at TEXT NOT NULL
+            );
+            CREATE TABLE workspace_files (
+                workspace_id INTEGER NOT NULL,
+                path TEXT NOT NULL UNIQUE,
+                relative_path TEXT NOT NULL,
+                format TEXT NOT NULL,
+                title TEXT NOT NULL,
+                languages TEXT NOT NULL,
+                cell_count INTEGER NOT NULL,
+                code_cell_count INTEGER NOT NULL,
+                mtime_ms INTEGER NOT NULL,
+                error TEXT
+            );
+            CREATE INDEX idx_workspace_files_workspace ON workspace_files(workspace_id, relative_path);
+        "#,
+    },
+    Migration {
+        description: "search index rows by notebook path",
+        sql: r#"
+            CREATE TABLE cell_fts_rows (
+                fts_rowid INTEGER PRIMARY KEY,
+                path TEXT NOT NULL
+            );
+            CREATE INDEX idx_cell_fts_rows_path ON cell_fts_rows(path);
+            INSERT INTO cell_fts_rows(fts_rowid, path) SELECT rowid, path FROM cell_fts;
+        "#,
+    },
+];
+
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn schema_version(conn: &Connection) -> CoreResult<u32> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+/// `<name>.v<version>.bak` next to the database.
+pub fn backup_path(db_path: &Path, version: u32) -> PathBuf {
+    let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".v{version}.bak"));
+    db_path.with_file_name(name)
+}
+
+/// Brings the database up to [`SCHEMA_VERSION`]. Existing databases on disk
+/// are copied with `VACUUM INTO` first so a failed upgrade can be rolled back
+/// by hand. Each step takes the write lock up front and re-reads the version,
+/// so processes opening the same database concurrently apply it only once.
+pub fn migrate(conn: &mut Connection, db_path: Option<&Path>) -> CoreResult<()> {
+    let current = schema_version(conn)?;
+    check_supported(current)?;
+    if current == SCHEMA_VERSION {
+        return Ok(());
+    }
+    if let Some(db_path) = db_path {
+        if has_tables(conn)? {
+            // Written under a unique name first: another process may be taking
+            // the same backup right now.
+            let backup = backup_path(db_path, current);
+            let partial = backup.with_extension(format!("bak.{}.tmp", Uuid::new_v4()));
+            conn.execute("VACUUM INTO ?1", [partial.to_string_lossy()])?;
+            fs::rename(&partial, &backup)?;
+        }
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version = schema_version(&tx)?;
+        check_supported(version)?;
+        if version as usize > index {
+            continue;
+        }
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", index as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn check_supported(version: u32) -> CoreResult<()> {
+    if version > SCHEMA_VERSION {
+        return Err(CoreError::Validation(format!(
+            "metadata store schema version {version} is newer than supported version {SCHEMA_VERSION}"
+        )));
+    }
+    Ok(())
+}
+
+fn has_tables(conn: &Connection) -> CoreResult<bool> {
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'", [], |row| {
+        row.get(0)
+    })?;
+    Ok(count > 0)
+}
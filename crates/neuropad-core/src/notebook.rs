@@ -1,15 +1,38 @@
+use crate::archive;
+use crate::canonical;
+use crate::history::{EditOp, History};
+use crate::schema::{self, SchemaViolation};
+use crate::storage::{self, FileStamp, SaveOptions};
 use crate::{CoreError, CoreResult};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
 use std::fs;
 use std::path::Path;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Notebook {
     pub version: String,
     pub metadata: NotebookMetadata,
     pub cells: Vec<Cell>,
+    /// Undo/redo for this session. Never serialized, so it neither reaches
+    /// files nor travels to the UI, and never cloned; see [`Notebook::history`].
+    #[serde(skip)]
+    pub(crate) history: History,
+}
+
+/// Copies the document only: the copy starts with an empty history.
+impl Clone for Notebook {
+    fn clone(&self) -> Self {
+        Self {
+            version: self.version.clone(),
+            metadata: self.metadata.clone(),
+            cells: self.cells.clone(),
+            history: History::default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,21 +69,88 @@ pub enum CellStatus {
     Cancelled,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl CellStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CellStatus::Idle => "idle",
+            CellStatus::Running => "running",
+            CellStatus::Ok => "ok",
+            CellStatus::Error => "error",
+            CellStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CellExecution {
     pub count: u32,
     pub status: CellStatus,
+    #[serde(default)]
     pub duration_ms: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CellOutput {
     pub kind: CellOutputKind,
     pub mime: String,
     pub data: String,
+    #[serde(default = "canonical::stripped_timestamp")]
     pub created_at: DateTime<Utc>,
 }
 
+/// Well-known cell tags; anything else round-trips as `Custom`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum CellTag {
+    Parameters,
+    Skip,
+    HideInput,
+    Slow,
+    MergeConflict,
+    Custom(String),
+}
+
+impl CellTag {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CellTag::Parameters => "parameters",
+            CellTag::Skip => "skip",
+            CellTag::HideInput => "hide-input",
+            CellTag::Slow => "slow",
+            CellTag::MergeConflict => "merge-conflict",
+            CellTag::Custom(name) => name,
+        }
+    }
+}
+
+impl From<String> for CellTag {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "parameters" => CellTag::Parameters,
+            "skip" => CellTag::Skip,
+            "hide-input" => CellTag::HideInput,
+            "slow" => CellTag::Slow,
+            "merge-conflict" => CellTag::MergeConflict,
+            _ => CellTag::Custom(name),
+        }
+    }
+}
+
+impl From<&str> for CellTag {
+    fn from(name: &str) -> Self {
+        CellTag::from(name.to_string())
+    }
+}
+
+impl From<CellTag> for String {
+    fn from(tag: CellTag) -> Self {
+        match tag {
+            CellTag::Custom(name) => name,
+            known => known.as_str().to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cell {
     pub id: Uuid,
@@ -68,10 +158,15 @@ pub struct Cell {
     pub cell_type: CellType,
     #[serde(default)]
     pub language: Option<String>,
+    #[serde(deserialize_with = "canonical::deserialize_source")]
     pub source: String,
     #[serde(default)]
     pub outputs: Vec<CellOutput>,
     pub execution: CellExecution,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<CellTag>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
 }
 
 impl Notebook {
@@ -86,25 +181,37 @@ impl Notebook {
                 kernel_policy: "per_notebook".to_string(),
             },
             cells: vec![],
+            history: History::default(),
         }
     }
 
     pub fn add_markdown_cell(&mut self, source: impl Into<String>) -> Uuid {
-        let cell = Cell::new_markdown(source);
-        let id = cell.id;
-        self.cells.push(cell);
-        self.touch();
-        id
+        self.push_cell(Cell::new_markdown(source))
     }
 
     pub fn add_code_cell(&mut self, language: impl Into<String>, source: impl Into<String>) -> Uuid {
-        let cell = Cell::new_code(language, source);
+        self.push_cell(Cell::new_code(language, source))
+    }
+
+    fn push_cell(&mut self, cell: Cell) -> Uuid {
         let id = cell.id;
+        self.history.record(EditOp::InsertCell {
+            index: self.cells.len(),
+            cell: cell.clone(),
+        });
         self.cells.push(cell);
         self.touch();
         id
     }
 
+    pub fn cells_with_tag<'a>(&'a self, tag: &'a CellTag) -> impl Iterator<Item = &'a Cell> + 'a {
+        self.cells.iter().filter(move |cell| cell.tags.contains(tag))
+    }
+
+    pub fn cells_without_tag<'a>(&'a self, tag: &'a CellTag) -> impl Iterator<Item = &'a Cell> + 'a {
+        self.cells.iter().filter(move |cell| !cell.tags.contains(tag))
+    }
+
     pub fn touch(&mut self) {
         self.metadata.updated_at = Utc::now();
     }
@@ -114,46 +221,114 @@ impl Notebook {
             return Err(CoreError::Validation("version cannot be empty".to_string()));
         }
         for cell in &self.cells {
-            match cell.cell_type {
-                CellType::Markdown => {
-                    if cell.language.is_some() {
-                        return Err(CoreError::Validation(format!(
-                            "markdown cell {} must not have a language",
-                            cell.id
-                        )));
-                    }
-                }
-                CellType::Code => {
-                    let lang = cell.language.as_deref().unwrap_or_default();
-                    if lang != "go" && lang != "ruby" && lang != "python" {
-                        return Err(CoreError::Validation(format!(
-                            "code cell {} has unsupported language '{}'",
-                            cell.id, lang
-                        )));
-                    }
-                }
-            }
+            validate_cell_kind(cell.id, &cell.cell_type, cell.language.as_deref())?;
         }
         Ok(())
     }
 
     pub fn save_npad<P: AsRef<Path>>(&mut self, path: P) -> CoreResult<()> {
-        self.touch();
-        self.validate()?;
-        let data = serde_json::to_string_pretty(self)?;
-        fs::write(path, data)?;
+        self.save_npad_with(path, &SaveOptions::default())?;
         Ok(())
     }
 
+    /// Saves atomically and returns the stamp to pass as `expected` on the next save.
+    /// Canonical saves leave `updated_at` alone so a no-op save rewrites identical bytes.
+    pub fn save_npad_with<P: AsRef<Path>>(&mut self, path: P, options: &SaveOptions) -> CoreResult<FileStamp> {
+        if let Some(canonical) = &options.canonical {
+            self.validate()?;
+            let data = canonical::to_canonical_string(self, canonical)?;
+            return storage::write_atomic(path, data.as_bytes(), options);
+        }
+        self.save_touched(|notebook| {
+            notebook.validate()?;
+            let data = serde_json::to_string_pretty(notebook)?;
+            storage::write_atomic(path, data.as_bytes(), options)
+        })
+    }
+
+    /// Runs `save` with `updated_at` bumped, putting the old value back if the
+    /// save fails, e.g. on a conflict, so a refused save changes nothing.
+    fn save_touched(&mut self, save: impl FnOnce(&Self) -> CoreResult<FileStamp>) -> CoreResult<FileStamp> {
+        let previous = self.metadata.updated_at;
+        self.touch();
+        let saved = save(self);
+        if saved.is_err() {
+            self.metadata.updated_at = previous;
+        }
+        saved
+    }
+
     pub fn load_npad<P: AsRef<Path>>(path: P) -> CoreResult<Self> {
+        Self::load_npad_stamped(path).map(|(notebook, _)| notebook)
+    }
+
+    pub fn load_npad_stamped<P: AsRef<Path>>(path: P) -> CoreResult<(Self, FileStamp)> {
         let data = fs::read_to_string(path)?;
         let notebook: Self = serde_json::from_str(&data)?;
         notebook.validate()?;
+        Ok((notebook, FileStamp::of_bytes(data.as_bytes())))
+    }
+
+    /// Saves as a `.npadz` archive, moving large and binary outputs into blobs.
+    pub fn save_npadz<P: AsRef<Path>>(&mut self, path: P) -> CoreResult<FileStamp> {
+        self.save_npadz_with(path, &SaveOptions::default())
+    }
+
+    pub fn save_npadz_with<P: AsRef<Path>>(&mut self, path: P, options: &SaveOptions) -> CoreResult<FileStamp> {
+        self.save_touched(|notebook| archive::save_npadz(notebook, path, options))
+    }
+
+    pub fn load_npadz<P: AsRef<Path>>(path: P) -> CoreResult<Self> {
+        archive::load_npadz(path)
+    }
+
+    /// Loads a notebook only if the file conforms to `schemas/npad.schema.json`,
+    /// reporting every violation instead of serde's first failure.
+    pub fn load_npad_strict<P: AsRef<Path>>(path: P) -> CoreResult<Self> {
+        let data = fs::read_to_string(path)?;
+        let violations = schema::validate_npad_str(&data)?;
+        if !violations.is_empty() {
+            return Err(CoreError::Schema(violations));
+        }
+        let notebook: Self = serde_json::from_str(&data)?;
+        notebook.validate()?;
         Ok(notebook)
     }
+
+    pub fn check_schema<P: AsRef<Path>>(path: P) -> CoreResult<Vec<SchemaViolation>> {
+        let data = fs::read_to_string(path)?;
+        schema::validate_npad_str(&data)
+    }
+}
+
+pub(crate) fn validate_cell_kind(id: Uuid, cell_type: &CellType, language: Option<&str>) -> CoreResult<()> {
+    match cell_type {
+        CellType::Markdown => {
+            if language.is_some() {
+                return Err(CoreError::Validation(format!(
+                    "markdown cell {} must not have a language",
+                    id
+                )));
+            }
+        }
+        CellType::Code => {
+            let lang = language.unwrap_or_default();
+            if lang != "go" && lang != "ruby" && lang != "python" {
+                return Err(CoreError::Validation(format!(
+                    "code cell {} has unsupported language '{}'",
+                    id, lang
+                )));
+            }
+        }
+    }
+    Ok(())
 }
 
 impl Cell {
+    pub fn has_tag(&self, tag: &CellTag) -> bool {
+        self.tags.contains(tag)
+    }
+
     pub fn new_markdown(source: impl Into<String>) -> Self {
         Self {
             id: Uuid::new_v4(),
@@ -166,6 +341,8 @@ impl Cell {
                 status: CellStatus::Idle,
                 duration_ms: 0,
             },
+            tags: BTreeSet::new(),
+            metadata: Map::new(),
         }
     }
 
@@ -181,6 +358,8 @@ impl Cell {
                 status: CellStatus::Idle,
                 duration_ms: 0,
             },
+            tags: BTreeSet::new(),
+            metadata: Map::new(),
         }
     }
 }
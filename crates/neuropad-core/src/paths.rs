@@ -0,0 +1,223 @@
+use crate::{CoreError, CoreResult};
+use rusqlite::{Connection, OpenFlags};
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Overrides where the metadata store and recovery journal live.
+pub const DATA_DIR_ENV: &str = "NEUROPAD_DATA_DIR";
+/// Overrides the location of [`CONFIG_FILE`].
+pub const CONFIG_ENV: &str = "NEUROPAD_CONFIG";
+pub const CONFIG_FILE: &str = "config.json";
+pub const DATABASE_FILE: &str = "neuropad.sqlite";
+pub const RECOVERY_DIR: &str = "recovery";
+/// Where versions before the per-user data directory kept the recovery
+/// journal, next to [`DATABASE_FILE`] in the working directory.
+pub const LEGACY_RECOVERY_DIR: &str = "neuropad-recovery";
+const APP_DIR: &str = "neuropad";
+
+/// User settings read from `<config dir>/neuropad/config.json`.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AppConfig {
+    pub data_dir: Option<PathBuf>,
+}
+
+impl AppConfig {
+    /// Reads the config file; a missing file is the default config.
+    pub fn load<P: AsRef<Path>>(path: P) -> CoreResult<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(serde_json::from_str(&text)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// `$NEUROPAD_CONFIG`, else `config.json` in the platform config directory.
+pub fn config_path() -> Option<PathBuf> {
+    env_path(CONFIG_ENV).or_else(|| dirs::config_dir().map(|dir| dir.join(APP_DIR).join(CONFIG_FILE)))
+}
+
+/// Picks the per-user data directory and creates it. In order of precedence:
+/// `$NEUROPAD_DATA_DIR`, `data_dir` from the config file, `fallback` (e.g. the
+/// directory the host application would use), then the platform data directory
+/// (`$XDG_DATA_HOME/neuropad` on Linux). Data older versions left in the
+/// working directory is moved there while it is still empty; see
+/// [`import_legacy_data`], which fails if that database is still in use.
+pub fn resolve_data_dir(fallback: Option<PathBuf>) -> CoreResult<PathBuf> {
+    let config = match config_path() {
+        Some(path) => AppConfig::load(path)?,
+        None => AppConfig::default(),
+    };
+    let platform = dirs::data_dir().map(|dir| dir.join(APP_DIR));
+    let dir = choose_data_dir(env_path(DATA_DIR_ENV), &config, fallback, platform)
+        .unwrap_or_else(|| PathBuf::from(APP_DIR));
+    fs::create_dir_all(&dir)?;
+    import_legacy_data(&std::env::current_dir()?, &dir)?;
+    Ok(dir)
+}
+
+/// Moves the database (with its journal files) and recovery journal that
+/// older versions kept in `legacy_dir` into `data_dir`. Does nothing once
+/// `data_dir` has a database or journal of its own, so it happens at most
+/// once. Returns whether anything was moved.
+///
+/// A database another process is writing to, or whose rollback journal
+/// cannot be replayed, is refused with [`CoreError::Conflict`] rather than
+/// moved half-written. An older instance that merely has the database open
+/// between transactions holds no lock and cannot be detected.
+pub fn import_legacy_data(legacy_dir: &Path, data_dir: &Path) -> CoreResult<bool> {
+    let database = data_dir.join(DATABASE_FILE);
+    if database.exists() || data_dir.join(RECOVERY_DIR).exists() {
+        return Ok(false);
+    }
+    let legacy_database = legacy_dir.join(DATABASE_FILE);
+    if legacy_database.exists() {
+        ensure_settled(&legacy_database)?;
+    }
+    let mut moved = false;
+    for suffix in ["", "-journal", "-wal", "-shm"] {
+        let name = format!("{DATABASE_FILE}{suffix}");
+        moved |= move_if_exists(&legacy_dir.join(&name), &data_dir.join(&name))?;
+    }
+    moved |= move_if_exists(&legacy_dir.join(LEGACY_RECOVERY_DIR), &data_dir.join(RECOVERY_DIR))?;
+    Ok(moved)
+}
+
+/// Takes the database's write lock and lets it go again. Failing to get it
+/// means another process is using the database; getting it rolls back a hot
+/// journal a crashed older build left behind, so the database is whole
+/// without it.
+fn ensure_settled(database: &Path) -> CoreResult<()> {
+    let in_use = |reason: String| {
+        CoreError::Conflict(format!(
+            "not moving {}: {reason}; close other NeuroPad instances and start again",
+            database.display()
+        ))
+    };
+    let conn = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    // Long enough for a transaction in flight to finish, short enough not to
+    // hold up startup.
+    conn.busy_timeout(Duration::from_secs(1))?;
+    conn.execute_batch("BEGIN EXCLUSIVE; ROLLBACK;")
+        .map_err(|err| in_use(err.to_string()))?;
+    drop(conn);
+    let journal = database.with_file_name(format!("{DATABASE_FILE}-journal"));
+    if fs::metadata(&journal).is_ok_and(|metadata| metadata.len() > 0) {
+        return Err(in_use("its rollback journal was not replayed".to_string()));
+    }
+    Ok(())
+}
+
+/// Renames, or copies and deletes when `from` is on another file system.
+fn move_if_exists(from: &Path, to: &Path) -> CoreResult<bool> {
+    if !from.exists() {
+        return Ok(false);
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(true);
+    }
+    if from.is_dir() {
+        for entry in walkdir::WalkDir::new(from) {
+            let entry = entry.map_err(std::io::Error::from)?;
+            let target = to.join(entry.path().strip_prefix(from).unwrap_or(entry.path()));
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&target)?;
+            } else {
+                fs::copy(entry.path(), &target)?;
+            }
+        }
+        fs::remove_dir_all(from)?;
+    } else {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(true)
+}
+
+fn choose_data_dir(
+    env: Option<PathBuf>,
+    config: &AppConfig,
+    fallback: Option<PathBuf>,
+    platform: Option<PathBuf>,
+) -> Option<PathBuf> {
+    env.or_else(|| config.data_dir.clone()).or(fallback).or(platform)
+}
+
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_beats_config_beats_fallback() {
+        let config = AppConfig {
+            data_dir: Some("/from/config".into()),
+        };
+        let pick = |env: Option<&str>, config: &AppConfig, fallback: Option<&str>| {
+            choose_data_dir(
+                env.map(PathBuf::from),
+                config,
+                fallback.map(PathBuf::from),
+                Some("/platform".into()),
+            )
+        };
+        assert_eq!(pick(Some("/env"), &config, Some("/app")), Some("/env".into()));
+        assert_eq!(pick(None, &config, Some("/app")), Some("/from/config".into()));
+        assert_eq!(pick(None, &AppConfig::default(), Some("/app")), Some("/app".into()));
+        assert_eq!(pick(None, &AppConfig::default(), None), Some("/platform".into()));
+    }
+
+    fn legacy_database(dir: &Path, value: &str) {
+        let conn = Connection::open(dir.join(DATABASE_FILE)).expect("db");
+        conn.execute_batch("CREATE TABLE t(v TEXT)").expect("table");
+        conn.execute("INSERT INTO t VALUES(?1)", [value]).expect("row");
+    }
+
+    fn stored_value(dir: &Path) -> String {
+        Connection::open(dir.join(DATABASE_FILE))
+            .expect("db")
+            .query_row("SELECT v FROM t", [], |row| row.get(0))
+            .expect("row")
+    }
+
+    #[test]
+    fn imports_legacy_data_only_into_an_empty_data_dir() {
+        let legacy = tempfile::tempdir().expect("legacy");
+        let data = tempfile::tempdir().expect("data");
+        legacy_database(legacy.path(), "db");
+        fs::write(legacy.path().join(format!("{DATABASE_FILE}-journal")), "").expect("journal");
+        fs::create_dir(legacy.path().join(LEGACY_RECOVERY_DIR)).expect("journal");
+        fs::write(legacy.path().join(LEGACY_RECOVERY_DIR).join("s.npad"), "{}").expect("snapshot");
+
+        assert!(import_legacy_data(legacy.path(), data.path()).expect("import"));
+        assert_eq!(stored_value(data.path()), "db");
+        assert!(data.path().join(format!("{DATABASE_FILE}-journal")).exists());
+        assert!(data.path().join(RECOVERY_DIR).join("s.npad").exists());
+        assert!(!legacy.path().join(DATABASE_FILE).exists());
+
+        legacy_database(legacy.path(), "stale");
+        assert!(!import_legacy_data(legacy.path(), data.path()).expect("again"));
+        assert_eq!(stored_value(data.path()), "db");
+    }
+
+    #[test]
+    fn refuses_to_move_a_database_in_use() {
+        let legacy = tempfile::tempdir().expect("legacy");
+        let data = tempfile::tempdir().expect("data");
+        legacy_database(legacy.path(), "db");
+        let writer = Connection::open(legacy.path().join(DATABASE_FILE)).expect("db");
+        writer.execute_batch("BEGIN EXCLUSIVE").expect("lock");
+
+        let refused = import_legacy_data(legacy.path(), data.path());
+        assert!(matches!(refused, Err(CoreError::Conflict(_))), "{refused:?}");
+        assert!(legacy.path().join(DATABASE_FILE).exists());
+        assert!(!data.path().join(DATABASE_FILE).exists());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::CoreResult;
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+const NPAD_SCHEMA: &str = include_str!("../../../schemas/npad.schema.json");
+
+/// A single schema failure, located by a JSON pointer into the checked document.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SchemaViolation {
+    pub pointer: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pointer = if self.pointer.is_empty() { "/" } else { &self.pointer };
+        write!(f, "{pointer}: {}", self.message)
+    }
+}
+
+pub fn npad_schema() -> &'static Value {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    SCHEMA.get_or_init(|| serde_json::from_str(NPAD_SCHEMA).expect("bundled npad schema is valid json"))
+}
+
+pub fn validate_npad_str(raw: &str) -> CoreResult<Vec<SchemaViolation>> {
+    let value: Value = serde_json::from_str(raw)?;
+    Ok(validate_npad_value(&value))
+}
+
+pub fn validate_npad_value(value: &Value) -> Vec<SchemaViolation> {
+    let schema = npad_schema();
+    let mut violations = vec![];
+    check(schema, schema, value, "", &mut violations);
+    violations
+}
+
+/// Keywords [`check`] enforces: the subset `schemas/npad.schema.json` uses.
+/// `schemas/ipc.schema.json` needs more (`oneOf`, `const`) and is not
+/// validated here.
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "$ref",
+    "type",
+    "enum",
+    "format",
+    "minimum",
+    "required",
+    "properties",
+    "additionalProperties",
+    "items",
+];
+
+/// Keywords that only describe a schema and never fail a document.
+const ANNOTATIONS: &[&str] = &["$schema", "$id", "$comment", "title", "description", "definitions", "default"];
+
+/// Checks `value` against `schema`. A keyword outside [`SUPPORTED_KEYWORDS`]
+/// is reported as a violation rather than skipped, so a schema edit that
+/// needs more of JSON Schema fails loudly instead of validating less.
+fn check(root: &Value, schema: &Value, value: &Value, pointer: &str, out: &mut Vec<SchemaViolation>) {
+    let Some(schema) = schema.as_object() else {
+        return;
+    };
+
+    for keyword in schema.keys() {
+        if !is_known(keyword) {
+            push(out, pointer, format!("schema keyword '{keyword}' is not supported"));
+        }
+    }
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match resolve_ref(root, reference) {
+            Some(target) => check(root, target, value, pointer, out),
+            None => push(out, pointer, format!("unresolvable schema reference '{reference}'")),
+        }
+        return;
+    }
+
+    if let Some(expected) = schema.get("type") {
+        let allowed = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|name| matches_type(name, value)) {
+            push(
+                out,
+                pointer,
+                format!("expected {}, found {}", allowed.join(" or "), type_name(value)),
+            );
+            return;
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            let listed = options.iter().map(Value::to_string).collect::<Vec<_>>().join(", ");
+            push(out, pointer, format!("{value} is not one of [{listed}]"));
+        }
+    }
+
+    if let (Some(format), Some(text)) = (schema.get("format").and_then(Value::as_str), value.as_str()) {
+        let valid = match format {
+            "date-time" => DateTime::parse_from_rfc3339(text).is_ok(),
+            "uuid" => Uuid::parse_str(text).is_ok(),
+            _ => true,
+        };
+        if !valid {
+            push(out, pointer, format!("'{text}' is not a valid {format}"));
+        }
+    }
+
+    if let (Some(minimum), Some(number)) = (schema.get("minimum").and_then(Value::as_f64), value.as_f64()) {
+        if number < minimum {
+            push(out, pointer, format!("{number} is less than the minimum of {minimum}"));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    push(out, pointer, format!("missing required property '{key}'"));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (key, child) in object {
+            let child_pointer = format!("{pointer}/{}", escape_pointer(key));
+            match properties.and_then(|p| p.get(key)) {
+                Some(child_schema) => check(root, child_schema, child, &child_pointer, out),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        push(out, &child_pointer, "unexpected property".to_string())
+                    }
+                    Some(extra @ Value::Object(_)) => check(root, extra, child, &child_pointer, out),
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (index, item) in array.iter().enumerate() {
+            check(root, items, item, &format!("{pointer}/{index}"), out);
+        }
+    }
+}
+
+fn is_known(keyword: &str) -> bool {
+    SUPPORTED_KEYWORDS.contains(&keyword) || ANNOTATIONS.contains(&keyword)
+}
+
+fn resolve_ref<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+fn matches_type(name: &str, value: &Value) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn push(out: &mut Vec<SchemaViolation>, pointer: &str, message: String) {
+    out.push(SchemaViolation {
+        pointer: pointer.to_string(),
+        message,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Notebook;
+    use serde_json::json;
+
+    #[test]
+    fn accepts_serialized_notebook() {
+        let mut nb = Notebook::new("schema");
+        nb.add_markdown_cell("# Title");
+        nb.add_code_cell("python", "print(1)");
+        let value = serde_json::to_value(&nb).expect("serialize");
+        assert!(validate_npad_value(&value).is_empty());
+    }
+
+    #[test]
+    fn reports_pointer_for_each_violation() {
+        let mut nb = Notebook::new("schema");
+        nb.add_code_cell("go", "fmt.Println(1)");
+        let mut value = serde_json::to_value(&nb).expect("serialize");
+        value["cells"][0]["type"] = json!("raw");
+        value["cells"][0]["execution"]["count"] = json!(-1);
+        value["metadata"].as_object_mut().unwrap().remove("title");
+
+        let violations = validate_npad_value(&value);
+        let pointers = violations.iter().map(|v| v.pointer.as_str()).collect::<Vec<_>>();
+        assert_eq!(violations.len(), 3, "{violations:?}");
+        assert!(pointers.contains(&"/metadata"));
+        assert!(pointers.contains(&"/cells/0/type"));
+        assert!(pointers.contains(&"/cells/0/execution/count"));
+    }
+
+    /// Every keyword of `schema` and the schemas nested in it.
+    fn keywords(schema: &Value, found: &mut Vec<String>) {
+        let Some(schema) = schema.as_object() else {
+            return;
+        };
+        for (keyword, value) in schema {
+            found.push(keyword.clone());
+            match keyword.as_str() {
+                "properties" | "definitions" => value
+                    .as_object()
+                    .into_iter()
+                    .flat_map(|nested| nested.values())
+                    .for_each(|nested| keywords(nested, found)),
+                "items" | "additionalProperties" => keywords(value, found),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn bundled_schema_uses_only_supported_keywords() {
+        let mut found = vec![];
+        keywords(npad_schema(), &mut found);
+        let unsupported = found
+            .iter()
+            .filter(|keyword| !is_known(keyword))
+            .collect::<Vec<_>>();
+        assert!(unsupported.is_empty(), "{unsupported:?}");
+    }
+
+    #[test]
+    fn unsupported_keywords_are_violations() {
+        let schema = json!({ "type": "object", "properties": { "kind": { "const": "ack" } } });
+        let mut violations = vec![];
+        check(&schema, &schema, &json!({ "kind": "nack" }), "", &mut violations);
+        assert_eq!(
+            violations,
+            vec![SchemaViolation {
+                pointer: "/kind".to_string(),
+                message: "schema keyword 'const' is not supported".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use crate::diff::{diff_notebooks, NotebookDiff};
+use crate::digest::sha256_hex;
+use crate::{CoreError, CoreResult, MetadataStore, Notebook};
+use chrono::{DateTime, Utc};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{Read, Write};
+use uuid::Uuid;
+
+/// Older versions of a notebook are dropped once it has this many.
+pub const MAX_VERSIONS_PER_NOTEBOOK: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NotebookVersion {
+    pub id: i64,
+    pub notebook_path: String,
+    pub saved_at: String,
+    pub title: String,
+    pub cell_count: usize,
+}
+
+/// Local version history. Each version stores the notebook header plus the
+/// ordered hashes of its cells; cells themselves are compressed blobs shared
+/// by every version that contains an identical cell.
+impl MetadataStore {
+    /// Records a version unless the notebook is unchanged since the last one;
+    /// either way returns the id of the version matching `notebook`.
+    pub fn snapshot_notebook(&self, path: &str, notebook: &Notebook, saved_at: DateTime<Utc>) -> CoreResult<i64> {
+        let mut cell_hashes = Vec::with_capacity(notebook.cells.len());
+        let mut blobs = Vec::with_capacity(notebook.cells.len());
+        for cell in &notebook.cells {
+            let json = serde_json::to_vec(cell)?;
+            let hash = sha256_hex(&json);
+            cell_hashes.push(hash.clone());
+            blobs.push((hash, json));
+        }
+        let cell_hashes = serde_json::to_string(&cell_hashes)?;
+
+        // The notebook as stored on disk, minus its cells.
+        let header = serde_json::json!({
+            "version": notebook.version,
+            "metadata": notebook.metadata,
+            "cells": [],
+        });
+
+        let latest: Option<(i64, Vec<u8>, String)> = self
+            .conn
+            .query_row(
+                r#"
+                SELECT id, header, cell_hashes FROM notebook_versions
+                WHERE notebook_path = ?1
+                ORDER BY saved_at DESC, id DESC
+                LIMIT 1
+                "#,
+                params![path],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        if let Some((id, latest_header, hashes)) = latest {
+            let latest_header: Value = serde_json::from_slice(&decompress(&latest_header)?)?;
+            if hashes == cell_hashes && header_key(latest_header) == header_key(header.clone()) {
+                return Ok(id);
+            }
+        }
+
+        let header = compress(&serde_json::to_vec(&header)?)?;
+
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut insert_blob = tx.prepare("INSERT OR IGNORE INTO snapshot_blobs(hash, data) VALUES(?1, ?2)")?;
+            for (hash, json) in blobs {
+                insert_blob.execute(params![hash, compress(&json)?])?;
+            }
+        }
+        tx.execute(
+            r#"
+            INSERT INTO notebook_versions(notebook_path, saved_at, title, header, cell_hashes)
+            VALUES(?1, ?2, ?3, ?4, ?5)
+            "#,
+            params![path, saved_at.to_rfc3339(), notebook.metadata.title, header, cell_hashes],
+        )?;
+        let id = tx.last_insert_rowid();
+        let pruned = tx.execute(
+            r#"
+            DELETE FROM notebook_versions
+            WHERE notebook_path = ?1 AND id NOT IN (
+                SELECT id FROM notebook_versions
+                WHERE notebook_path = ?1
+                ORDER BY saved_at DESC, id DESC
+                LIMIT ?2
+            )
+            "#,
+            params![path, MAX_VERSIONS_PER_NOTEBOOK as i64],
+        )?;
+        if pruned > 0 {
+            delete_orphan_blobs(&tx)?;
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// A notebook's versions, newest first.
+    pub fn list_versions(&self, path: &str) -> CoreResult<Vec<NotebookVersion>> {
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT id, notebook_path, saved_at, title, json_array_length(cell_hashes)
+            FROM notebook_versions
+            WHERE notebook_path = ?1
+            ORDER BY saved_at DESC, id DESC
+            "#,
+        )?;
+        let rows = stmt.query_map(params![path], |row| {
+            Ok(NotebookVersion {
+                id: row.get(0)?,
+                notebook_path: row.get(1)?,
+                saved_at: row.get(2)?,
+                title: row.get(3)?,
+                cell_count: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn load_version(&self, version_id: i64) -> CoreResult<Notebook> {
+        let (header, cell_hashes): (Vec<u8>, String) = self
+            .conn
+            .query_row(
+                "SELECT header, cell_hashes FROM notebook_versions WHERE id = ?1",
+                params![version_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .ok_or_else(|| version_not_found(version_id))?;
+        let mut notebook: Notebook = serde_json::from_slice(&decompress(&header)?)?;
+        let mut load_blob = self.conn.prepare("SELECT data FROM snapshot_blobs WHERE hash = ?1")?;
+        for hash in serde_json::from_str::<Vec<String>>(&cell_hashes)? {
+            let data: Vec<u8> = load_blob
+                .query_row(params![hash], |row| row.get(0))
+                .optional()?
+                .ok_or_else(|| CoreError::Validation(format!("snapshot blob {hash} is missing")))?;
+            notebook.cells.push(serde_json::from_slice(&decompress(&data)?)?);
+        }
+        Ok(notebook)
+    }
+
+    /// What changed between the version and `current`.
+    pub fn diff_version(&self, version_id: i64, current: &Notebook) -> CoreResult<NotebookDiff> {
+        Ok(diff_notebooks(&self.load_version(version_id)?, current))
+    }
+
+    /// Rolls `current` back to the version's cells and metadata as one
+    /// undoable step.
+    pub fn restore_version(&self, version_id: i64, current: &mut Notebook) -> CoreResult<()> {
+        let version = self.load_version(version_id)?;
+        current.replace_contents(version.cells, version.metadata)
+    }
+
+    /// Brings back one cell as it was in the version, re-inserting it at its
+    /// old position if it has since been deleted.
+    pub fn restore_version_cell(&self, version_id: i64, cell_id: Uuid, current: &mut Notebook) -> CoreResult<()> {
+        let version = self.load_version(version_id)?;
+        let index = version
+            .cells
+            .iter()
+            .position(|cell| cell.id == cell_id)
+            .ok_or(CoreError::CellNotFound(cell_id))?;
+        let cell = version.cells[index].clone();
+        if current.cell(cell_id).is_some() {
+            current.replace_cell(cell)
+        } else {
+            current.insert_cell_at(index.min(current.cells.len()), cell).map(|_| ())
+        }
+    }
+}
+
+/// Drops cell blobs no remaining version refers to.
+pub(crate) fn delete_orphan_blobs(conn: &Connection) -> CoreResult<()> {
+    conn.execute(
+        r#"
+        DELETE FROM snapshot_blobs WHERE hash NOT IN (
+            SELECT h.value FROM notebook_versions v, json_each(v.cell_hashes) h
+        )
+        "#,
+        [],
+    )?;
+    Ok(())
+}
+
+/// What of a header decides whether it is a new version: everything but
+/// `updated_at`, which every save bumps.
+fn header_key(mut header: Value) -> Value {
+    if let Some(metadata) = header.get_mut("metadata").and_then(Value::as_object_mut) {
+        metadata.remove("updated_at");
+    }
+    header
+}
+
+fn version_not_found(version_id: i64) -> CoreError {
+    CoreError::Validation(format!("notebook version {version_id} not found"))
+}
+
+fn compress(bytes: &[u8]) -> CoreResult<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+fn decompress(bytes: &[u8]) -> CoreResult<Vec<u8>> {
+    let mut out = Vec::new();
+    DeflateDecoder::new(bytes).read_to_end(&mut out)?;
+    Ok(out)
+}
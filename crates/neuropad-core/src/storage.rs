@@ -0,0 +1,144 @@
+use crate::canonical::CanonicalOptions;
+use crate::digest::sha256_hex;
+use crate::{CoreError, CoreResult};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Identifies the on-disk contents of a file at the time it was loaded or saved.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileStamp {
+    pub len: u64,
+    /// Hex SHA-256 of the contents.
+    pub sha256: String,
+}
+
+impl FileStamp {
+    pub fn of_bytes(bytes: &[u8]) -> Self {
+        Self {
+            len: bytes.len() as u64,
+            sha256: sha256_hex(bytes),
+        }
+    }
+
+    /// Returns `None` when the file does not exist.
+    pub fn of_file<P: AsRef<Path>>(path: P) -> CoreResult<Option<Self>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(Self::of_bytes(&bytes))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    /// Copy the previous version to `<path>.bak` before replacing it.
+    pub backup: bool,
+    /// Refuse to overwrite unless the file still matches this stamp.
+    pub expected: Option<FileStamp>,
+    /// Write `.npad` files in the canonical, diff-friendly layout.
+    pub canonical: Option<CanonicalOptions>,
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+/// Replaces `path` with `bytes` via a synced temp file and a rename, so readers
+/// only ever observe the old or the new contents. A symlink is followed and
+/// its target replaced, so the link keeps pointing at the saved file.
+///
+/// The `expected` stamp is checked before anything is written and again just
+/// before the rename. A change landing between that last check and the rename
+/// is still overwritten; without file locking that window can only be narrowed.
+pub fn write_atomic<P: AsRef<Path>>(path: P, bytes: &[u8], options: &SaveOptions) -> CoreResult<FileStamp> {
+    let path = &resolve_symlink(path.as_ref())?;
+    if let Some(expected) = &options.expected {
+        check_unchanged(path, expected)?;
+    }
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| CoreError::Validation(format!("'{}' is not a file path", path.display())))?;
+    let temp = dir.join(format!(".{}.{}.tmp", file_name.to_string_lossy(), Uuid::new_v4().simple()));
+
+    let result = write_synced(&temp, bytes).and_then(|_| {
+        // Keep the mode of the file being replaced, e.g. a private 0600.
+        match fs::metadata(path) {
+            Ok(existing) => fs::set_permissions(&temp, existing.permissions())?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        // Writing the temp file can take a while for a large notebook.
+        if let Some(expected) = &options.expected {
+            check_unchanged(path, expected)?;
+        }
+        if options.backup && path.exists() {
+            fs::copy(path, backup_path(path))?;
+        }
+        fs::rename(&temp, path)?;
+        sync_dir(&dir);
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    Ok(FileStamp::of_bytes(bytes))
+}
+
+/// The file a symlink at `path` points to, or `path` itself if it is not a
+/// link. A dangling link resolves to where it points.
+fn resolve_symlink(path: &Path) -> CoreResult<PathBuf> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {}
+        _ => return Ok(path.to_path_buf()),
+    }
+    match fs::canonicalize(path) {
+        Ok(target) => Ok(target),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let target = fs::read_link(path)?;
+            Ok(path.parent().map_or(target.clone(), |parent| parent.join(target)))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn check_unchanged(path: &Path, expected: &FileStamp) -> CoreResult<()> {
+    match FileStamp::of_file(path)? {
+        Some(current) if current == *expected => Ok(()),
+        Some(_) => Err(CoreError::Conflict(format!(
+            "{} was modified on disk since it was loaded",
+            path.display()
+        ))),
+        None => Err(CoreError::Conflict(format!(
+            "{} was removed since it was loaded",
+            path.display()
+        ))),
+    }
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> CoreResult<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Persists the rename itself; directories cannot be opened for syncing on Windows.
+fn sync_dir(dir: &Path) {
+    if cfg!(unix) {
+        if let Ok(handle) = File::open(dir) {
+            let _ = handle.sync_all();
+        }
+    }
+}
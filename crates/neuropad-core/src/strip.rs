@@ -0,0 +1,256 @@
+use crate::canonical::{self, CanonicalOptions};
+use crate::storage::{self, FileStamp, SaveOptions};
+use crate::{CellExecution, CellTag, CoreError, CoreResult, Notebook};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Cell metadata keys written by editors on every run; never worth committing.
+pub const VOLATILE_CELL_METADATA: &[&str] = &["execution", "ExecuteTime", "collapsed", "scrolled"];
+
+/// Which outputs survive stripping. Everything else is removed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StripOptions {
+    /// Cells carrying any of these tags keep all outputs and execution state.
+    #[serde(default)]
+    pub keep_tags: BTreeSet<CellTag>,
+    /// Outputs with a matching MIME type are kept, e.g. `image/png` or `image/*`.
+    #[serde(default)]
+    pub keep_mimes: Vec<String>,
+}
+
+impl StripOptions {
+    fn keeps_cell<'a>(&self, mut tags: impl Iterator<Item = &'a CellTag>) -> bool {
+        tags.any(|tag| self.keep_tags.contains(tag))
+    }
+
+    fn keeps_mime(&self, mime: &str) -> bool {
+        self.keep_mimes.iter().any(|pattern| match pattern.strip_suffix("/*") {
+            Some(prefix) => mime.split('/').next() == Some(prefix),
+            None => pattern == mime,
+        })
+    }
+}
+
+/// Clears outputs and execution state in place, honouring the keep-lists.
+pub fn strip_notebook(notebook: &mut Notebook, options: &StripOptions) {
+    for cell in &mut notebook.cells {
+        for key in VOLATILE_CELL_METADATA {
+            cell.metadata.remove(*key);
+        }
+        if options.keeps_cell(cell.tags.iter()) {
+            continue;
+        }
+        cell.outputs.retain(|output| options.keeps_mime(&output.mime));
+        cell.execution = CellExecution::idle();
+    }
+}
+
+/// Strips a `.npad` document and renders it canonically without timestamps,
+/// so the result only changes when the notebook's content does. `updated_at`
+/// is reset to `created_at`, which never changes.
+pub fn strip_npad_str(data: &str, options: &StripOptions) -> CoreResult<String> {
+    let mut notebook: Notebook = serde_json::from_str(data)?;
+    notebook.validate()?;
+    strip_notebook(&mut notebook, options);
+    notebook.metadata.updated_at = notebook.metadata.created_at;
+    canonical::to_canonical_string(
+        &notebook,
+        &CanonicalOptions {
+            strip_timestamps: true,
+            strip_outputs: false,
+        },
+    )
+}
+
+/// Strips a Jupyter document, leaving everything this crate does not model untouched.
+pub fn strip_ipynb_str(data: &str, options: &StripOptions) -> CoreResult<String> {
+    let mut root: Value = serde_json::from_str(data)?;
+    let cells = root
+        .get_mut("cells")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| CoreError::Validation("ipynb missing cells array".to_string()))?;
+    for cell in cells {
+        strip_ipynb_cell(cell, options);
+    }
+    let mut out = serde_json::to_string_pretty(&root)?;
+    out.push('\n');
+    Ok(out)
+}
+
+fn strip_ipynb_cell(cell: &mut Value, options: &StripOptions) {
+    let Some(cell) = cell.as_object_mut() else {
+        return;
+    };
+    let metadata = cell.get_mut("metadata").and_then(Value::as_object_mut);
+    let tags = metadata
+        .as_ref()
+        .and_then(|m| m.get("tags"))
+        .and_then(Value::as_array)
+        .map(|tags| tags.iter().filter_map(Value::as_str).map(CellTag::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Some(metadata) = metadata {
+        for key in VOLATILE_CELL_METADATA {
+            metadata.remove(*key);
+        }
+    }
+    if cell.get("cell_type").and_then(Value::as_str) != Some("code") || options.keeps_cell(tags.iter()) {
+        return;
+    }
+    if let Some(outputs) = cell.get_mut("outputs").and_then(Value::as_array_mut) {
+        outputs.retain_mut(|output| strip_ipynb_output(output, options));
+    }
+    cell.insert("execution_count".to_string(), Value::Null);
+}
+
+/// Returns whether the output survives. Streams and tracebacks count as `text/plain`.
+fn strip_ipynb_output(output: &mut Value, options: &StripOptions) -> bool {
+    match output.get("output_type").and_then(Value::as_str) {
+        Some("execute_result") | Some("display_data") => {
+            let Some(data) = output.get_mut("data").and_then(Value::as_object_mut) else {
+                return false;
+            };
+            data.retain(|mime, _| options.keeps_mime(mime));
+            if data.is_empty() {
+                return false;
+            }
+            if let Some(output) = output.as_object_mut() {
+                output.insert("metadata".to_string(), Value::Object(Map::new()));
+                if output.contains_key("execution_count") {
+                    output.insert("execution_count".to_string(), Value::Null);
+                }
+            }
+            true
+        }
+        _ => options.keeps_mime("text/plain"),
+    }
+}
+
+/// Writes a stripped copy of `notebook` to `path` as `.npad`, leaving the
+/// notebook itself, outputs and all, untouched.
+pub fn write_stripped<P: AsRef<Path>>(notebook: &Notebook, path: P, options: &StripOptions) -> CoreResult<FileStamp> {
+    let stripped = strip_npad_str(&serde_json::to_string(notebook)?, options)?;
+    storage::write_atomic(path.as_ref(), stripped.as_bytes(), &SaveOptions::default())
+}
+
+/// Strips a `.npad` or `.ipynb` file in place, picking the format from its extension.
+pub fn strip_file<P: AsRef<Path>>(path: P, options: &StripOptions) -> CoreResult<FileStamp> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)?;
+    let stripped = if is_ipynb(path) {
+        strip_ipynb_str(&data, options)?
+    } else {
+        strip_npad_str(&data, options)?
+    };
+    storage::write_atomic(path, stripped.as_bytes(), &SaveOptions::default())
+}
+
+pub fn is_ipynb(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("ipynb")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CellOutput, CellOutputKind, CellStatus};
+    use serde_json::json;
+
+    fn output(mime: &str, data: &str) -> CellOutput {
+        CellOutput {
+            kind: CellOutputKind::Result,
+            mime: mime.to_string(),
+            data: data.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn strips_npad_outputs_except_kept_tags_and_mimes() {
+        let mut nb = Notebook::new("strip");
+        let secret = nb.add_code_cell("python", "print(token)");
+        let plot = nb.add_code_cell("python", "plot()");
+        let pinned = nb.add_code_cell("python", "summary()");
+        nb.tag_cell(pinned, CellTag::from("keep-output")).expect("tag");
+        for cell in &mut nb.cells {
+            cell.outputs = vec![output("text/plain", "sk-123"), output("image/png", "iVBOR")];
+            cell.execution.count = 7;
+            cell.execution.status = CellStatus::Ok;
+            cell.metadata.insert("collapsed".to_string(), json!(true));
+        }
+
+        let options = StripOptions {
+            keep_tags: BTreeSet::from([CellTag::from("keep-output")]),
+            keep_mimes: vec!["image/*".to_string()],
+        };
+        let data = serde_json::to_string(&nb).expect("serialize");
+        let stripped: Notebook =
+            serde_json::from_str(&strip_npad_str(&data, &options).expect("strip")).expect("reload");
+
+        let cell = |id| stripped.cell(id).expect("cell");
+        assert_eq!(cell(secret).outputs.len(), 1);
+        assert_eq!(cell(secret).outputs[0].mime, "image/png");
+        assert_eq!(cell(secret).execution.count, 0);
+        assert_eq!(cell(plot).execution.status, CellStatus::Idle);
+        assert_eq!(cell(pinned).outputs.len(), 2);
+        assert!(cell(pinned).metadata.is_empty());
+
+        let dir = tempfile::tempdir().expect("tempdir");
+        let copy = dir.path().join("clean.npad");
+        write_stripped(&nb, &copy, &options).expect("write copy");
+        assert_eq!(
+            fs::read_to_string(&copy).expect("read copy"),
+            strip_npad_str(&data, &options).expect("strip")
+        );
+        assert_eq!(nb.cell(secret).expect("cell").outputs.len(), 2);
+
+        // Saving again only bumps `updated_at`, which must not show up.
+        nb.metadata.updated_at += chrono::Duration::seconds(5);
+        let resaved = serde_json::to_string(&nb).expect("serialize");
+        assert_eq!(
+            strip_npad_str(&resaved, &options).expect("strip"),
+            strip_npad_str(&data, &options).expect("strip")
+        );
+    }
+
+    #[test]
+    fn strips_ipynb_outputs_and_counts() {
+        let data = json!({
+            "cells": [
+                {
+                    "cell_type": "code",
+                    "execution_count": 4,
+                    "metadata": { "ExecuteTime": {}, "tags": [] },
+                    "source": ["print(1)"],
+                    "outputs": [
+                        { "output_type": "stream", "name": "stdout", "text": ["secret\n"] },
+                        {
+                            "output_type": "execute_result",
+                            "execution_count": 4,
+                            "metadata": {},
+                            "data": { "text/plain": ["1"], "image/svg+xml": ["<svg/>"] }
+                        }
+                    ]
+                },
+                { "cell_type": "markdown", "metadata": {}, "source": ["# hi"] }
+            ],
+            "metadata": {},
+            "nbformat": 4,
+            "nbformat_minor": 5
+        });
+        let options = StripOptions {
+            keep_mimes: vec!["image/svg+xml".to_string()],
+            ..StripOptions::default()
+        };
+        let stripped: Value =
+            serde_json::from_str(&strip_ipynb_str(&data.to_string(), &options).expect("strip")).expect("json");
+
+        let cell = &stripped["cells"][0];
+        assert_eq!(cell["execution_count"], Value::Null);
+        assert_eq!(cell["metadata"], json!({ "tags": [] }));
+        assert_eq!(cell["outputs"].as_array().map(Vec::len), Some(1));
+        assert_eq!(cell["outputs"][0]["data"], json!({ "image/svg+xml": ["<svg/>"] }));
+        assert_eq!(stripped["cells"][1], data["cells"][1]);
+    }
+}
@@ -0,0 +1,593 @@
+use crate::{ipynb, CellType, CoreError, CoreResult, MetadataStore, Notebook};
+use crate::metadata::delete_indexed_cells;
+use chrono::Utc;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+pub const NOTEBOOK_EXTENSIONS: &[&str] = &["npad", "npadz", "ipynb"];
+
+/// Directories never descended into, besides hidden ones.
+pub const SKIPPED_DIRS: &[&str] = &["node_modules", "target", "__pycache__"];
+
+/// File-system events arriving within this window are rescanned together.
+pub const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// A batch is rescanned once its first event is this old, even if events
+/// keep arriving.
+pub const WATCH_MAX_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Workspace {
+    pub id: i64,
+    pub root: String,
+    pub name: String,
+    pub added_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WorkspaceFile {
+    pub workspace_id: i64,
+    pub path: String,
+    /// Relative to the workspace root with `/` separators, for the project tree.
+    pub relative_path: String,
+    pub format: String,
+    pub title: String,
+    pub languages: Vec<String>,
+    pub cell_count: usize,
+    pub code_cell_count: usize,
+    pub mtime_ms: i64,
+    /// Why the file could not be parsed; such files stay listed but unsearchable.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+}
+
+pub fn is_notebook_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| NOTEBOOK_EXTENSIONS.contains(&ext))
+}
+
+fn is_skipped_dir(name: &str) -> bool {
+    name.starts_with('.') || SKIPPED_DIRS.contains(&name)
+}
+
+/// Registered folders whose notebooks are indexed for the project tree and
+/// full-text search. Scans are incremental: files whose mtime is unchanged
+/// are not parsed again.
+impl MetadataStore {
+    /// Registers `root`, or returns the existing workspace for it.
+    pub fn add_workspace<P: AsRef<Path>>(&self, root: P) -> CoreResult<Workspace> {
+        let root = fs::canonicalize(root.as_ref())?;
+        if !root.is_dir() {
+            return Err(CoreError::Validation(format!("{} is not a directory", root.display())));
+        }
+        let root_str = root.to_string_lossy().to_string();
+        let name = root
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| root_str.clone());
+        self.conn.execute(
+            "INSERT OR IGNORE INTO workspaces(root, name, added_at) VALUES(?1, ?2, ?3)",
+            params![root_str, name, Utc::now().to_rfc3339()],
+        )?;
+        Ok(self.conn.query_row(
+            "SELECT id, root, name, added_at FROM workspaces WHERE root = ?1",
+            params![root_str],
+            workspace_from_row,
+        )?)
+    }
+
+    pub fn list_workspaces(&self) -> CoreResult<Vec<Workspace>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, root, name, added_at FROM workspaces ORDER BY name COLLATE NOCASE, id")?;
+        let rows = stmt.query_map([], workspace_from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn workspace(&self, id: i64) -> CoreResult<Workspace> {
+        self.conn
+            .query_row(
+                "SELECT id, root, name, added_at FROM workspaces WHERE id = ?1",
+                params![id],
+                workspace_from_row,
+            )
+            .optional()?
+            .ok_or_else(|| CoreError::Validation(format!("workspace {id} not found")))
+    }
+
+    /// Unregisters the workspace and drops its files from the search index,
+    /// except notebooks saved in the app, which stay searchable. Files that
+    /// also sit under another workspace's root move to it instead. The files
+    /// themselves are left alone.
+    pub fn remove_workspace(&self, id: i64) -> CoreResult<()> {
+        let remaining = self
+            .list_workspaces()?
+            .into_iter()
+            .filter(|workspace| workspace.id != id)
+            .collect::<Vec<_>>();
+        let tx = self.conn.unchecked_transaction()?;
+        let paths = {
+            let mut stmt = tx.prepare("SELECT path FROM workspace_files WHERE workspace_id = ?1")?;
+            let rows = stmt.query_map(params![id], |row| row.get::<_, String>(0))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for path in &paths {
+            match owner(&remaining, Path::new(path)) {
+                Some(outer) => {
+                    tx.execute(
+                        "UPDATE workspace_files SET workspace_id = ?1, relative_path = ?2 WHERE path = ?3",
+                        params![outer.id, relative_path(outer, Path::new(path)), path],
+                    )?;
+                }
+                None => delete_workspace_cells(&tx, path)?,
+            }
+        }
+        tx.execute("DELETE FROM workspace_files WHERE workspace_id = ?1", params![id])?;
+        tx.execute("DELETE FROM workspaces WHERE id = ?1", params![id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn workspace_files(&self, id: i64) -> CoreResult<Vec<WorkspaceFile>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{WORKSPACE_FILE_COLUMNS} WHERE workspace_id = ?1 ORDER BY relative_path"
+        ))?;
+        let rows = stmt.query_map(params![id], workspace_file_from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Walks the whole workspace, indexing new and modified notebooks and
+    /// forgetting ones that disappeared. Holds `self` throughout; callers
+    /// sharing the store should read with [`WorkspaceScan`] instead.
+    pub fn scan_workspace(&self, id: i64) -> CoreResult<ScanReport> {
+        let workspace = self.workspace(id)?;
+        let scan = WorkspaceScan::of_workspace(&workspace, &self.list_workspaces()?, &self.workspace_mtimes(id)?);
+        self.apply_workspace_scan(scan)
+    }
+
+    /// Re-indexes specific paths after file-system changes, see
+    /// [`WorkspaceScan::of_paths`].
+    pub fn rescan_paths(&self, paths: &[PathBuf]) -> CoreResult<ScanReport> {
+        let scan = WorkspaceScan::of_paths(&self.list_workspaces()?, &self.indexed_mtimes()?, paths);
+        self.apply_workspace_scan(scan)
+    }
+
+    /// The indexed files of workspace `id` and their mtimes, for
+    /// [`WorkspaceScan::of_workspace`].
+    pub fn workspace_mtimes(&self, id: i64) -> CoreResult<HashMap<String, i64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, mtime_ms FROM workspace_files WHERE workspace_id = ?1")?;
+        let rows = stmt.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<HashMap<_, _>, _>>()?)
+    }
+
+    /// Every indexed workspace file and its mtime, for [`WorkspaceScan::of_paths`].
+    pub fn indexed_mtimes(&self) -> CoreResult<HashMap<String, i64>> {
+        let mut stmt = self.conn.prepare("SELECT path, mtime_ms FROM workspace_files")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<HashMap<_, _>, _>>()?)
+    }
+
+    /// Records what a [`WorkspaceScan`] read: upserts changed files, refreshes
+    /// their search entries and forgets removed ones.
+    pub fn apply_workspace_scan(&self, scan: WorkspaceScan) -> CoreResult<ScanReport> {
+        let mut report = ScanReport {
+            unchanged: scan.unchanged,
+            ..ScanReport::default()
+        };
+        // A workspace removed while its files were being read stays removed.
+        let workspaces = self
+            .list_workspaces()?
+            .into_iter()
+            .map(|workspace| workspace.id)
+            .collect::<BTreeSet<_>>();
+        for ChangedFile { file, notebook, known } in scan.changed {
+            if !workspaces.contains(&file.workspace_id) {
+                continue;
+            }
+            match &notebook {
+                Some(notebook) => self.index_notebook_cells(&file.path, notebook)?,
+                None => self.remove_notebook_cells(&file.path)?,
+            }
+            self.conn.execute(
+                r#"
+                INSERT INTO workspace_files(workspace_id, path, relative_path, format, title, languages,
+                                            cell_count, code_cell_count, mtime_ms, error)
+                VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                ON CONFLICT(path) DO UPDATE SET
+                    workspace_id = excluded.workspace_id,
+                    relative_path = excluded.relative_path,
+                    format = excluded.format,
+                    title = excluded.title,
+                    languages = excluded.languages,
+                    cell_count = excluded.cell_count,
+                    code_cell_count = excluded.code_cell_count,
+                    mtime_ms = excluded.mtime_ms,
+                    error = excluded.error
+                "#,
+                params![
+                    file.workspace_id,
+                    file.path,
+                    file.relative_path,
+                    file.format,
+                    file.title,
+                    serde_json::to_string(&file.languages)?,
+                    file.cell_count as i64,
+                    file.code_cell_count as i64,
+                    file.mtime_ms,
+                    file.error,
+                ],
+            )?;
+            match (notebook, known) {
+                (None, _) => report.failed += 1,
+                (Some(_), true) => report.updated += 1,
+                (Some(_), false) => report.added += 1,
+            }
+        }
+        for path in scan.removed {
+            if self.forget_workspace_file(&path)? {
+                report.removed += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Returns whether the path was indexed.
+    fn forget_workspace_file(&self, path: &str) -> CoreResult<bool> {
+        delete_workspace_cells(&self.conn, path)?;
+        let removed = self
+            .conn
+            .execute("DELETE FROM workspace_files WHERE path = ?1", params![path])?;
+        Ok(removed > 0)
+    }
+}
+
+/// Drops the search rows of a workspace file unless the notebook was also
+/// saved in the app, whose index of it does not depend on the workspace.
+fn delete_workspace_cells(conn: &Connection, path: &str) -> CoreResult<()> {
+    let saved: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM notebook_index WHERE path = ?1)",
+        params![path],
+        |row| row.get(0),
+    )?;
+    if saved {
+        return Ok(());
+    }
+    delete_indexed_cells(conn, path)
+}
+
+/// Notebooks read from disk, for [`MetadataStore::apply_workspace_scan`] to
+/// record. Reading needs nothing from the store but the mtimes of what is
+/// already indexed, so a store shared behind a lock is only held for that
+/// and for the writes, not while files are walked and parsed.
+#[derive(Debug, Default)]
+pub struct WorkspaceScan {
+    changed: Vec<ChangedFile>,
+    removed: Vec<String>,
+    unchanged: usize,
+    read: BTreeSet<String>,
+}
+
+/// A new, modified or unreadable file; `notebook` is `None` when it failed
+/// to load.
+#[derive(Debug)]
+struct ChangedFile {
+    file: WorkspaceFile,
+    notebook: Option<Notebook>,
+    known: bool,
+}
+
+impl WorkspaceScan {
+    /// Walks the whole workspace. `known` maps its indexed files to their
+    /// mtimes; the ones no longer found are forgotten. Files under the root of
+    /// a workspace nested in this one are left to that workspace, see
+    /// `workspaces`, which lists every registered workspace.
+    pub fn of_workspace(workspace: &Workspace, workspaces: &[Workspace], known: &HashMap<String, i64>) -> Self {
+        let mut scan = Self::default();
+        for path in walk_notebooks(Path::new(&workspace.root)) {
+            if !is_nested_elsewhere(workspaces, workspace, &path) {
+                scan.read_file(workspace, &path, known);
+            }
+        }
+        // A file claimed by a nested workspace is moved by that workspace's
+        // scan, not forgotten here.
+        let gone = known
+            .keys()
+            .filter(|path| !scan.read.contains(*path))
+            .filter(|path| {
+                let path = Path::new(path);
+                !(path.is_file() && is_nested_elsewhere(workspaces, workspace, path))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        scan.removed.extend(gone);
+        scan
+    }
+
+    /// Reads paths reported by a [`WorkspaceWatcher`]. Directories are walked,
+    /// and everything indexed at or below a path that no longer exists is
+    /// forgotten, so moving or deleting a folder is picked up too. Paths
+    /// outside every workspace are ignored, and a path under nested roots
+    /// belongs to the innermost workspace. `known` maps all indexed files to
+    /// their mtimes.
+    pub fn of_paths(workspaces: &[Workspace], known: &HashMap<String, i64>, paths: &[PathBuf]) -> Self {
+        let mut scan = Self::default();
+        for path in paths {
+            let Some(workspace) = owner(workspaces, path) else {
+                continue;
+            };
+            if path.is_dir() {
+                if !is_skipped_path(workspace, path) {
+                    for file in walk_notebooks(path) {
+                        if let Some(workspace) = owner(workspaces, &file) {
+                            scan.read_file(workspace, &file, known);
+                        }
+                    }
+                }
+            } else if path.is_file() {
+                if is_notebook_file(path) {
+                    scan.read_file(workspace, path, known);
+                }
+            } else {
+                let gone = known
+                    .keys()
+                    .filter(|indexed| Path::new(indexed).starts_with(path))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                scan.removed.extend(gone);
+            }
+        }
+        scan
+    }
+
+    fn read_file(&mut self, workspace: &Workspace, path: &Path, known: &HashMap<String, i64>) {
+        let path_str = path.to_string_lossy().to_string();
+        if !self.read.insert(path_str.clone()) {
+            return;
+        }
+        let known_mtime = known.get(&path_str).copied();
+        let (mtime_ms, error) = match fs::metadata(path).and_then(|meta| meta.modified()) {
+            Ok(modified) => (
+                modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64),
+                None,
+            ),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                // Deleted since it was listed.
+                if known_mtime.is_some() {
+                    self.removed.push(path_str);
+                }
+                return;
+            }
+            Err(err) => (0, Some(err.to_string())),
+        };
+        if known_mtime == Some(mtime_ms) {
+            self.unchanged += 1;
+            return;
+        }
+
+        let relative_path = relative_path(workspace, path);
+        let format = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        let mut file = WorkspaceFile {
+            workspace_id: workspace.id,
+            path: path_str,
+            relative_path,
+            format: format.to_string(),
+            title: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            languages: vec![],
+            cell_count: 0,
+            code_cell_count: 0,
+            mtime_ms,
+            error,
+        };
+        let loaded = match &file.error {
+            Some(_) => None,
+            None if format == "ipynb" => Some(ipynb::import_ipynb(path)),
+            None if format == "npadz" => Some(Notebook::load_npadz(path)),
+            None => Some(Notebook::load_npad(path)),
+        };
+        let notebook = match loaded {
+            Some(Ok(notebook)) => {
+                file.title = notebook.metadata.title.clone();
+                file.languages = notebook
+                    .cells
+                    .iter()
+                    .filter_map(|cell| cell.language.clone())
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect();
+                file.cell_count = notebook.cells.len();
+                file.code_cell_count = notebook
+                    .cells
+                    .iter()
+                    .filter(|cell| cell.cell_type == CellType::Code)
+                    .count();
+                Some(notebook)
+            }
+            Some(Err(err)) => {
+                file.error = Some(err.to_string());
+                None
+            }
+            None => None,
+        };
+        self.changed.push(ChangedFile {
+            file,
+            notebook,
+            known: known_mtime.is_some(),
+        });
+    }
+}
+
+/// Notebook files under `dir`, leaving out hidden and [`SKIPPED_DIRS`].
+fn walk_notebooks(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !entry.file_type().is_dir()
+                || !is_skipped_dir(&entry.file_name().to_string_lossy())
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file() && is_notebook_file(entry.path()))
+        .map(|entry| entry.into_path())
+}
+
+/// The workspace `path` belongs to: the deepest registered root containing
+/// it, so nested workspaces never index the same file.
+fn owner<'a>(workspaces: &'a [Workspace], path: &Path) -> Option<&'a Workspace> {
+    workspaces
+        .iter()
+        .filter(|ws| contains_path(ws, path))
+        .max_by_key(|ws| Path::new(&ws.root).components().count())
+}
+
+/// Whether `path`, inside `workspace`, belongs to a workspace nested in it.
+fn is_nested_elsewhere(workspaces: &[Workspace], workspace: &Workspace, path: &Path) -> bool {
+    owner(workspaces, path).is_some_and(|ws| {
+        ws.id != workspace.id
+            && Path::new(&ws.root).components().count() > Path::new(&workspace.root).components().count()
+    })
+}
+
+fn relative_path(workspace: &Workspace, path: &Path) -> String {
+    path.strip_prefix(&workspace.root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn contains_path(workspace: &Workspace, path: &Path) -> bool {
+    path.starts_with(&workspace.root)
+        && path
+            .strip_prefix(&workspace.root)
+            .map(|rel| {
+                rel.parent()
+                    .into_iter()
+                    .flat_map(Path::components)
+                    .all(|c| !is_skipped_dir(&c.as_os_str().to_string_lossy()))
+            })
+            .unwrap_or(false)
+}
+
+/// Whether the directory `path` is, or is inside, one a scan leaves out.
+fn is_skipped_path(workspace: &Workspace, path: &Path) -> bool {
+    path.strip_prefix(&workspace.root)
+        .map(|rel| rel.components().any(|c| is_skipped_dir(&c.as_os_str().to_string_lossy())))
+        .unwrap_or(true)
+}
+
+const WORKSPACE_FILE_COLUMNS: &str = r#"
+    SELECT workspace_id, path, relative_path, format, title, languages,
+           cell_count, code_cell_count, mtime_ms, error
+    FROM workspace_files
+"#;
+
+fn workspace_from_row(row: &rusqlite::Row) -> rusqlite::Result<Workspace> {
+    Ok(Workspace {
+        id: row.get(0)?,
+        root: row.get(1)?,
+        name: row.get(2)?,
+        added_at: row.get(3)?,
+    })
+}
+
+fn workspace_file_from_row(row: &rusqlite::Row) -> rusqlite::Result<WorkspaceFile> {
+    let languages: String = row.get(5)?;
+    Ok(WorkspaceFile {
+        workspace_id: row.get(0)?,
+        path: row.get(1)?,
+        relative_path: row.get(2)?,
+        format: row.get(3)?,
+        title: row.get(4)?,
+        languages: serde_json::from_str(&languages).unwrap_or_default(),
+        cell_count: row.get(6)?,
+        code_cell_count: row.get(7)?,
+        mtime_ms: row.get(8)?,
+        error: row.get(9)?,
+    })
+}
+
+/// Watches workspace roots and reports changed paths in debounced batches,
+/// typically fed to [`WorkspaceScan::of_paths`]: notebooks, directories, and
+/// paths that no longer exist, which may have been either. Stops when dropped.
+pub struct WorkspaceWatcher {
+    watcher: RecommendedWatcher,
+}
+
+/// Other files, and hidden names such as the temp files of atomic saves, are
+/// left out.
+fn is_reported(path: &Path) -> bool {
+    if is_notebook_file(path) {
+        return true;
+    }
+    let hidden = path
+        .file_name()
+        .is_some_and(|name| is_skipped_dir(&name.to_string_lossy()));
+    !hidden && !path.is_file()
+}
+
+impl WorkspaceWatcher {
+    pub fn start<F>(roots: &[PathBuf], on_change: F) -> CoreResult<Self>
+    where
+        F: Fn(Vec<PathBuf>) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<Vec<PathBuf>>();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                let _ = tx.send(event.paths);
+            }
+        })?;
+        for root in roots {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+        }
+        thread::spawn(move || {
+            // Ends once the watcher, and with it the sender, is dropped.
+            while let Ok(first) = rx.recv() {
+                let mut batch = first.into_iter().collect::<BTreeSet<_>>();
+                let flush_at = Instant::now() + WATCH_MAX_DELAY;
+                while let Some(left) = flush_at.checked_duration_since(Instant::now()) {
+                    match rx.recv_timeout(left.min(WATCH_DEBOUNCE)) {
+                        Ok(more) => batch.extend(more),
+                        Err(_) => break,
+                    }
+                }
+                let paths = batch.into_iter().filter(|path| is_reported(path)).collect::<Vec<_>>();
+                if !paths.is_empty() {
+                    on_change(paths);
+                }
+            }
+        });
+        Ok(Self { watcher })
+    }
+
+    pub fn watch<P: AsRef<Path>>(&mut self, root: P) -> CoreResult<()> {
+        Ok(self.watcher.watch(root.as_ref(), RecursiveMode::Recursive)?)
+    }
+
+    pub fn unwatch<P: AsRef<Path>>(&mut self, root: P) -> CoreResult<()> {
+        Ok(self.watcher.unwatch(root.as_ref())?)
+    }
+}
@@ -0,0 +1,51 @@
+use neuropad_core::archive::{BLOB_DIR, NOTEBOOK_ENTRY};
+use neuropad_core::digest::sha256_hex;
+use neuropad_core::{CellOutput, CellOutputKind, Notebook};
+use std::fs::File;
+use std::io::Read;
+use tempfile::tempdir;
+
+fn output(mime: &str, data: String) -> CellOutput {
+    CellOutput {
+        kind: CellOutputKind::Result,
+        mime: mime.to_string(),
+        data,
+        created_at: chrono::Utc::now(),
+    }
+}
+
+#[test]
+fn large_outputs_move_to_deduplicated_blobs() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("big.npadz");
+
+    let image = "iVBORw0KGgo".repeat(1000);
+    let mut nb = Notebook::new("Big");
+    nb.add_markdown_cell(
+        "![plot](data:image/png;base64,AAAA) and text about attachment:sha256:abc123 links, ![again](data:x)",
+    );
+    for _ in 0..2 {
+        nb.add_code_cell("python", "plot()");
+        let cell = nb.cells.last_mut().unwrap();
+        cell.outputs.push(output("image/png", image.clone()));
+        cell.outputs.push(output("text/plain", "small".to_string()));
+    }
+    nb.save_npadz(&path).expect("save");
+
+    let mut zip = zip::ZipArchive::new(File::open(&path).expect("open")).expect("zip");
+    let blobs = zip.file_names().filter(|n| n.starts_with(BLOB_DIR)).count();
+    assert_eq!(blobs, 1, "identical outputs share one blob");
+    let mut json = String::new();
+    zip.by_name(NOTEBOOK_ENTRY)
+        .expect("notebook entry")
+        .read_to_string(&mut json)
+        .expect("read");
+    assert!(!json.contains(&image));
+    assert!(!json.contains("data:image/png"));
+    assert!(json.contains(&sha256_hex(image.as_bytes())));
+
+    let loaded = Notebook::load_npadz(&path).expect("load");
+    assert_eq!(loaded.cells[0].source, nb.cells[0].source);
+    assert_eq!(loaded.cells[1].outputs[0].data, image);
+    assert_eq!(loaded.cells[2].outputs[1].data, "small");
+}
@@ -0,0 +1,78 @@
+use neuropad_core::schema::validate_npad_str;
+use neuropad_core::{CanonicalOptions, CellOutput, CellOutputKind, Notebook, SaveOptions};
+use serde_json::Value;
+use std::fs;
+use tempfile::tempdir;
+
+fn executed_notebook() -> Notebook {
+    let mut nb = Notebook::new("Canonical");
+    nb.add_markdown_cell("# Title\n\nSome text\n");
+    nb.add_code_cell("python", "import csv\nrows = list(csv.reader(open('billing.csv')))");
+    nb.cells[1].outputs.push(CellOutput {
+        kind: CellOutputKind::Stdout,
+        mime: "text/plain".to_string(),
+        data: "ok\n".to_string(),
+        created_at: chrono::Utc::now(),
+    });
+    nb.cells[1].execution.count = 3;
+    nb.cells[1].execution.duration_ms = 42;
+    nb
+}
+
+fn canonical(options: CanonicalOptions) -> SaveOptions {
+    SaveOptions {
+        canonical: Some(options),
+        ..SaveOptions::default()
+    }
+}
+
+#[test]
+fn no_op_open_and_save_is_byte_identical() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("stable.npad");
+    let options = canonical(CanonicalOptions::default());
+
+    executed_notebook().save_npad_with(&path, &options).expect("first save");
+    let first = fs::read_to_string(&path).expect("read");
+
+    let mut reloaded = Notebook::load_npad(&path).expect("load");
+    reloaded.save_npad_with(&path, &options).expect("second save");
+    assert_eq!(fs::read_to_string(&path).expect("read"), first);
+
+    let value: Value = serde_json::from_str(&first).expect("json");
+    assert_eq!(
+        value["cells"][1]["source"],
+        serde_json::json!(["import csv\n", "rows = list(csv.reader(open('billing.csv')))"])
+    );
+    assert_eq!(reloaded.cells[0].source, "# Title\n\nSome text\n");
+    assert!(validate_npad_str(&first).expect("schema").is_empty());
+}
+
+#[test]
+fn strips_volatile_fields_on_request() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("stripped.npad");
+
+    let mut nb = executed_notebook();
+    nb.save_npad_with(
+        &path,
+        &canonical(CanonicalOptions {
+            strip_timestamps: true,
+            strip_outputs: false,
+        }),
+    )
+    .expect("save");
+    let data = fs::read_to_string(&path).expect("read");
+    assert!(!data.contains("duration_ms"));
+    assert!(validate_npad_str(&data).expect("schema").is_empty());
+    let reloaded = Notebook::load_npad(&path).expect("load");
+    assert_eq!(reloaded.cells[1].outputs[0].data, "ok\n");
+    assert_eq!(reloaded.cells[1].execution.count, 3);
+
+    nb.save_npad_with(&path, &canonical(CanonicalOptions::strip_all()))
+        .expect("save");
+    let reloaded = Notebook::load_npad(&path).expect("load");
+    assert!(reloaded.cells[1].outputs.is_empty());
+    assert_eq!(reloaded.cells[1].execution.count, 0);
+    assert_eq!(reloaded.metadata.updated_at, nb.metadata.updated_at);
+}
@@ -1,5 +1,5 @@
 use neuropad_core::ipynb;
-use neuropad_core::Notebook;
+use neuropad_core::{CellTag, Notebook};
 use tempfile::tempdir;
 
 #[test]
@@ -34,3 +34,30 @@ fn ipynb_export_then_import() {
         Some("ruby")
     );
 }
+
+#[test]
+fn ipynb_carries_tags_and_metadata() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("tags.ipynb");
+
+    let mut nb = Notebook::new("Tags");
+    let id = nb.add_code_cell("python", "alpha = 1");
+    nb.tag_cell(id, CellTag::Parameters).expect("tag");
+    nb.tag_cell(id, CellTag::from("team-review")).expect("tag");
+    nb.set_cell_metadata(id, "collapsed", Some(true.into())).expect("metadata");
+    ipynb::export_ipynb(&nb, &path).expect("export");
+
+    let raw: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).expect("read")).expect("json");
+    assert_eq!(
+        raw["cells"][0]["metadata"]["tags"],
+        serde_json::json!(["parameters", "team-review"])
+    );
+
+    let imported = ipynb::import_ipynb(&path).expect("import");
+    let cell = &imported.cells[0];
+    assert!(cell.has_tag(&CellTag::Parameters));
+    assert!(cell.has_tag(&CellTag::Custom("team-review".to_string())));
+    assert_eq!(cell.metadata.get("collapsed"), Some(&serde_json::Value::Bool(true)));
+    assert!(!cell.metadata.contains_key("language"));
+}
@@ -0,0 +1,77 @@
+use neuropad_core::{IndexQuery, IndexSort, MetadataStore, Notebook};
+use tempfile::tempdir;
+
+fn paths<T>(items: &[T], path: impl Fn(&T) -> &str) -> Vec<String> {
+    items.iter().map(|item| path(item).to_string()).collect()
+}
+
+#[test]
+fn lists_recent_notebooks_newest_first() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+
+    store.mark_recent_open("a.npad", "2024-01-01T00:00:00Z").expect("open");
+    store.mark_recent_open("b.npad", "2024-01-02T00:00:00Z").expect("open");
+    store.mark_recent_open("c.npad", "2024-01-03T00:00:00Z").expect("open");
+    store.mark_recent_open("a.npad", "2024-01-04T00:00:00Z").expect("reopen");
+    store
+        .upsert_notebook_index("b.npad", "Bee", "2024-01-02T00:00:00Z")
+        .expect("index");
+
+    let recent = store.list_recent(2).expect("recent");
+    assert_eq!(paths(&recent, |r| &r.path), vec!["a.npad", "c.npad"]);
+    let all = store.list_recent(10).expect("recent");
+    assert_eq!(all[2].title.as_deref(), Some("Bee"));
+    assert_eq!(all[0].title, None);
+}
+
+#[test]
+fn filters_sorts_and_prunes_the_index() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+
+    let kept = dir.path().join("kept.npad");
+    Notebook::new("Kept").save_npad(&kept).expect("save");
+    let kept = kept.to_string_lossy().to_string();
+    for (path, title, updated_at) in [
+        (kept.as_str(), "Billing 2024", "2024-03-01T00:00:00Z"),
+        ("gone.npad", "billing_old", "2023-06-01T00:00:00Z"),
+        ("other.npad", "Churn", "2024-02-01T00:00:00Z"),
+    ] {
+        store.upsert_notebook_index(path, title, updated_at).expect("index");
+    }
+
+    let by_date = store.list_notebook_index(&IndexQuery::default()).expect("list");
+    assert_eq!(paths(&by_date, |n| &n.path), vec!["gone.npad", "other.npad", kept.as_str()]);
+
+    let query = IndexQuery {
+        title_contains: Some("BILLING".to_string()),
+        sort: IndexSort::Title,
+        descending: true,
+        ..IndexQuery::default()
+    };
+    let billing = store.list_notebook_index(&query).expect("filter");
+    assert_eq!(paths(&billing, |n| &n.title), vec!["billing_old", "Billing 2024"]);
+
+    let query = IndexQuery {
+        title_contains: Some("_".to_string()),
+        ..IndexQuery::default()
+    };
+    assert_eq!(store.list_notebook_index(&query).expect("literal underscore").len(), 1);
+
+    let query = IndexQuery {
+        updated_after: Some("2024-01-01T00:00:00Z".to_string()),
+        limit: Some(1),
+        offset: 1,
+        ..IndexQuery::default()
+    };
+    let page = store.list_notebook_index(&query).expect("page");
+    assert_eq!(paths(&page, |n| &n.path), vec![kept.as_str()]);
+
+    store.mark_recent_open("gone.npad", "2024-01-01T00:00:00Z").expect("open");
+    let pruned = store.prune_missing().expect("prune");
+    assert_eq!(pruned, vec!["gone.npad", "other.npad"]);
+    assert!(store.list_recent(10).expect("recent").is_empty());
+    let remaining = store.list_notebook_index(&IndexQuery::default()).expect("list");
+    assert_eq!(paths(&remaining, |n| &n.path), vec![kept.as_str()]);
+}
@@ -0,0 +1,81 @@
+use chrono::{Duration, Utc};
+use neuropad_core::Notebook;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::tempdir;
+
+fn merge(base: &Path, ours: &Path, theirs: &Path) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_neuropad-git"))
+        .arg("merge")
+        .args([base, ours, theirs])
+        .status()
+        .expect("run neuropad-git")
+        .code()
+}
+
+#[test]
+fn merge_keeps_the_merged_timestamp() {
+    let dir = tempdir().expect("tempdir");
+    let mut base = Notebook::new("Driver");
+    let cell = base.add_code_cell("go", "fmt.Println(1)");
+    base.save_npad(dir.path().join("base.npad")).expect("base");
+
+    let mut ours = base.clone();
+    ours.add_markdown_cell("ours");
+    ours.metadata.updated_at = Utc::now() - Duration::days(2);
+    let mut theirs = base.clone();
+    theirs.set_cell_source(cell, "fmt.Println(2)").expect("edit");
+    theirs.metadata.updated_at = Utc::now() - Duration::days(1);
+    let paths = ["base", "ours", "theirs"].map(|name| dir.path().join(format!("{name}.npad")));
+    fs::write(&paths[1], serde_json::to_string(&ours).expect("ours")).expect("write ours");
+    fs::write(&paths[2], serde_json::to_string(&theirs).expect("theirs")).expect("write theirs");
+
+    assert_eq!(merge(&paths[0], &paths[1], &paths[2]), Some(0));
+    let merged = Notebook::load_npad(&paths[1]).expect("merged");
+    assert_eq!(merged.metadata.updated_at, theirs.metadata.updated_at);
+    assert_eq!(merged.cells.len(), 2);
+    assert_eq!(merged.cells[0].source, "fmt.Println(2)");
+}
+
+#[test]
+fn merge_treats_an_empty_base_as_an_empty_notebook() {
+    let dir = tempdir().expect("tempdir");
+    let base = dir.path().join("base.npad");
+    fs::write(&base, "").expect("empty base");
+    let mut ours = Notebook::new("Added");
+    ours.add_markdown_cell("ours");
+    ours.save_npad(dir.path().join("ours.npad")).expect("ours");
+    let mut theirs = Notebook::new("Added");
+    theirs.add_markdown_cell("theirs");
+    theirs.save_npad(dir.path().join("theirs.npad")).expect("theirs");
+
+    assert_eq!(
+        merge(&base, &dir.path().join("ours.npad"), &dir.path().join("theirs.npad")),
+        Some(0)
+    );
+    let merged = Notebook::load_npad(dir.path().join("ours.npad")).expect("merged");
+    let sources = merged.cells.iter().map(|c| c.source.as_str()).collect::<Vec<_>>();
+    assert_eq!(sources, vec!["ours", "theirs"]);
+}
+
+#[test]
+fn merge_keeps_crlf_line_endings() {
+    let dir = tempdir().expect("tempdir");
+    let mut base = Notebook::new("Windows");
+    let untouched = base.add_code_cell("python", "import os\r\nprint(os.name)\r\n");
+    let edited = base.add_code_cell("python", "a = 1\r\nb = 2\r\nc = 3\r\n");
+    let mut ours = base.clone();
+    ours.set_cell_source(edited, "a = 10\r\nb = 2\r\nc = 3\r\n").expect("ours");
+    let mut theirs = base.clone();
+    theirs.set_cell_source(edited, "a = 1\r\nb = 2\r\nc = 30\r\n").expect("theirs");
+    let paths = ["base", "ours", "theirs"].map(|name| dir.path().join(format!("{name}.npad")));
+    for (notebook, path) in [&mut base, &mut ours, &mut theirs].into_iter().zip(&paths) {
+        notebook.save_npad(path).expect("save");
+    }
+
+    assert_eq!(merge(&paths[0], &paths[1], &paths[2]), Some(0));
+    let merged = Notebook::load_npad(&paths[1]).expect("merged");
+    assert_eq!(merged.cell(untouched).expect("untouched").source, "import os\r\nprint(os.name)\r\n");
+    assert_eq!(merged.cell(edited).expect("edited").source, "a = 10\r\nb = 2\r\nc = 30\r\n");
+}
@@ -0,0 +1,169 @@
+use neuropad_core::migrations::{backup_path, MIGRATIONS, SCHEMA_VERSION};
+use neuropad_core::{CoreError, IndexQuery, MetadataStore, Notebook};
+use rusqlite::Connection;
+use std::path::Path;
+use tempfile::tempdir;
+
+/// Layouts that shipped before `user_version` was tracked, oldest first. All
+/// of them report version 0.
+const UNVERSIONED_LAYOUTS: &[&str] = &[
+    r#"
+    CREATE TABLE notebook_index (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        path TEXT NOT NULL UNIQUE,
+        title TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+    CREATE TABLE recent_open (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        path TEXT NOT NULL UNIQUE,
+        opened_at TEXT NOT NULL
+    );
+    "#,
+    r#"
+    CREATE TABLE recovery_session (
+        session_id TEXT PRIMARY KEY,
+        notebook_path TEXT,
+        title TEXT NOT NULL,
+        snapshot_path TEXT NOT NULL,
+        saved_at TEXT NOT NULL
+    );
+    "#,
+    r#"
+    CREATE VIRTUAL TABLE cell_fts USING fts5(
+        path UNINDEXED, cell_id UNINDEXED, cell_type UNINDEXED, language UNINDEXED,
+        source, outputs, tokenize = 'unicode61'
+    );
+    "#,
+];
+
+fn seed(path: &Path, layouts: usize) {
+    let conn = Connection::open(path).expect("open raw");
+    for layout in &UNVERSIONED_LAYOUTS[..layouts] {
+        conn.execute_batch(layout).expect("legacy schema");
+    }
+    conn.execute(
+        "INSERT INTO notebook_index(path, title, updated_at) VALUES('a.npad', 'Old', '2024-01-01T00:00:00Z')",
+        [],
+    )
+    .expect("seed");
+}
+
+#[test]
+fn upgrades_every_unversioned_layout_and_keeps_data() {
+    for layouts in 1..=UNVERSIONED_LAYOUTS.len() {
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("meta.sqlite");
+        seed(&path, layouts);
+
+        let store = MetadataStore::open(&path).expect("migrate");
+        assert_eq!(store.schema_version().expect("version"), SCHEMA_VERSION);
+        let index = store.list_notebook_index(&IndexQuery::default()).expect("list");
+        assert_eq!(index[0].title, "Old", "layout {layouts}");
+        assert!(store.list_recovery_sessions().expect("recovery").is_empty());
+        assert!(store.search_cells("anything", 5).expect("fts").is_empty());
+
+        let backup = Connection::open(backup_path(&path, 0)).expect("backup");
+        let title: String = backup
+            .query_row("SELECT title FROM notebook_index", [], |row| row.get(0))
+            .expect("backup data");
+        assert_eq!(title, "Old");
+    }
+}
+
+#[test]
+fn upgrades_from_every_versioned_step() {
+    for version in 1..SCHEMA_VERSION {
+        let dir = tempdir().expect("tempdir");
+        let path = dir.path().join("meta.sqlite");
+        {
+            let conn = Connection::open(&path).expect("open raw");
+            for migration in &MIGRATIONS[..version as usize] {
+                conn.execute_batch(migration.sql).expect("step");
+            }
+            conn.pragma_update(None, "user_version", version).expect("version");
+        }
+
+        let store = MetadataStore::open(&path).expect("migrate");
+        assert_eq!(store.schema_version().expect("version"), SCHEMA_VERSION);
+        assert!(backup_path(&path, version).exists());
+    }
+}
+
+#[test]
+fn cells_indexed_before_the_row_map_are_still_replaced() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("meta.sqlite");
+    {
+        let conn = Connection::open(&path).expect("open raw");
+        for migration in &MIGRATIONS[..SCHEMA_VERSION as usize - 1] {
+            conn.execute_batch(migration.sql).expect("step");
+        }
+        conn.execute(
+            "INSERT INTO cell_fts(path, cell_id, cell_type, language, source, outputs) \
+             VALUES('a.npad', 'c1', 'code', 'python', 'stale_name = 1', '')",
+            [],
+        )
+        .expect("old row");
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION - 1).expect("version");
+    }
+
+    let store = MetadataStore::open(&path).expect("migrate");
+    assert_eq!(store.search_cells("stale_name", 5).expect("search").len(), 1);
+    let mut nb = Notebook::new("A");
+    nb.add_code_cell("python", "fresh_name = 2");
+    store.index_notebook_cells("a.npad", &nb).expect("reindex");
+    assert!(store.search_cells("stale_name", 5).expect("search").is_empty());
+    assert_eq!(store.search_cells("fresh_name", 5).expect("search").len(), 1);
+    store.remove_notebook_cells("a.npad").expect("remove");
+    assert!(store.search_cells("fresh_name", 5).expect("search").is_empty());
+}
+
+#[test]
+fn fresh_and_current_databases_are_not_backed_up() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("meta.sqlite");
+    drop(MetadataStore::open(&path).expect("create"));
+    drop(MetadataStore::open(&path).expect("reopen"));
+    let entries = std::fs::read_dir(dir.path()).expect("read dir").count();
+    assert_eq!(entries, 1);
+}
+
+#[test]
+fn refuses_databases_from_newer_versions() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("meta.sqlite");
+    Connection::open(&path)
+        .expect("open raw")
+        .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+        .expect("version");
+    assert!(matches!(MetadataStore::open(&path), Err(CoreError::Validation(_))));
+}
+
+#[test]
+fn concurrent_processes_share_one_database() {
+    let dir = tempdir().expect("tempdir");
+    let db = dir.path().join("shared.sqlite");
+    let handles = (0..4)
+        .map(|worker| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                let store = MetadataStore::open(&db).expect("open");
+                assert_eq!(store.journal_mode().expect("mode"), "wal");
+                for i in 0..25 {
+                    store
+                        .upsert_notebook_index(&format!("w{worker}/n{i}.npad"), "Shared", "2026-01-01T00:00:00Z")
+                        .expect("write");
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().expect("worker");
+    }
+
+    let store = MetadataStore::open(&db).expect("open");
+    assert_eq!(store.schema_version().expect("version"), SCHEMA_VERSION);
+    let all = store.list_notebook_index(&IndexQuery::default()).expect("list");
+    assert_eq!(all.len(), 100);
+}
@@ -0,0 +1,39 @@
+use neuropad_core::{MetadataStore, Notebook, RecoveryJournal, RecoverySession};
+use tempfile::tempdir;
+use uuid::Uuid;
+
+#[test]
+fn snapshots_survive_until_discarded() {
+    let dir = tempdir().expect("tempdir");
+    let journal = RecoveryJournal::open(dir.path().join("recovery")).expect("journal");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+
+    let session_id = Uuid::new_v4();
+    let mut nb = Notebook::new("Unsaved");
+    nb.add_code_cell("python", "print('draft')");
+    let snapshot = journal.write_snapshot(session_id, &nb).expect("snapshot");
+    store
+        .upsert_recovery_session(&RecoverySession {
+            session_id: session_id.to_string(),
+            notebook_path: Some("draft.npad".to_string()),
+            title: nb.metadata.title.clone(),
+            snapshot_path: snapshot.to_string_lossy().to_string(),
+            saved_at: chrono::Utc::now().to_rfc3339(),
+        })
+        .expect("register");
+
+    let sessions = store.list_recovery_sessions().expect("list");
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].notebook_path.as_deref(), Some("draft.npad"));
+
+    let restored = journal.read_snapshot(session_id).expect("restore");
+    assert_eq!(restored.cells[0].source, "print('draft')");
+
+    journal.remove_snapshot(session_id).expect("discard snapshot");
+    store
+        .remove_recovery_session(&session_id.to_string())
+        .expect("discard session");
+    assert!(store.list_recovery_sessions().expect("list").is_empty());
+    assert!(journal.read_snapshot(session_id).is_err());
+    journal.remove_snapshot(session_id).expect("discard is idempotent");
+}
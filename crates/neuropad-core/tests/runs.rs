@@ -0,0 +1,118 @@
+use chrono::{Duration, TimeZone, Utc};
+use neuropad_core::metadata::MAX_RUNS_PER_CELL;
+use neuropad_core::{CellOutput, CellOutputKind, CellRunRecord, CellStatus, MetadataStore, Notebook};
+use tempfile::tempdir;
+
+fn output(data: &str) -> CellOutput {
+    CellOutput {
+        kind: CellOutputKind::Stdout,
+        mime: "text/plain".to_string(),
+        data: data.to_string(),
+        created_at: Utc::now(),
+    }
+}
+
+#[test]
+fn records_runs_and_restores_an_earlier_output() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let mut nb = Notebook::new("Runs");
+    let id = nb.add_code_cell("python", "print(total)");
+    let cell_id = id.to_string();
+
+    let yesterday = Utc.with_ymd_and_hms(2024, 5, 1, 9, 0, 0).unwrap();
+    let outputs = [output("1200\n")];
+    let first = store
+        .record_cell_run(&CellRunRecord {
+            notebook_path: "billing.npad",
+            cell_id: &cell_id,
+            language: "python",
+            source: "print(total)",
+            started_at: yesterday,
+            finished_at: yesterday + Duration::milliseconds(250),
+            status: CellStatus::Ok,
+            outputs: &outputs,
+        })
+        .expect("record");
+    let today = yesterday + Duration::days(1);
+    store
+        .record_cell_run(&CellRunRecord {
+            notebook_path: "billing.npad",
+            cell_id: &cell_id,
+            language: "python",
+            source: "print(totl)",
+            started_at: today,
+            finished_at: today,
+            status: CellStatus::Error,
+            outputs: &[output("NameError: totl")],
+        })
+        .expect("record");
+
+    let runs = store.list_cell_runs("billing.npad", &cell_id, 10).expect("history");
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0].status, CellStatus::Error);
+    assert_eq!(runs[1].id, first);
+    assert_eq!(runs[1].duration_ms, 250);
+    assert_eq!(runs[1].output_summary, "1200\n");
+    assert_ne!(runs[0].source_hash, runs[1].source_hash);
+    assert!(runs[1].restorable);
+
+    store.restore_cell_run(&mut nb, first).expect("restore");
+    assert_eq!(nb.cells[0].outputs[0].data, "1200\n");
+    assert_eq!(nb.cells[0].execution.status, CellStatus::Ok);
+    nb.undo().expect("restore is undoable");
+    assert!(nb.cells[0].outputs.is_empty());
+    assert!(store.restore_cell_run(&mut nb, first + 100).is_err());
+}
+
+#[test]
+fn keeps_a_bounded_history_per_cell() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let start = Utc::now();
+    for i in 0..MAX_RUNS_PER_CELL + 5 {
+        let at = start + Duration::seconds(i as i64);
+        store
+            .record_cell_run(&CellRunRecord {
+                notebook_path: "a.npad",
+                cell_id: "cell",
+                language: "go",
+                source: "x",
+                started_at: at,
+                finished_at: at,
+                status: CellStatus::Ok,
+                outputs: &[],
+            })
+            .expect("record");
+    }
+    let runs = store.list_cell_runs("a.npad", "cell", 1000).expect("history");
+    assert_eq!(runs.len(), MAX_RUNS_PER_CELL);
+    store.remove_notebook("a.npad").expect("forget");
+    assert!(store.list_cell_runs("a.npad", "cell", 10).expect("history").is_empty());
+}
+
+#[test]
+fn an_unknown_status_is_an_error() {
+    let dir = tempdir().expect("tempdir");
+    let db = dir.path().join("meta.sqlite");
+    let store = MetadataStore::open(&db).expect("store");
+    let at = Utc::now();
+    let run_id = store
+        .record_cell_run(&CellRunRecord {
+            notebook_path: "a.npad",
+            cell_id: "cell",
+            language: "go",
+            source: "x",
+            started_at: at,
+            finished_at: at,
+            status: CellStatus::Ok,
+            outputs: &[],
+        })
+        .expect("record");
+    rusqlite::Connection::open(&db)
+        .expect("raw")
+        .execute("UPDATE cell_runs SET status = 'exploded' WHERE id = ?1", [run_id])
+        .expect("corrupt");
+    assert!(store.cell_run(run_id).is_err());
+    assert!(store.list_cell_runs("a.npad", "cell", 10).is_err());
+}
@@ -0,0 +1,136 @@
+use neuropad_core::schema::{npad_schema, validate_npad_value};
+use neuropad_core::{
+    CellOutput, CellOutputKind, CellStatus, CellTag, CellType, CoreError, Notebook,
+};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use tempfile::tempdir;
+
+fn populated_notebook() -> Notebook {
+    let mut nb = Notebook::new("Sync");
+    nb.add_markdown_cell("# Heading");
+    nb.add_code_cell("ruby", "puts 1");
+    nb.cells[1].outputs.push(CellOutput {
+        kind: CellOutputKind::Stdout,
+        mime: "text/plain".to_string(),
+        data: "1\n".to_string(),
+        created_at: chrono::Utc::now(),
+    });
+    let id = nb.cells[1].id;
+    nb.tag_cell(id, CellTag::Slow).expect("tag");
+    nb.set_cell_metadata(id, "owner", Some(Value::from("data"))).expect("metadata");
+    nb
+}
+
+/// Every key serde emits must be declared by the schema, and every key the
+/// schema requires must be emitted by serde.
+fn assert_keys_in_sync(value: &Value, schema: &Value, pointer: &str) {
+    match value {
+        Value::Object(object) => {
+            // Objects declared without `properties` are free-form maps.
+            let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+                assert_eq!(schema["type"], "object", "{pointer}: schema does not allow an object");
+                return;
+            };
+            let emitted = object.keys().cloned().collect::<BTreeSet<_>>();
+            let declared = properties.keys().cloned().collect::<BTreeSet<_>>();
+            let undeclared = emitted.difference(&declared).collect::<Vec<_>>();
+            assert!(undeclared.is_empty(), "{pointer}: serde emits undeclared keys {undeclared:?}");
+
+            let required: BTreeSet<String> = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|r| r.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                .unwrap_or_default();
+            let missing = required.difference(&emitted).collect::<Vec<_>>();
+            assert!(missing.is_empty(), "{pointer}: serde omits required keys {missing:?}");
+
+            for (key, child) in object {
+                assert_keys_in_sync(child, &properties[key], &format!("{pointer}/{key}"));
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    assert_keys_in_sync(item, item_schema, &format!("{pointer}/{index}"));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn schema_enum(pointer: &str) -> BTreeSet<String> {
+    npad_schema()
+        .pointer(pointer)
+        .and_then(Value::as_array)
+        .unwrap_or_else(|| panic!("no enum at {pointer}"))
+        .iter()
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect()
+}
+
+fn serialized<T: serde::Serialize>(variants: &[T]) -> BTreeSet<String> {
+    variants
+        .iter()
+        .map(|v| serde_json::to_value(v).expect("serialize"))
+        .filter_map(|v| v.as_str().map(str::to_string))
+        .collect()
+}
+
+#[test]
+fn serialized_notebook_matches_schema() {
+    let value = serde_json::to_value(populated_notebook()).expect("serialize");
+    let violations = validate_npad_value(&value);
+    assert!(violations.is_empty(), "{violations:?}");
+    assert_keys_in_sync(&value, npad_schema(), "");
+}
+
+#[test]
+fn enum_variants_match_schema() {
+    let cell = "/properties/cells/items/properties";
+    assert_eq!(
+        serialized(&[CellType::Markdown, CellType::Code]),
+        schema_enum(&format!("{cell}/type/enum"))
+    );
+    assert_eq!(
+        serialized(&[
+            CellOutputKind::Stdout,
+            CellOutputKind::Stderr,
+            CellOutputKind::Result,
+            CellOutputKind::Error,
+        ]),
+        schema_enum(&format!("{cell}/outputs/items/properties/kind/enum"))
+    );
+    assert_eq!(
+        serialized(&[
+            CellStatus::Idle,
+            CellStatus::Running,
+            CellStatus::Ok,
+            CellStatus::Error,
+            CellStatus::Cancelled,
+        ]),
+        schema_enum(&format!("{cell}/execution/properties/status/enum"))
+    );
+}
+
+#[test]
+fn strict_load_reports_all_violations() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("broken.npad");
+
+    let mut value = serde_json::to_value(populated_notebook()).expect("serialize");
+    value["cells"][1]["language"] = Value::from("cobol");
+    value["cells"][1]["outputs"][0]["kind"] = Value::from("display");
+    std::fs::write(&path, serde_json::to_string_pretty(&value).unwrap()).expect("write");
+
+    let violations = Notebook::check_schema(&path).expect("check");
+    let pointers = violations.iter().map(|v| v.pointer.as_str()).collect::<Vec<_>>();
+    assert_eq!(pointers, vec!["/cells/1/language", "/cells/1/outputs/0/kind"]);
+
+    match Notebook::load_npad_strict(&path) {
+        Err(CoreError::Schema(found)) => assert_eq!(found, violations),
+        other => panic!("expected schema error, got {other:?}"),
+    }
+}
@@ -0,0 +1,62 @@
+use neuropad_core::{CellOutput, CellOutputKind, CellType, MetadataStore, Notebook};
+use tempfile::tempdir;
+
+fn billing_notebook() -> Notebook {
+    let mut nb = Notebook::new("Billing");
+    nb.add_markdown_cell("# Monthly billing report");
+    nb.add_code_cell("python", "import csv\nrows = list(csv.reader(open('billing.csv')))");
+    nb.cells[1].outputs.push(CellOutput {
+        kind: CellOutputKind::Stdout,
+        mime: "text/plain".to_string(),
+        data: "parsed 1200 invoices\n".to_string(),
+        created_at: chrono::Utc::now(),
+    });
+    nb
+}
+
+#[test]
+fn finds_sources_markdown_and_outputs_across_notebooks() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+
+    let billing = billing_notebook();
+    store
+        .upsert_notebook_index("billing.npad", "Billing", "2024-01-01T00:00:00Z")
+        .expect("index");
+    store.index_notebook_cells("billing.npad", &billing).expect("fts");
+    let mut other = Notebook::new("Other");
+    other.add_code_cell("go", "fmt.Println(\"no csv here\")");
+    store.index_notebook_cells("other.npad", &other).expect("fts");
+
+    let hits = store.search_cells("billing csv", 10).expect("search");
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].notebook_path, "billing.npad");
+    assert_eq!(hits[0].title.as_deref(), Some("Billing"));
+    assert_eq!(hits[0].cell_id, billing.cells[1].id.to_string());
+    assert_eq!(hits[0].language.as_deref(), Some("python"));
+    assert!(hits[0].snippet.iter().any(|part| part.matched && part.text == "billing"));
+
+    let hits = store.search_cells("invoic", 10).expect("prefix search");
+    assert_eq!(hits.len(), 1);
+    let hits = store.search_cells("monthly", 10).expect("markdown search");
+    assert_eq!(hits[0].cell_type, CellType::Markdown);
+    assert_eq!(store.search_cells("csv", 10).expect("search").len(), 2);
+    assert!(store.search_cells("\"unbalanced (", 10).expect("odd input").is_empty());
+}
+
+#[test]
+fn reindexing_replaces_stale_cells() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+
+    let mut nb = billing_notebook();
+    store.index_notebook_cells("billing.npad", &nb).expect("fts");
+    nb.cells[1].source = "import pandas".to_string();
+    nb.cells[1].outputs.clear();
+    store.index_notebook_cells("billing.npad", &nb).expect("reindex");
+
+    assert!(store.search_cells("invoices", 10).expect("search").is_empty());
+    assert_eq!(store.search_cells("pandas", 10).expect("search").len(), 1);
+    store.remove_notebook_cells("billing.npad").expect("remove");
+    assert!(store.search_cells("pandas", 10).expect("search").is_empty());
+}
@@ -0,0 +1,93 @@
+use chrono::{Duration, Utc};
+use neuropad_core::diff::CellChangeKind;
+use neuropad_core::{MetadataStore, Notebook};
+use rusqlite::Connection;
+use tempfile::tempdir;
+
+fn blob_count(path: &std::path::Path) -> i64 {
+    Connection::open(path)
+        .expect("raw")
+        .query_row("SELECT COUNT(*) FROM snapshot_blobs", [], |row| row.get(0))
+        .expect("count")
+}
+
+#[test]
+fn versions_share_unchanged_cells_and_skip_no_op_saves() {
+    let dir = tempdir().expect("tempdir");
+    let db = dir.path().join("meta.sqlite");
+    let store = MetadataStore::open(&db).expect("store");
+    let start = Utc::now();
+
+    let mut nb = Notebook::new("Versions");
+    nb.add_markdown_cell("# Intro");
+    let code = nb.add_code_cell("ruby", "puts 1");
+    let v1 = store.snapshot_notebook("v.npad", &nb, start).expect("v1");
+    assert_eq!(
+        store.snapshot_notebook("v.npad", &nb, start + Duration::seconds(1)).expect("no-op"),
+        v1
+    );
+
+    nb.set_cell_source(code, "puts 2").expect("edit");
+    let v2 = store
+        .snapshot_notebook("v.npad", &nb, start + Duration::seconds(2))
+        .expect("v2");
+    assert_ne!(v1, v2);
+    assert_eq!(blob_count(&db), 3, "the markdown cell is stored once");
+
+    nb.touch();
+    assert_eq!(
+        store.snapshot_notebook("v.npad", &nb, start + Duration::seconds(3)).expect("resave"),
+        v2,
+        "a bumped updated_at alone is no new version"
+    );
+    nb.metadata.kernel_policy = "shared".to_string();
+    let v3 = store
+        .snapshot_notebook("v.npad", &nb, start + Duration::seconds(4))
+        .expect("v3");
+    assert_ne!(v2, v3);
+    assert_eq!(store.load_version(v3).expect("load").metadata.kernel_policy, "shared");
+
+    let versions = store.list_versions("v.npad").expect("list");
+    assert_eq!(versions.iter().map(|v| v.id).collect::<Vec<_>>(), vec![v3, v2, v1]);
+    assert_eq!(versions[0].cell_count, 2);
+
+    let old = store.load_version(v1).expect("load");
+    assert_eq!(old.cells[1].source, "puts 1");
+    assert_eq!(old.metadata.title, "Versions");
+    let diff = store.diff_version(v1, &nb).expect("diff");
+    assert_eq!(diff.changed_cells().count(), 1);
+    assert_eq!(diff.cells[1].kind, CellChangeKind::Modified);
+}
+
+#[test]
+fn restores_whole_notebook_or_single_cells() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+
+    let mut nb = Notebook::new("Restore");
+    let a = nb.add_code_cell("python", "a = 1");
+    let b = nb.add_code_cell("python", "b = 2");
+    let v1 = store.snapshot_notebook("r.npad", &nb, Utc::now()).expect("v1");
+
+    nb.metadata.title = "Renamed".to_string();
+    nb.set_cell_source(a, "a = 100").expect("edit");
+    nb.delete_cell(b).expect("delete");
+    nb.add_markdown_cell("new");
+
+    let mut single = nb.clone();
+    store.restore_version_cell(v1, b, &mut single).expect("restore deleted cell");
+    assert_eq!(single.cells[1].source, "b = 2");
+    store.restore_version_cell(v1, a, &mut single).expect("restore edited cell");
+    assert_eq!(single.cells[0].source, "a = 1");
+    assert_eq!(single.cells.len(), 3);
+
+    store.restore_version(v1, &mut nb).expect("restore all");
+    let sources = nb.cells.iter().map(|c| c.source.as_str()).collect::<Vec<_>>();
+    assert_eq!(sources, vec!["a = 1", "b = 2"]);
+    assert_eq!(nb.metadata.title, "Restore");
+    nb.undo().expect("undo restore");
+    assert_eq!(nb.cells.len(), 2);
+    assert_eq!(nb.cells[1].source, "new");
+    assert_eq!(nb.metadata.title, "Renamed");
+    assert!(store.load_version(v1 + 10).is_err());
+}
@@ -0,0 +1,105 @@
+use neuropad_core::storage::backup_path;
+use neuropad_core::{CoreError, Notebook, SaveOptions};
+use std::fs;
+use tempfile::tempdir;
+
+#[test]
+fn save_leaves_no_temp_files_and_keeps_backup() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("demo.npad");
+
+    let mut nb = Notebook::new("First");
+    nb.save_npad(&path).expect("first save");
+    let first = fs::read_to_string(&path).expect("read");
+
+    nb.metadata.title = "Second".to_string();
+    let options = SaveOptions {
+        backup: true,
+        ..SaveOptions::default()
+    };
+    nb.save_npad_with(&path, &options).expect("second save");
+
+    assert_eq!(fs::read_to_string(backup_path(&path)).expect("backup"), first);
+    assert_eq!(Notebook::load_npad(&path).expect("load").metadata.title, "Second");
+    let entries = fs::read_dir(dir.path()).expect("read dir").count();
+    assert_eq!(entries, 2, "only the notebook and its backup should remain");
+}
+
+#[test]
+fn save_refuses_to_overwrite_concurrent_changes() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("shared.npad");
+    Notebook::new("Shared").save_npad(&path).expect("seed");
+
+    let (mut ours, stamp) = Notebook::load_npad_stamped(&path).expect("load ours");
+    let (mut theirs, _) = Notebook::load_npad_stamped(&path).expect("load theirs");
+    theirs.add_markdown_cell("their edit");
+    theirs.save_npad(&path).expect("their save");
+
+    ours.add_markdown_cell("our edit");
+    let loaded_at = ours.metadata.updated_at;
+    let options = SaveOptions {
+        expected: Some(stamp),
+        ..SaveOptions::default()
+    };
+    let err = ours.save_npad_with(&path, &options).expect_err("conflict");
+    assert!(matches!(err, CoreError::Conflict(_)), "{err:?}");
+    let err = ours.save_npadz_with(&path, &options).expect_err("conflict");
+    assert!(matches!(err, CoreError::Conflict(_)), "{err:?}");
+    assert_eq!(ours.metadata.updated_at, loaded_at, "a refused save is not a save");
+
+    let on_disk = Notebook::load_npad(&path).expect("reload");
+    assert_eq!(on_disk.cells[0].source, "their edit");
+}
+
+#[test]
+fn chained_saves_accept_their_own_stamp() {
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("chain.npad");
+
+    let mut nb = Notebook::new("Chain");
+    let mut options = SaveOptions::default();
+    for i in 0..3 {
+        nb.add_markdown_cell(format!("cell {i}"));
+        options.expected = Some(nb.save_npad_with(&path, &options).expect("save"));
+    }
+    assert_eq!(Notebook::load_npad(&path).expect("load").cells.len(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn save_keeps_the_file_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir().expect("tempdir");
+    let path = dir.path().join("private.npad");
+    let mut nb = Notebook::new("Private");
+    nb.save_npad(&path).expect("first save");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).expect("chmod");
+
+    nb.save_npad(&path).expect("second save");
+    let mode = fs::metadata(&path).expect("metadata").permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[cfg(unix)]
+#[test]
+fn save_through_a_symlink_replaces_its_target() {
+    let dir = tempdir().expect("tempdir");
+    let target = dir.path().join("shared/notes.npad");
+    fs::create_dir_all(target.parent().expect("parent")).expect("mkdir");
+    let link = dir.path().join("notes.npad");
+    let mut nb = Notebook::new("Linked");
+    nb.save_npad(&target).expect("first save");
+    std::os::unix::fs::symlink(&target, &link).expect("symlink");
+
+    nb.metadata.title = "Saved through the link".to_string();
+    let options = SaveOptions {
+        backup: true,
+        ..SaveOptions::default()
+    };
+    nb.save_npad_with(&link, &options).expect("save");
+    assert!(fs::symlink_metadata(&link).expect("link").file_type().is_symlink());
+    assert_eq!(Notebook::load_npad(&target).expect("load").metadata.title, "Saved through the link");
+    assert!(backup_path(&target).exists());
+}
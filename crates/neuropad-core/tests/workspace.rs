@@ -0,0 +1,254 @@
+use neuropad_core::ipynb::export_ipynb;
+use neuropad_core::{MetadataStore, Notebook, ScanReport, WorkspaceWatcher};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+use tempfile::tempdir;
+
+fn write_npad(path: &Path, title: &str, source: &str) {
+    let mut nb = Notebook::new(title);
+    nb.add_markdown_cell("notes");
+    nb.add_code_cell("go", source);
+    nb.save_npad(path).expect("save");
+}
+
+fn bump_mtime(path: &Path) {
+    let later = SystemTime::now() + Duration::from_secs(60);
+    File::options()
+        .write(true)
+        .open(path)
+        .expect("open")
+        .set_modified(later)
+        .expect("mtime");
+}
+
+#[test]
+fn scans_folders_incrementally() {
+    let dir = tempdir().expect("tempdir");
+    let root = dir.path().join("project");
+    fs::create_dir_all(root.join("analysis")).expect("mkdir");
+    fs::create_dir_all(root.join(".git")).expect("mkdir");
+    fs::create_dir_all(root.join("node_modules/pkg")).expect("mkdir");
+    write_npad(&root.join("analysis/billing.npad"), "Billing", "parseBillingCSV()");
+    write_npad(&root.join(".git/ignored.npad"), "Hidden", "x");
+    write_npad(&root.join("node_modules/pkg/dep.npad"), "Dep", "x");
+    let mut imported = Notebook::new("Imported");
+    imported.add_code_cell("python", "import pandas");
+    export_ipynb(&imported, root.join("imported.ipynb")).expect("ipynb");
+    fs::write(root.join("broken.npad"), "{ not json").expect("broken");
+    fs::write(root.join("readme.md"), "# not a notebook").expect("md");
+
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let ws = store.add_workspace(&root).expect("add");
+    assert_eq!(ws.name, "project");
+    assert_eq!(store.add_workspace(&root).expect("again").id, ws.id);
+
+    let report = store.scan_workspace(ws.id).expect("scan");
+    assert_eq!(
+        report,
+        ScanReport {
+            added: 2,
+            failed: 1,
+            ..ScanReport::default()
+        }
+    );
+    let files = store.workspace_files(ws.id).expect("files");
+    let relative = files.iter().map(|f| f.relative_path.as_str()).collect::<Vec<_>>();
+    assert_eq!(relative, vec!["analysis/billing.npad", "broken.npad", "imported.ipynb"]);
+    assert_eq!(files[0].title, "Billing");
+    assert_eq!(files[0].languages, vec!["go"]);
+    assert_eq!((files[0].cell_count, files[0].code_cell_count), (2, 1));
+    assert!(files[1].error.is_some());
+    assert_eq!(files[2].format, "ipynb");
+
+    let hits = store.search_cells("parseBillingCSV", 5).expect("search");
+    assert_eq!(hits[0].title.as_deref(), Some("Billing"));
+
+    // Unmodified files, broken ones included, are not parsed again.
+    let again = store.scan_workspace(ws.id).expect("rescan");
+    assert_eq!(
+        again,
+        ScanReport {
+            unchanged: 3,
+            ..ScanReport::default()
+        }
+    );
+
+    write_npad(&root.join("analysis/billing.npad"), "Billing v2", "parseInvoices()");
+    bump_mtime(&root.join("analysis/billing.npad"));
+    fs::remove_file(root.join("imported.ipynb")).expect("delete");
+    let changed = store.scan_workspace(ws.id).expect("rescan");
+    assert_eq!((changed.updated, changed.removed), (1, 1));
+    assert!(store.search_cells("parseBillingCSV", 5).expect("search").is_empty());
+    assert!(store.search_cells("pandas", 5).expect("search").is_empty());
+
+    store.remove_workspace(ws.id).expect("remove");
+    assert!(store.list_workspaces().expect("list").is_empty());
+    assert!(store.search_cells("parseInvoices", 5).expect("search").is_empty());
+}
+
+#[test]
+fn rescans_only_changed_paths() {
+    let dir = tempdir().expect("tempdir");
+    let root = dir.path().join("ws");
+    fs::create_dir_all(&root).expect("mkdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let ws = store.add_workspace(&root).expect("add");
+    let root = Path::new(&ws.root).to_path_buf();
+
+    let created = root.join("new.npad");
+    write_npad(&created, "New", "fmt.Println(1)");
+    let outside = dir.path().join("outside.npad");
+    write_npad(&outside, "Outside", "x");
+    let report = store
+        .rescan_paths(&[created.clone(), outside, root.join("notes.txt")])
+        .expect("rescan");
+    assert_eq!(report.added, 1);
+    assert_eq!(store.workspace_files(ws.id).expect("files").len(), 1);
+
+    fs::remove_file(&created).expect("delete");
+    let report = store.rescan_paths(&[created]).expect("rescan");
+    assert_eq!(report.removed, 1);
+    assert!(store.workspace_files(ws.id).expect("files").is_empty());
+}
+
+#[test]
+fn rescans_directories_and_forgets_what_was_below_them() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let ws = store.add_workspace(dir.path()).expect("add");
+    let root = Path::new(&ws.root).to_path_buf();
+
+    let reports = root.join("reports");
+    fs::create_dir_all(reports.join("2024")).expect("mkdir");
+    write_npad(&reports.join("q1.npad"), "Q1", "x");
+    write_npad(&reports.join("2024/q2.npad"), "Q2", "x");
+    let report = store.rescan_paths(std::slice::from_ref(&reports)).expect("rescan");
+    assert_eq!(report.added, 2);
+
+    let archive = root.join("archive");
+    fs::rename(&reports, &archive).expect("move");
+    let report = store.rescan_paths(&[reports, archive]).expect("rescan");
+    assert_eq!((report.added, report.removed), (2, 2));
+    let relative = store
+        .workspace_files(ws.id)
+        .expect("files")
+        .into_iter()
+        .map(|f| f.relative_path)
+        .collect::<Vec<_>>();
+    assert_eq!(relative, vec!["archive/2024/q2.npad", "archive/q1.npad"]);
+}
+
+#[test]
+fn removing_a_workspace_keeps_notebooks_saved_in_the_app() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let ws = store.add_workspace(dir.path()).expect("add");
+    let root = Path::new(&ws.root).to_path_buf();
+    let saved = root.join("saved.npad");
+    write_npad(&saved, "Saved", "reconcileAccounts()");
+    write_npad(&root.join("scanned.npad"), "Scanned", "scannedOnly()");
+    assert_eq!(store.scan_workspace(ws.id).expect("scan").added, 2);
+
+    // What saving the notebook in the app records.
+    let saved_path = saved.to_string_lossy();
+    let notebook = Notebook::load_npad(&saved).expect("load");
+    store
+        .upsert_notebook_index(&saved_path, "Saved", &notebook.metadata.updated_at.to_rfc3339())
+        .expect("index");
+    store.index_notebook_cells(&saved_path, &notebook).expect("cells");
+
+    store.remove_workspace(ws.id).expect("remove");
+    assert!(store.search_cells("scannedOnly", 5).expect("search").is_empty());
+    assert_eq!(store.search_cells("reconcileAccounts", 5).expect("search").len(), 1);
+}
+
+#[test]
+fn nested_workspaces_index_each_file_once() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let outer = store.add_workspace(dir.path()).expect("add");
+    let root = Path::new(&outer.root).to_path_buf();
+    fs::create_dir_all(root.join("team")).expect("mkdir");
+    write_npad(&root.join("top.npad"), "Top", "x");
+    let nested = root.join("team/forecast.npad");
+    write_npad(&nested, "Forecast", "forecastDemand()");
+    assert_eq!(store.scan_workspace(outer.id).expect("scan").added, 2);
+
+    // The inner workspace takes over its files; rescanning either one leaves
+    // them where they are.
+    let inner = store.add_workspace(root.join("team")).expect("add inner");
+    let report = store.scan_workspace(inner.id).expect("scan inner");
+    assert_eq!(report.added, 1);
+    for _ in 0..2 {
+        assert_eq!(
+            store.scan_workspace(outer.id).expect("rescan"),
+            ScanReport {
+                unchanged: 1,
+                ..ScanReport::default()
+            }
+        );
+        assert_eq!(
+            store.scan_workspace(inner.id).expect("rescan inner"),
+            ScanReport {
+                unchanged: 1,
+                ..ScanReport::default()
+            }
+        );
+    }
+    let relative = |id| {
+        store
+            .workspace_files(id)
+            .expect("files")
+            .into_iter()
+            .map(|f| f.relative_path)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(relative(outer.id), vec!["top.npad"]);
+    assert_eq!(relative(inner.id), vec!["forecast.npad"]);
+
+    bump_mtime(&nested);
+    let report = store.rescan_paths(std::slice::from_ref(&nested)).expect("rescan path");
+    assert_eq!(report.updated, 1);
+    assert_eq!(relative(inner.id), vec!["forecast.npad"]);
+
+    // Removing the inner workspace hands its files back to the outer one.
+    store.remove_workspace(inner.id).expect("remove");
+    assert_eq!(relative(outer.id), vec!["team/forecast.npad", "top.npad"]);
+    assert_eq!(store.search_cells("forecastDemand", 5).expect("search").len(), 1);
+    assert_eq!(store.scan_workspace(outer.id).expect("rescan").unchanged, 2);
+}
+
+#[test]
+fn indexes_archived_notebooks() {
+    let dir = tempdir().expect("tempdir");
+    let store = MetadataStore::open(dir.path().join("meta.sqlite")).expect("store");
+    let ws = store.add_workspace(dir.path()).expect("add");
+    let mut archived = Notebook::new("Archived");
+    archived.add_code_cell("ruby", "summarizeLedger()");
+    archived.save_npadz(Path::new(&ws.root).join("ledger.npadz")).expect("npadz");
+
+    assert_eq!(store.scan_workspace(ws.id).expect("scan").added, 1);
+    let files = store.workspace_files(ws.id).expect("files");
+    assert_eq!((files[0].format.as_str(), files[0].title.as_str()), ("npadz", "Archived"));
+    let hits = store.search_cells("summarizeLedger", 5).expect("search");
+    assert_eq!(hits[0].title.as_deref(), Some("Archived"));
+}
+
+#[test]
+fn watcher_reports_changed_notebooks() {
+    let dir = tempdir().expect("tempdir");
+    let root = fs::canonicalize(dir.path()).expect("canonical");
+    let (tx, rx) = mpsc::channel();
+    let _watcher = WorkspaceWatcher::start(std::slice::from_ref(&root), move |paths| {
+        let _ = tx.send(paths);
+    })
+    .expect("watch");
+
+    fs::write(root.join("ignored.txt"), "x").expect("write");
+    write_npad(&root.join("watched.npad"), "Watched", "x");
+    let paths = rx.recv_timeout(Duration::from_secs(10)).expect("change event");
+    assert!(!paths.iter().any(|p| p.file_name().is_some_and(|n| n == "ignored.txt")));
+    assert!(paths.iter().any(|p| p.file_name().is_some_and(|n| n == "watched.npad")));
+}
@@ -0,0 +1,536 @@
+use crate::error::{ClientError, ClientResult};
+use crate::framing::{read_frame, write_frame};
+use crate::output::{Output, OutputReader};
+use crate::transport::{self, Endpoint, Transport};
+use crate::{IpcEnvelope, IpcError, IpcRequest, KernelEvent, KernelRequest, KernelResponse, StreamName};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An event from the kernel, with the id of the request that caused it if
+/// the kernel attributed it to one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub request_id: Option<String>,
+    pub event: KernelEvent,
+}
+
+/// Output a request printed, word that a stream has caught up with it, or
+/// the request's reply.
+enum Printed {
+    Text(StreamName, String),
+    Drained(StreamName),
+    Replied(ClientResult<KernelResponse>),
+}
+
+#[derive(Default)]
+struct Routes {
+    pending: HashMap<String, Sender<IpcEnvelope>>,
+    /// Callers of [`KernelClient::call_with_output`], by request id.
+    printed: HashMap<String, Sender<Printed>>,
+    subscribers: Vec<Sender<Notification>>,
+    /// Streams handed to [`KernelClient::capture_output`].
+    captured: Vec<StreamName>,
+    /// Set once the connection ends or turns out corrupt; later calls fail
+    /// immediately.
+    closed: bool,
+}
+
+/// One connection to a kernel. A reader thread hands each reply to the caller
+/// waiting on its id and fans events out to subscribers, so any number of
+/// threads can have requests in flight at once. Messages travel as
+/// [frames](crate::framing) on a channel of their own; what the kernel's
+/// program prints reaches subscribers through [`capture_output`](Self::capture_output).
+pub struct KernelClient {
+    writer: Mutex<Box<dyn Write + Send>>,
+    routes: Arc<Mutex<Routes>>,
+    next_id: AtomicU64,
+}
+
+impl KernelClient {
+    pub fn new<R, W>(reader: R, writer: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let reader_routes = Arc::clone(&routes);
+        // Exits by itself once the kernel closes its end of the connection.
+        thread::spawn(move || read_loop(reader, &reader_routes));
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+            routes,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// A client speaking over `transport`, e.g. a [`Pipe`](crate::transport::Pipe)
+    /// to a child's stdio or an accepted socket.
+    pub fn over<T: Transport>(transport: T) -> ClientResult<Self> {
+        let (reader, writer) = transport.split()?;
+        Ok(Self::new(reader, writer))
+    }
+
+    /// Connects to a kernel that is already listening at `endpoint`.
+    pub fn connect(endpoint: &Endpoint) -> ClientResult<Self> {
+        let (reader, writer) = transport::connect(endpoint)?;
+        Ok(Self::new(reader, writer))
+    }
+
+    /// Sends `request` and blocks until its reply arrives.
+    pub fn call(&self, request: KernelRequest) -> ClientResult<KernelResponse> {
+        let (_, rx) = self.send(request, None)?;
+        // The sender is only dropped once the reader has given up on the kernel.
+        let envelope = rx.recv().map_err(|_| ClientError::Disconnected)?;
+        Ok(envelope.into_result()?)
+    }
+
+    /// Like [`call`](Self::call), but gives up after `timeout`. A reply that
+    /// arrives later is dropped.
+    pub fn call_timeout(&self, request: KernelRequest, timeout: Duration) -> ClientResult<KernelResponse> {
+        let method = request.method();
+        let (id, rx) = self.send(request, None)?;
+        match rx.recv_timeout(timeout) {
+            Ok(envelope) => Ok(envelope.into_result()?),
+            Err(err) => {
+                self.forget(&id);
+                Err(match err {
+                    RecvTimeoutError::Timeout => ClientError::Timeout { method, timeout },
+                    RecvTimeoutError::Disconnected => ClientError::Disconnected,
+                })
+            }
+        }
+    }
+
+    /// Like [`call`](Self::call), for requests that run user code: also
+    /// returns what the request printed to the captured streams, in the order
+    /// it was read, and hands each piece to `on_output` as soon as it is read.
+    /// After the reply, waits up to `settle` for every stream to reach the
+    /// request's end [mark](crate::output::OUTPUT_MARK). A kernel that has
+    /// marked the end on every captured stream but not replied within
+    /// `settle` is taken to have lost the request, and the call fails with
+    /// [`Timeout`](ClientError::Timeout) rather than waiting forever.
+    pub fn call_with_output(
+        &self,
+        request: KernelRequest,
+        settle: Duration,
+        mut on_output: impl FnMut(StreamName, &str),
+    ) -> (Vec<(StreamName, String)>, ClientResult<KernelResponse>) {
+        let method = request.method();
+        let (printed_tx, printed) = mpsc::channel();
+        let (id, rx) = match self.send(request, Some(printed_tx.clone())) {
+            Ok(sent) => sent,
+            Err(err) => return (Vec::new(), Err(err)),
+        };
+        // The reply joins the output on one channel so output can be passed on
+        // while the request runs.
+        thread::spawn(move || {
+            let reply = rx
+                .recv()
+                .map_err(|_| ClientError::Disconnected)
+                .and_then(|envelope| Ok(envelope.into_result()?));
+            let _ = printed_tx.send(Printed::Replied(reply));
+        });
+        let mut streams: Vec<(StreamName, String)> = Vec::new();
+        let mut record = |name: StreamName, text: String| {
+            on_output(name, &text);
+            // Consecutive writes to the same stream read as one.
+            match streams.last_mut() {
+                Some((last, buffered)) if *last == name => buffered.push_str(&text),
+                _ => streams.push((name, text)),
+            }
+        };
+        let captured = self
+            .routes
+            .lock()
+            .map(|routes| routes.captured.clone())
+            .unwrap_or_default();
+        let mut drained = Vec::new();
+        // Set once every captured stream has seen the end mark: the request is
+        // done, so its reply is due.
+        let mut reply_due: Option<Instant> = None;
+        let reply = loop {
+            // The reply thread holds a sender until it has sent the reply.
+            let next = match reply_due {
+                Some(deadline) => match printed.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Err(RecvTimeoutError::Timeout) => break Err(ClientError::Timeout { method, timeout: settle }),
+                    next => next.ok(),
+                },
+                None => printed.recv().ok(),
+            };
+            match next {
+                Some(Printed::Text(name, text)) => record(name, text),
+                Some(Printed::Drained(name)) => {
+                    drained.push(name);
+                    if !captured.is_empty() && captured.iter().all(|stream| drained.contains(stream)) {
+                        reply_due.get_or_insert_with(|| Instant::now() + settle);
+                    }
+                }
+                Some(Printed::Replied(reply)) => break reply,
+                None => break Err(ClientError::Disconnected),
+            }
+        };
+        // A kernel that went away or lost the request marks nothing more.
+        let mut waiting = match reply {
+            Err(ClientError::Disconnected | ClientError::Timeout { .. }) => Vec::new(),
+            _ => captured,
+        };
+        waiting.retain(|stream| !drained.contains(stream));
+        let deadline = Instant::now() + settle;
+        loop {
+            let next = if waiting.is_empty() {
+                printed.try_recv().ok()
+            } else {
+                printed.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok()
+            };
+            match next {
+                Some(Printed::Text(name, text)) => record(name, text),
+                Some(Printed::Drained(name)) => waiting.retain(|stream| *stream != name),
+                Some(Printed::Replied(_)) => {}
+                None => break,
+            }
+        }
+        self.forget(&id);
+        (streams, reply)
+    }
+
+    /// Reports everything read from `reader` (the kernel's stdout or stderr)
+    /// as `name` output until it ends. Output between a request's marks is
+    /// attributed to that request.
+    pub fn capture_output<R: Read + Send + 'static>(&self, reader: R, name: StreamName) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.captured.push(name);
+        }
+        let routes = Arc::clone(&self.routes);
+        thread::spawn(move || {
+            let mut reader = OutputReader::new(reader);
+            let mut running: Option<String> = None;
+            while let Ok(Some(output)) = reader.read_output() {
+                let Ok(mut routes) = routes.lock() else {
+                    return;
+                };
+                match output {
+                    Output::Text(text) => {
+                        if let Some(tx) = running.as_ref().and_then(|id| routes.printed.get(id)) {
+                            let _ = tx.send(Printed::Text(name, text.clone()));
+                        }
+                        publish(&mut routes, running.clone(), KernelEvent::Stream { name, text });
+                    }
+                    Output::Begin(id) => running = Some(id),
+                    Output::End(id) => {
+                        if let Some(tx) = routes.printed.get(&id) {
+                            let _ = tx.send(Printed::Drained(name));
+                        }
+                        running = None;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Receives every event the kernel sends from now on.
+    pub fn subscribe(&self) -> Receiver<Notification> {
+        let (tx, rx) = mpsc::channel();
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.subscribers.push(tx);
+        }
+        rx
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.routes.lock().map_or(true, |routes| routes.closed)
+    }
+
+    fn send(
+        &self,
+        request: KernelRequest,
+        printed: Option<Sender<Printed>>,
+    ) -> ClientResult<(String, Receiver<IpcEnvelope>)> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut routes = self.routes.lock().map_err(|_| ClientError::Disconnected)?;
+            if routes.closed {
+                return Err(ClientError::Disconnected);
+            }
+            routes.pending.insert(id.clone(), tx);
+            if let Some(printed) = printed {
+                routes.printed.insert(id.clone(), printed);
+            }
+        }
+        let payload = serde_json::to_vec(&IpcRequest::new(id.clone(), request))?;
+        let written = self
+            .writer
+            .lock()
+            .map_err(|_| ClientError::Disconnected)
+            .and_then(|mut writer| Ok(write_frame(&mut *writer, &payload)?));
+        if let Err(err) = written {
+            self.forget(&id);
+            return Err(err);
+        }
+        Ok((id, rx))
+    }
+
+    fn forget(&self, id: &str) {
+        if let Ok(mut routes) = self.routes.lock() {
+            routes.pending.remove(id);
+            routes.printed.remove(id);
+        }
+    }
+}
+
+fn read_loop<R: Read>(reader: R, routes: &Mutex<Routes>) {
+    let mut reader = BufReader::new(reader);
+    // A corrupt frame ends the connection like EOF does: nothing after it
+    // can be trusted to line up.
+    while let Ok(Some(payload)) = read_frame(&mut reader) {
+        let Ok(mut routes) = routes.lock() else {
+            return;
+        };
+        match serde_json::from_slice::<IpcEnvelope>(&payload) {
+            Ok(envelope) => dispatch(&mut routes, envelope),
+            Err(err) => fail_unreadable_reply(&mut routes, &payload, err),
+        }
+    }
+    if let Ok(mut routes) = routes.lock() {
+        routes.closed = true;
+        // Dropping the senders wakes every waiting caller with `Disconnected`.
+        routes.pending.clear();
+        routes.subscribers.clear();
+    }
+}
+
+fn dispatch(routes: &mut Routes, envelope: IpcEnvelope) {
+    if let Some(event) = envelope.event {
+        publish(routes, envelope.id, event);
+        return;
+    }
+    // Replies without a known id (e.g. to a request that timed out, or a
+    // parse error the kernel could not attribute) have nobody to go to.
+    if let Some(tx) = envelope.id.as_ref().and_then(|id| routes.pending.remove(id)) {
+        let _ = tx.send(envelope);
+    }
+}
+
+/// Answers the caller of a reply that does not parse with `bad_response`,
+/// if its id can still be made out. Unreadable events are dropped.
+fn fail_unreadable_reply(routes: &mut Routes, payload: &[u8], err: serde_json::Error) {
+    let Ok(Value::Object(object)) = serde_json::from_slice::<Value>(payload) else {
+        return;
+    };
+    if object.contains_key("event") {
+        return;
+    }
+    let Some(tx) = object
+        .get("id")
+        .and_then(Value::as_str)
+        .and_then(|id| routes.pending.remove(id))
+    else {
+        return;
+    };
+    let _ = tx.send(IpcEnvelope {
+        id: object.get("id").and_then(Value::as_str).map(str::to_string),
+        event: None,
+        result: None,
+        error: Some(IpcError {
+            code: "bad_response".to_string(),
+            message: format!("unreadable reply: {err}"),
+            details: None,
+        }),
+    });
+}
+
+fn publish(routes: &mut Routes, request_id: Option<String>, event: KernelEvent) {
+    let notification = Notification { request_id, event };
+    routes
+        .subscribers
+        .retain(|subscriber| subscriber.send(notification.clone()).is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OUTPUT_MARK;
+    use crate::ExecuteParams;
+    use std::io;
+    use std::net::{TcpListener, TcpStream};
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (ours, listener.accept().unwrap().0)
+    }
+
+    fn reply(writer: &mut impl Write, id: String, result: KernelResponse) {
+        let envelope = IpcEnvelope {
+            id: Some(id),
+            event: None,
+            result: Some(result),
+            error: None,
+        };
+        write_frame(writer, &serde_json::to_vec(&envelope).unwrap()).unwrap();
+    }
+
+    /// A fake kernel answering `ping` at once and `execute` only after the
+    /// next `ping`. Anything else ends it.
+    fn fake_kernel(stream: TcpStream) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut parked = None;
+        while let Ok(Some(payload)) = read_frame(&mut reader) {
+            let request: IpcRequest = serde_json::from_slice(&payload).unwrap();
+            match request.request {
+                KernelRequest::Execute(_) => parked = Some(request.id),
+                KernelRequest::Ping => {
+                    reply(&mut writer, request.id, KernelResponse::Ack);
+                    if let Some(id) = parked.take() {
+                        reply(&mut writer, id, KernelResponse::Execute { data: Default::default() });
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    #[test]
+    fn replies_reach_the_caller_that_sent_the_request() {
+        let (ours, theirs) = connected_pair();
+        let kernel = thread::spawn(move || fake_kernel(theirs));
+        let client = Arc::new(KernelClient::new(ours.try_clone().unwrap(), ours));
+        let events = client.subscribe();
+        client.capture_output(io::Cursor::new("hé".as_bytes().to_vec()), StreamName::Stderr);
+
+        let executing = {
+            let client = Arc::clone(&client);
+            thread::spawn(move || {
+                client.call(KernelRequest::Execute(ExecuteParams {
+                    code: "slow()".to_string(),
+                }))
+            })
+        };
+        // Answered while the execute is still outstanding.
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.call(KernelRequest::Ping).unwrap(), KernelResponse::Ack);
+        let executed = executing.join().unwrap().unwrap();
+        assert!(matches!(executed, KernelResponse::Execute { .. }));
+        let notification = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(notification.request_id, None);
+        assert_eq!(
+            notification.event,
+            KernelEvent::Stream {
+                name: StreamName::Stderr,
+                text: "hé".to_string()
+            }
+        );
+
+        // The kernel exits without answering; the caller is woken, not left hanging.
+        let restart = client.call_timeout(KernelRequest::Restart, Duration::from_secs(5));
+        assert!(matches!(restart, Err(ClientError::Disconnected)));
+        kernel.join().unwrap();
+        assert!(client.is_closed());
+        assert!(matches!(client.call(KernelRequest::Ping), Err(ClientError::Disconnected)));
+    }
+
+    #[test]
+    fn unreadable_reply_fails_its_caller() {
+        let (ours, mut theirs) = connected_pair();
+        let kernel = thread::spawn(move || {
+            let payload = read_frame(&mut theirs).unwrap().unwrap();
+            let request: IpcRequest = serde_json::from_slice(&payload).unwrap();
+            let garbled = serde_json::json!({ "id": request.id, "result": { "kind": "bogus" } });
+            write_frame(&mut theirs, garbled.to_string().as_bytes()).unwrap();
+        });
+        let client = KernelClient::new(ours.try_clone().unwrap(), ours);
+
+        match client.call(KernelRequest::Ping) {
+            Err(ClientError::Kernel(err)) => assert_eq!(err.code, "bad_response"),
+            other => panic!("expected bad_response, got {other:?}"),
+        }
+        kernel.join().unwrap();
+    }
+
+    #[test]
+    fn output_between_marks_belongs_to_its_request() {
+        let (ours, mut theirs) = connected_pair();
+        let (stdout, mut kernel_stdout) = connected_pair();
+        let kernel = thread::spawn(move || {
+            let payload = read_frame(&mut theirs).unwrap().unwrap();
+            let request: IpcRequest = serde_json::from_slice(&payload).unwrap();
+            let mark = |kind: char| {
+                let mut line = OUTPUT_MARK.to_vec();
+                line.extend_from_slice(format!("{kind}{}\n", request.id).as_bytes());
+                line
+            };
+            kernel_stdout.write_all(b"left over\n").unwrap();
+            kernel_stdout.write_all(&mark('+')).unwrap();
+            kernel_stdout.write_all(b"hello\n").unwrap();
+            reply(&mut theirs, request.id.clone(), KernelResponse::Execute { data: Default::default() });
+            // Output may trail the reply; the caller waits for the end mark.
+            thread::sleep(Duration::from_millis(50));
+            kernel_stdout.write_all(b"world\n").unwrap();
+            kernel_stdout.write_all(&mark('-')).unwrap();
+            kernel_stdout.write_all(b"later\n").unwrap();
+        });
+        let client = KernelClient::new(ours.try_clone().unwrap(), ours);
+        client.capture_output(stdout, StreamName::Stdout);
+
+        let mut live = Vec::new();
+        let (streams, reply) = client.call_with_output(
+            KernelRequest::Execute(ExecuteParams {
+                code: "puts()".to_string(),
+            }),
+            Duration::from_secs(5),
+            |name, text| live.push((name, text.to_string())),
+        );
+        assert!(matches!(reply, Ok(KernelResponse::Execute { .. })));
+        assert_eq!(streams, vec![(StreamName::Stdout, "hello\nworld\n".to_string())]);
+        assert_eq!(
+            live,
+            vec![
+                (StreamName::Stdout, "hello\n".to_string()),
+                (StreamName::Stdout, "world\n".to_string()),
+            ]
+        );
+        kernel.join().unwrap();
+    }
+
+    #[test]
+    fn finished_request_without_a_reply_times_out() {
+        let (ours, mut theirs) = connected_pair();
+        let (stdout, mut kernel_stdout) = connected_pair();
+        let kernel = thread::spawn(move || {
+            let payload = read_frame(&mut theirs).unwrap().unwrap();
+            let request: IpcRequest = serde_json::from_slice(&payload).unwrap();
+            // The cell runs to its end but its reply is never sent, as when
+            // it takes the kernel's execution thread down with it.
+            for kind in ['+', '-'] {
+                let mut line = OUTPUT_MARK.to_vec();
+                line.extend_from_slice(format!("{kind}{}\n", request.id).as_bytes());
+                kernel_stdout.write_all(&line).unwrap();
+            }
+            // Still connected, and still answering everything else.
+            let payload = read_frame(&mut theirs).unwrap().unwrap();
+            let request: IpcRequest = serde_json::from_slice(&payload).unwrap();
+            reply(&mut theirs, request.id, KernelResponse::Ack);
+        });
+        let client = KernelClient::new(ours.try_clone().unwrap(), ours);
+        client.capture_output(stdout, StreamName::Stdout);
+
+        let (streams, reply) = client.call_with_output(
+            KernelRequest::Execute(ExecuteParams {
+                code: "exit()".to_string(),
+            }),
+            Duration::from_millis(200),
+            |_, _| {},
+        );
+        assert!(streams.is_empty());
+        assert!(matches!(reply, Err(ClientError::Timeout { method: "execute", .. })));
+        assert_eq!(client.call(KernelRequest::Ping).unwrap(), KernelResponse::Ack);
+        kernel.join().unwrap();
+    }
+}
@@ -0,0 +1,19 @@
+use crate::IpcError;
+use std::time::Duration;
+use thiserror::Error;
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("kernel error: {0}")]
+    Kernel(#[from] IpcError),
+    #[error("kernel disconnected")]
+    Disconnected,
+    #[error("no reply to '{method}' within {timeout:?}")]
+    Timeout { method: &'static str, timeout: Duration },
+}
@@ -0,0 +1,101 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Starts every frame. A stream that does not continue with it where a frame
+/// is expected is corrupt.
+pub const FRAME_MAGIC: [u8; 4] = *b"\0NPF";
+/// Larger frames are treated as corrupt rather than allocated.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+const HEADER_LEN: usize = FRAME_MAGIC.len() + 4;
+
+/// Writes `payload` as one frame: [`FRAME_MAGIC`], its length as a big-endian
+/// `u32`, then the bytes. Header and payload go out in a single write so
+/// frames written from several threads do not interleave.
+pub fn write_frame<W: Write + ?Sized>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "frame too large"));
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&FRAME_MAGIC);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// Reads the next frame's payload, or `None` if the stream ended between
+/// frames. Reads the header a byte range at a time, so wrap unbuffered
+/// streams in a `BufReader`.
+pub fn read_frame<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if header[..FRAME_MAGIC.len()] != FRAME_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "missing frame header"));
+    }
+    let len_bytes: [u8; 4] = header[FRAME_MAGIC.len()..]
+        .try_into()
+        .expect("header slice is four bytes");
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out its bytes a few at a time, like a socket under load.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let n = self.1.min(self.0.len()).min(out.len());
+            out[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, br#"{"id":"1"}"#).unwrap();
+        write_frame(&mut stream, b"").unwrap();
+
+        for step in [1, 3, 4096] {
+            let mut reader = Trickle(&stream, step);
+            assert_eq!(read_frame(&mut reader).unwrap(), Some(br#"{"id":"1"}"#.to_vec()), "step {step}");
+            assert_eq!(read_frame(&mut reader).unwrap(), Some(Vec::new()), "step {step}");
+            assert_eq!(read_frame(&mut reader).unwrap(), None, "step {step}");
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        let mut oversized = FRAME_MAGIC.to_vec();
+        oversized.extend_from_slice(&u32::MAX.to_be_bytes());
+        let err = read_frame(&mut &oversized[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let err = read_frame(&mut &b"hello, world"[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut truncated = Vec::new();
+        write_frame(&mut truncated, b"payload").unwrap();
+        truncated.truncate(10);
+        let err = read_frame(&mut &truncated[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
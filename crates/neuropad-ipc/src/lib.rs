@@ -1,15 +1,188 @@
+pub mod client;
+pub mod error;
+pub mod framing;
+pub mod output;
+pub mod transport;
+
+pub use client::{KernelClient, Notification};
+pub use error::{ClientError, ClientResult};
+pub use transport::{Endpoint, KernelAddress, Transport};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Bumped whenever a change to the wire format would break existing kernels.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// MIME type to rendered text, e.g. `{"text/plain": "42"}`.
+pub type MimeBundle = BTreeMap<String, String>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// One request on the wire: `{"id": "...", "method": "execute", "params": {...}}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IpcRequest {
     pub id: String,
-    pub method: String,
-    #[serde(default)]
-    pub params: Value,
+    #[serde(flatten)]
+    pub request: KernelRequest,
+}
+
+impl IpcRequest {
+    pub fn new(id: impl Into<String>, request: KernelRequest) -> Self {
+        Self { id: id.into(), request }
+    }
+}
+
+/// Every method a kernel understands. Methods without parameters omit `params`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum KernelRequest {
+    /// Sent once right after the kernel starts, before anything else.
+    Hello(HelloParams),
+    Execute(ExecuteParams),
+    Interrupt,
+    Restart,
+    Ping,
+    Complete(CompleteParams),
+    Inspect(InspectParams),
+    Shutdown,
+}
+
+impl KernelRequest {
+    pub fn method(&self) -> &'static str {
+        match self {
+            Self::Hello(_) => "hello",
+            Self::Execute(_) => "execute",
+            Self::Interrupt => "interrupt",
+            Self::Restart => "restart",
+            Self::Ping => "ping",
+            Self::Complete(_) => "complete",
+            Self::Inspect(_) => "inspect",
+            Self::Shutdown => "shutdown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HelloParams {
+    pub protocol_version: u32,
+    /// Required by kernels started with a token; they refuse every other
+    /// request until a hello carrying it has been accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Default for HelloParams {
+    fn default() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecuteParams {
+    pub code: String,
+}
+
+/// `cursor_pos` is a character offset into `code`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompleteParams {
+    pub code: String,
+    pub cursor_pos: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InspectParams {
+    pub code: String,
+    pub cursor_pos: usize,
+}
+
+/// The `result` of a successful request, tagged by `kind`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum KernelResponse {
+    Hello(KernelInfo),
+    /// Answer to `interrupt`, `restart`, `ping` and `shutdown`.
+    Ack,
+    Execute {
+        data: MimeBundle,
+    },
+    /// Replaces `code[cursor_start..cursor_end]` with one of `matches`.
+    Complete {
+        matches: Vec<String>,
+        cursor_start: usize,
+        cursor_end: usize,
+    },
+    Inspect {
+        found: bool,
+        #[serde(default)]
+        data: MimeBundle,
+    },
+}
+
+/// What a kernel reports about itself in its `hello` reply.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelInfo {
+    pub protocol_version: u32,
+    pub language: String,
+    pub language_version: String,
+    /// Methods the kernel implements, `hello` included.
+    pub methods: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl KernelInfo {
+    pub fn supports(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+
+    /// Refuses kernels speaking another protocol version or lacking `execute`.
+    pub fn check_compatible(&self) -> Result<(), IpcError> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Err(IpcError {
+                code: "incompatible_kernel".to_string(),
+                message: format!(
+                    "{} kernel speaks protocol version {}, expected {PROTOCOL_VERSION}",
+                    self.language, self.protocol_version
+                ),
+                details: None,
+            });
+        }
+        if !self.supports("execute") {
+            return Err(IpcError {
+                code: "incompatible_kernel".to_string(),
+                message: format!("{} kernel does not support execute", self.language),
+                details: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamName {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KernelState {
+    Busy,
+    Idle,
+}
+
+/// Unsolicited messages, tagged by `type`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KernelEvent {
+    Stream { name: StreamName, text: String },
+    DisplayData { data: MimeBundle },
+    Status { state: KernelState },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IpcError {
     pub code: String,
     pub message: String,
@@ -17,19 +190,128 @@ pub struct IpcError {
     pub details: Option<Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl std::fmt::Display for IpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for IpcError {}
+
+/// One message from a kernel: a `result` or `error` answering request `id`,
+/// or an `event`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IpcEnvelope {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub id: Option<String>,
-    #[serde(default)]
-    pub event: Option<String>,
-    #[serde(default)]
-    pub result: Option<Value>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<KernelEvent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<KernelResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub error: Option<IpcError>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ExecuteParams {
-    pub code: String,
+impl IpcEnvelope {
+    pub fn into_result(self) -> Result<KernelResponse, IpcError> {
+        match (self.result, self.error) {
+            (_, Some(error)) => Err(error),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(IpcError {
+                code: "bad_response".to_string(),
+                message: "envelope carries neither result nor error".to_string(),
+                details: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn requests_use_method_and_params() {
+        let execute = IpcRequest::new(
+            "1",
+            KernelRequest::Execute(ExecuteParams {
+                code: "puts 1".to_string(),
+            }),
+        );
+        assert_eq!(
+            serde_json::to_value(&execute).unwrap(),
+            json!({"id": "1", "method": "execute", "params": {"code": "puts 1"}})
+        );
+        let ping = IpcRequest::new("2", KernelRequest::Ping);
+        assert_eq!(serde_json::to_value(&ping).unwrap(), json!({"id": "2", "method": "ping"}));
+
+        let parsed: IpcRequest =
+            serde_json::from_value(json!({"id": "3", "method": "complete", "params": {"code": "pri", "cursor_pos": 3}}))
+                .unwrap();
+        assert_eq!(parsed.request.method(), "complete");
+        assert!(serde_json::from_value::<IpcRequest>(json!({"id": "4", "method": "launch"})).is_err());
+    }
+
+    #[test]
+    fn envelopes_carry_tagged_results_and_events() {
+        let envelope: IpcEnvelope = serde_json::from_value(json!({
+            "id": "1",
+            "result": {"kind": "execute", "data": {"text/plain": "2"}}
+        }))
+        .unwrap();
+        let KernelResponse::Execute { data } = envelope.into_result().unwrap() else {
+            panic!("expected an execute result");
+        };
+        assert_eq!(data["text/plain"], "2");
+
+        let event: IpcEnvelope = serde_json::from_value(json!({
+            "event": {"type": "stream", "name": "stderr", "text": "warn\n"}
+        }))
+        .unwrap();
+        assert_eq!(
+            event.event,
+            Some(KernelEvent::Stream {
+                name: StreamName::Stderr,
+                text: "warn\n".to_string()
+            })
+        );
+
+        let error: IpcEnvelope =
+            serde_json::from_value(json!({"id": "2", "error": {"code": "unknown_method", "message": "x"}})).unwrap();
+        assert_eq!(error.into_result().unwrap_err().to_string(), "unknown_method: x");
+    }
+
+    #[test]
+    fn hello_reports_kernel_info() {
+        let hello = IpcRequest::new("0", KernelRequest::Hello(HelloParams::default()));
+        assert_eq!(
+            serde_json::to_value(&hello).unwrap(),
+            json!({"id": "0", "method": "hello", "params": {"protocol_version": PROTOCOL_VERSION}})
+        );
+
+        let reply: IpcEnvelope = serde_json::from_value(json!({
+            "id": "0",
+            "result": {
+                "kind": "hello",
+                "protocol_version": PROTOCOL_VERSION,
+                "language": "ruby",
+                "language_version": "3.3.0",
+                "methods": ["hello", "execute", "ping"]
+            }
+        }))
+        .unwrap();
+        let KernelResponse::Hello(info) = reply.into_result().unwrap() else {
+            panic!("expected a hello result");
+        };
+        assert!(info.check_compatible().is_ok());
+        assert!(info.supports("ping"));
+        assert!(!info.supports("complete"));
+
+        let stale = KernelInfo {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..info
+        };
+        assert_eq!(stale.check_compatible().unwrap_err().code, "incompatible_kernel");
+    }
 }
@@ -0,0 +1,165 @@
+use std::io::{self, ErrorKind, Read};
+
+/// Kernels write `OUTPUT_MARK`, `+` or `-`, the request id and `\n` to both
+/// stdout and stderr right before and right after running a cell, so the
+/// host can tell whose output is whose and when it has all of it.
+pub const OUTPUT_MARK: [u8; 4] = *b"\0NPM";
+/// Longer lines starting with [`OUTPUT_MARK`] are ordinary output.
+const MAX_MARK_LEN: usize = 256;
+
+/// A piece of a kernel's stdout or stderr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Text(String),
+    /// The request with this id starts running.
+    Begin(String),
+    /// Everything the request with this id printed to the stream came before.
+    End(String),
+}
+
+/// Turns a kernel's stdout or stderr into text and marks as they arrive,
+/// holding back only a trailing partial UTF-8 sequence or a mark still
+/// being written.
+pub struct OutputReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> OutputReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// The next piece of output, or `None` once the stream has ended.
+    pub fn read_output(&mut self) -> io::Result<Option<Output>> {
+        loop {
+            if let Some(output) = self.take_output() {
+                return Ok(Some(output));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            let mut block = [0u8; 8192];
+            match self.inner.read(&mut block) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.buf.extend_from_slice(&block[..n]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn take_output(&mut self) -> Option<Output> {
+        match find_mark(&self.buf) {
+            Some(0) => self.take_mark(),
+            Some(start) => self.take_text(start),
+            None => {
+                let keep = if self.eof { 0 } else { partial_mark_len(&self.buf) };
+                self.take_text(self.buf.len() - keep)
+            }
+        }
+    }
+
+    /// The mark at the start of the buffer, or its first bytes as text if it
+    /// turns out not to be one.
+    fn take_mark(&mut self) -> Option<Output> {
+        let body = &self.buf[OUTPUT_MARK.len()..];
+        let Some(newline) = body.iter().position(|&byte| byte == b'\n') else {
+            if body.len() > MAX_MARK_LEN || self.eof {
+                return self.take_text(OUTPUT_MARK.len());
+            }
+            return None;
+        };
+        let id = match body[..newline].split_first() {
+            Some((&kind, id)) if !id.is_empty() => std::str::from_utf8(id).ok().map(|id| (kind, id.to_string())),
+            _ => None,
+        };
+        let mark = match id {
+            Some((b'+', id)) => Output::Begin(id),
+            Some((b'-', id)) => Output::End(id),
+            _ => return self.take_text(OUTPUT_MARK.len()),
+        };
+        self.buf.drain(..OUTPUT_MARK.len() + newline + 1);
+        Some(mark)
+    }
+
+    /// Up to `end` bytes as text. At the end of the buffer an incomplete
+    /// character is held back for the next read to complete.
+    fn take_text(&mut self, end: usize) -> Option<Output> {
+        let end = match std::str::from_utf8(&self.buf[..end]) {
+            Ok(_) => end,
+            Err(err) if err.error_len().is_none() && end == self.buf.len() && !self.eof => err.valid_up_to(),
+            Err(_) => end,
+        };
+        if end == 0 {
+            return None;
+        }
+        let bytes: Vec<u8> = self.buf.drain(..end).collect();
+        Some(Output::Text(String::from_utf8_lossy(&bytes).into_owned()))
+    }
+}
+
+fn find_mark(buf: &[u8]) -> Option<usize> {
+    buf.windows(OUTPUT_MARK.len()).position(|window| window == OUTPUT_MARK)
+}
+
+/// How many trailing bytes could be the beginning of a mark.
+fn partial_mark_len(buf: &[u8]) -> usize {
+    (1..OUTPUT_MARK.len())
+        .rev()
+        .find(|&n| buf.ends_with(&OUTPUT_MARK[..n]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out its bytes a few at a time, like a pipe under load.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let n = self.1.min(self.0.len()).min(out.len());
+            out[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn read_all(stream: &[u8], step: usize) -> Vec<Output> {
+        let mut reader = OutputReader::new(Trickle(stream, step));
+        let mut outputs: Vec<Output> = Vec::new();
+        while let Some(output) = reader.read_output().unwrap() {
+            // How text happens to be split up depends on the reads.
+            match (outputs.last_mut(), output) {
+                (Some(Output::Text(buffered)), Output::Text(text)) => buffered.push_str(&text),
+                (_, output) => outputs.push(output),
+            }
+        }
+        outputs
+    }
+
+    #[test]
+    fn separates_marks_from_text() {
+        let stream = "before \0NPM+7\nhéllo \0NPF wörld\n\0NPM-7\n\0NPMbogus\nafter\0NP".as_bytes();
+        for step in [1, 2, 4096] {
+            assert_eq!(
+                read_all(stream, step),
+                vec![
+                    Output::Text("before ".to_string()),
+                    Output::Begin("7".to_string()),
+                    Output::Text("héllo \0NPF wörld\n".to_string()),
+                    Output::End("7".to_string()),
+                    Output::Text("\0NPMbogus\nafter\0NP".to_string()),
+                ],
+                "step {step}"
+            );
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use crate::framing::read_frame;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long [`connect`] waits for a kernel to accept the connection.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Holds the secret a kernel proves itself with: the hello token of a
+/// listening kernel, or what a spawned kernel sends back to its
+/// [`ChildListener`].
+pub const TOKEN_ENV: &str = "NEUROPAD_KERNEL_TOKEN";
+
+pub type ReadHalf = Box<dyn Read + Send>;
+pub type WriteHalf = Box<dyn Write + Send>;
+
+/// A byte stream to a kernel that a [`KernelClient`](crate::KernelClient)
+/// can read frames from and write frames to concurrently. Dropping the write
+/// half ends the connection, so the kernel sees end of input.
+pub trait Transport {
+    fn split(self) -> io::Result<(ReadHalf, WriteHalf)>;
+}
+
+/// The stdout and stdin of a kernel running as a child process.
+pub struct Pipe<R, W> {
+    pub reader: R,
+    pub writer: W,
+}
+
+impl<R, W> Transport for Pipe<R, W>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    fn split(self) -> io::Result<(ReadHalf, WriteHalf)> {
+        Ok((Box::new(self.reader), Box::new(self.writer)))
+    }
+}
+
+impl Transport for TcpStream {
+    fn split(self) -> io::Result<(ReadHalf, WriteHalf)> {
+        self.set_nodelay(true)?;
+        Ok((Box::new(self.try_clone()?), Box::new(SocketWriter(self))))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn split(self) -> io::Result<(ReadHalf, WriteHalf)> {
+        Ok((Box::new(self.try_clone()?), Box::new(SocketWriter(self))))
+    }
+}
+
+trait Socket: Write {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Socket for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl Socket for std::os::unix::net::UnixStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, how)
+    }
+}
+
+/// The write half of a socket. The read half is a clone of the same socket,
+/// which keeps it open after this one is dropped; shutting it down instead
+/// also wakes the reader blocked on the other half.
+struct SocketWriter<S: Socket>(S);
+
+impl<S: Socket> Write for SocketWriter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: Socket> Drop for SocketWriter<S> {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+/// A private endpoint a spawned kernel connects back to (`--connect`), so
+/// protocol traffic never shares the child's stdout or stderr with what its
+/// programs print. On Unix it is a socket in a directory only the current
+/// user can enter, elsewhere a loopback TCP port.
+pub struct ChildListener {
+    listener: Listener,
+    endpoint: Endpoint,
+    dir: Option<PathBuf>,
+}
+
+enum Listener {
+    #[cfg(not(unix))]
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl ChildListener {
+    #[cfg(unix)]
+    pub fn bind() -> io::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let dir = loop {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let dir = std::env::temp_dir().join(format!("neuropad-{}-{n}", std::process::id()));
+            match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => break dir,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        };
+        let path = dir.join("kernel.sock");
+        let listener = match std::os::unix::net::UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                return Err(err);
+            }
+        };
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener: Listener::Unix(listener),
+            endpoint: Endpoint::Unix(path),
+            dir: Some(dir),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind() -> io::Result<Self> {
+        let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            endpoint: Endpoint::Tcp(listener.local_addr()?.to_string()),
+            listener: Listener::Tcp(listener),
+            dir: None,
+        })
+    }
+
+    /// The address to hand the kernel.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Waits for the kernel to connect and send `token` as its first frame;
+    /// connections that do not are dropped. Gives up after `timeout`, or as
+    /// soon as `alive` returns false, e.g. because the child exited.
+    pub fn accept(
+        &self,
+        token: &str,
+        timeout: Duration,
+        mut alive: impl FnMut() -> bool,
+    ) -> io::Result<(ReadHalf, WriteHalf)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let accepted = match &self.listener {
+                #[cfg(not(unix))]
+                Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Accepted::Tcp(stream)),
+                #[cfg(unix)]
+                Listener::Unix(listener) => listener.accept().map(|(stream, _)| Accepted::Unix(stream)),
+            };
+            match accepted {
+                Ok(stream) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if let Some(halves) = stream.identify(token, remaining)? {
+                        return Ok(halves);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+            if !alive() {
+                return Err(io::Error::new(ErrorKind::BrokenPipe, "kernel exited before connecting"));
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "kernel did not connect in time"));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for ChildListener {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+enum Accepted {
+    #[cfg(not(unix))]
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+}
+
+impl Accepted {
+    /// The connection's halves if its first frame is `token`, `None` if it
+    /// is someone else.
+    fn identify(self, token: &str, timeout: Duration) -> io::Result<Option<(ReadHalf, WriteHalf)>> {
+        // A zero timeout would mean "block forever".
+        let timeout = Some(timeout.max(Duration::from_millis(1)));
+        match self {
+            #[cfg(not(unix))]
+            Self::Tcp(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(timeout)?;
+                if !sent_token(&stream, token) {
+                    return Ok(None);
+                }
+                stream.set_read_timeout(None)?;
+                stream.split().map(Some)
+            }
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(timeout)?;
+                if !sent_token(&stream, token) {
+                    return Ok(None);
+                }
+                stream.set_read_timeout(None)?;
+                stream.split().map(Some)
+            }
+        }
+    }
+}
+
+/// Reads the first frame straight from the socket, so nothing the kernel
+/// sends after it is left behind in a buffer.
+fn sent_token(mut stream: impl Read, token: &str) -> bool {
+    match read_frame(&mut stream) {
+        Ok(Some(sent)) => constant_time_eq(&sent, token.as_bytes()),
+        _ => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Where a kernel that was started on its own is listening.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// `host:port`.
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// An [`Endpoint`] plus the token the kernel was started with, written as
+/// `tcp://127.0.0.1:9000?token=secret` or `unix:///run/neuropad/ruby.sock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelAddress {
+    pub endpoint: Endpoint,
+    pub token: Option<String>,
+}
+
+impl FromStr for KernelAddress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(ErrorKind::InvalidInput, format!("{message}: '{s}'"));
+        let (location, token) = match s.split_once("?token=") {
+            Some((location, token)) if !token.is_empty() => (location, Some(token.to_string())),
+            Some(_) => return Err(invalid("empty kernel token")),
+            None => (s, None),
+        };
+        let endpoint = if let Some(host_port) = location.strip_prefix("tcp://") {
+            if host_port.rsplit_once(':').is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err()) {
+                return Err(invalid("expected tcp://host:port"));
+            }
+            Endpoint::Tcp(host_port.to_string())
+        } else if let Some(path) = location.strip_prefix("unix://") {
+            if path.is_empty() {
+                return Err(invalid("expected unix:///path/to/socket"));
+            }
+            Endpoint::Unix(PathBuf::from(path))
+        } else {
+            return Err(invalid("kernel address must start with tcp:// or unix://"));
+        };
+        Ok(Self { endpoint, token })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(host_port) => write!(f, "tcp://{host_port}"),
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl fmt::Display for KernelAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.endpoint)?;
+        if let Some(token) = &self.token {
+            write!(f, "?token={token}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens a connection to a listening kernel.
+pub fn connect(endpoint: &Endpoint) -> io::Result<(ReadHalf, WriteHalf)> {
+    match endpoint {
+        Endpoint::Tcp(host_port) => {
+            let mut last_err = io::Error::new(ErrorKind::NotFound, format!("'{host_port}' did not resolve"));
+            for addr in host_port.to_socket_addrs()? {
+                match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                    Ok(stream) => return stream.split(),
+                    Err(err) => last_err = err,
+                }
+            }
+            Err(last_err)
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => std::os::unix::net::UnixStream::connect(path)?.split(),
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "unix sockets are not supported on this platform",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::write_frame;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+
+    /// Sends one frame to whoever connects, checks it comes back unchanged, then
+    /// hangs up.
+    fn round_trip(endpoint: &Endpoint, accept: impl FnOnce() -> (ReadHalf, WriteHalf) + Send + 'static) {
+        let echo = thread::spawn(move || {
+            let (reader, mut writer) = accept();
+            if let Ok(Some(payload)) = read_frame(&mut BufReader::new(reader)) {
+                write_frame(&mut writer, &payload).unwrap();
+            }
+        });
+        let (reader, mut writer) = connect(endpoint).unwrap();
+        write_frame(&mut writer, b"ping").unwrap();
+        let mut reader = BufReader::new(reader);
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"ping".to_vec()));
+        echo.join().unwrap();
+        // Dropping our write half closes the connection for both halves.
+        drop(writer);
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn connects_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap().to_string());
+        round_trip(&endpoint, move || listener.accept().unwrap().0.split().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn connects_over_unix_sockets() {
+        use std::os::unix::net::UnixListener;
+        let path = std::env::temp_dir().join(format!("neuropad-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        round_trip(&Endpoint::Unix(path.clone()), move || listener.accept().unwrap().0.split().unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn child_listener_accepts_only_the_kernel_with_the_token() {
+        let listener = ChildListener::bind().unwrap();
+        let endpoint = listener.endpoint().clone();
+        let kernel = thread::spawn(move || {
+            let (_, mut impostor) = connect(&endpoint).unwrap();
+            write_frame(&mut impostor, b"guess").unwrap();
+            let (reader, mut writer) = connect(&endpoint).unwrap();
+            write_frame(&mut writer, b"s3cret").unwrap();
+            write_frame(&mut writer, b"hello").unwrap();
+            // Stay connected until the host hangs up.
+            let _ = read_frame(&mut BufReader::new(reader));
+        });
+        let (reader, writer) = listener.accept("s3cret", Duration::from_secs(5), || true).unwrap();
+        assert_eq!(read_frame(&mut BufReader::new(reader)).unwrap(), Some(b"hello".to_vec()));
+        drop(writer);
+        kernel.join().unwrap();
+
+        let gave_up = listener.accept("s3cret", Duration::from_secs(5), || false);
+        assert!(matches!(gave_up, Err(err) if err.kind() == ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn parses_and_prints_addresses() {
+        let tcp: KernelAddress = "tcp://127.0.0.1:9000?token=s3cret".parse().unwrap();
+        assert_eq!(tcp.endpoint, Endpoint::Tcp("127.0.0.1:9000".to_string()));
+        assert_eq!(tcp.token.as_deref(), Some("s3cret"));
+        assert_eq!(tcp.to_string(), "tcp://127.0.0.1:9000?token=s3cret");
+
+        let unix: KernelAddress = "unix:///run/neuropad/ruby.sock".parse().unwrap();
+        assert_eq!(unix.endpoint, Endpoint::Unix(PathBuf::from("/run/neuropad/ruby.sock")));
+        assert_eq!(unix.token, None);
+
+        for bad in ["127.0.0.1:9000", "tcp://localhost", "tcp://:80", "unix://", "tcp://h:1?token="] {
+            assert!(bad.parse::<KernelAddress>().is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,83 @@
+package main
+
+import (
+	"sort"
+	"strings"
+	"unicode"
+)
+
+// Builtins and the fmt functions cells usually reach for, with signatures.
+var knownNames = map[string]string{
+	"append":      "func append(slice []Type, elems ...Type) []Type",
+	"cap":         "func cap(v Type) int",
+	"close":       "func close(c chan<- Type)",
+	"copy":        "func copy(dst, src []Type) int",
+	"delete":      "func delete(m map[Type]Type1, key Type)",
+	"len":         "func len(v Type) int",
+	"make":        "func make(t Type, size ...IntegerType) Type",
+	"new":         "func new(Type) *Type",
+	"panic":       "func panic(v any)",
+	"recover":     "func recover() any",
+	"fmt.Println": "func fmt.Println(a ...any) (n int, err error)",
+	"fmt.Printf":  "func fmt.Printf(format string, a ...any) (n int, err error)",
+	"fmt.Sprintf": "func fmt.Sprintf(format string, a ...any) string",
+	"fmt.Sprint":  "func fmt.Sprint(a ...any) string",
+	"fmt.Errorf":  "func fmt.Errorf(format string, a ...any) error",
+}
+
+var keywords = []string{
+	"break", "case", "chan", "const", "continue", "default", "defer", "else", "fallthrough", "for",
+	"func", "go", "goto", "if", "import", "interface", "map", "package", "range", "return", "select",
+	"struct", "switch", "type", "var",
+}
+
+func isNameRune(r rune) bool {
+	return r == '_' || r == '.' || unicode.IsLetter(r) || unicode.IsDigit(r)
+}
+
+// wordAt returns the dotted name ending at cursor (a rune offset) and its start.
+func wordAt(runes []rune, cursor int) (string, int) {
+	if cursor < 0 {
+		cursor = 0
+	}
+	if cursor > len(runes) {
+		cursor = len(runes)
+	}
+	start := cursor
+	for start > 0 && isNameRune(runes[start-1]) {
+		start--
+	}
+	return string(runes[start:cursor]), start
+}
+
+func completeGo(p cursorParams) completeResult {
+	word, start := wordAt([]rune(p.Code), p.CursorPos)
+	matches := []string{}
+	if word != "" {
+		for name := range knownNames {
+			if strings.HasPrefix(name, word) {
+				matches = append(matches, name)
+			}
+		}
+		for _, kw := range keywords {
+			if strings.HasPrefix(kw, word) {
+				matches = append(matches, kw)
+			}
+		}
+		sort.Strings(matches)
+	}
+	return completeResult{Kind: "complete", Matches: matches, CursorStart: start, CursorEnd: start + len([]rune(word))}
+}
+
+func inspectGo(p cursorParams) inspectResult {
+	runes := []rune(p.Code)
+	end := p.CursorPos
+	for end >= 0 && end < len(runes) && isNameRune(runes[end]) {
+		end++
+	}
+	word, _ := wordAt(runes, end)
+	if signature, ok := knownNames[word]; ok {
+		return inspectResult{Kind: "inspect", Found: true, Data: map[string]string{"text/plain": signature}}
+	}
+	return inspectResult{Kind: "inspect", Found: false, Data: map[string]string{}}
+}
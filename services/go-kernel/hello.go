@@ -0,0 +1,21 @@
+package main
+
+import (
+	"os/exec"
+	"runtime"
+	"strings"
+)
+
+const protocolVersion = 3
+
+var methods = []string{"hello", "execute", "interrupt", "restart", "ping", "complete", "inspect", "shutdown"}
+
+// goVersion reports the toolchain cells are run with, which may differ from
+// the one the kernel was built with.
+func goVersion() string {
+	out, err := exec.Command("go", "env", "GOVERSION").Output()
+	if err != nil || strings.TrimSpace(string(out)) == "" {
+		return runtime.Version()
+	}
+	return strings.TrimSpace(string(out))
+}
@@ -2,11 +2,47 @@ package main
 
 import (
 	"bufio"
+	"bytes"
+	"encoding/binary"
 	"encoding/json"
+	"errors"
 	"fmt"
+	"io"
 	"os"
+	"strings"
+	"sync"
 )
 
+// Messages travel on a socket of their own, framed as frameMagic, a
+// big-endian uint32 length and the JSON payload. stdout and stderr carry
+// nothing but program output, which the host shows as it arrives.
+var frameMagic = []byte("\x00NPF")
+
+// maxFrameLen bounds what a frame header can make the kernel allocate.
+const maxFrameLen = 64 << 20
+
+// outputMark, "+" or "-", the request id and a newline go to both stdout and
+// stderr around every cell, so the host knows which output is whose and when
+// it has all of it.
+var outputMark = []byte("\x00NPM")
+
+// markOutput is set once connected to a host that spawned the kernel; only
+// such a host reads its stdout and stderr.
+var markOutput = false
+
+func writeOutputMark(kind byte, id string) {
+	if !markOutput || id == "" || strings.Contains(id, "\n") {
+		return
+	}
+	line := append(append(append([]byte{}, outputMark...), kind), id+"\n"...)
+	// Unbuffered, so nothing printed before can end up after the mark.
+	_, _ = os.Stdout.Write(line)
+	_, _ = os.Stderr.Write(line)
+}
+
+// writeMu serialises replies from the request loop and the execution goroutine.
+var writeMu sync.Mutex
+
 func writeErr(writer *bufio.Writer, id string, code string, message string) {
 	env := envelope{
 		Error: &ipcError{
@@ -36,7 +72,38 @@ func mustWrite(writer *bufio.Writer, env envelope) {
 		fmt.Fprintf(os.Stderr, "marshal response error: %v\n", err)
 		return
 	}
+	// A host that went away has nobody to read the reply.
+	_ = writeFrame(writer, payload)
+}
+
+func writeFrame(writer *bufio.Writer, payload []byte) error {
+	var header [4]byte
+	binary.BigEndian.PutUint32(header[:], uint32(len(payload)))
+	writeMu.Lock()
+	defer writeMu.Unlock()
+	writer.Write(frameMagic)
+	writer.Write(header[:])
 	writer.Write(payload)
-	writer.WriteString("\n")
-	writer.Flush()
+	return writer.Flush()
+}
+
+// readFrame returns the next frame's payload. A stream that does not start
+// with a frame header, or announces an oversized frame, is corrupt.
+func readFrame(reader *bufio.Reader) ([]byte, error) {
+	var header [8]byte
+	if _, err := io.ReadFull(reader, header[:]); err != nil {
+		return nil, err
+	}
+	if !bytes.Equal(header[:4], frameMagic) {
+		return nil, errors.New("missing frame header")
+	}
+	length := binary.BigEndian.Uint32(header[4:])
+	if length > maxFrameLen {
+		return nil, fmt.Errorf("frame of %d bytes exceeds the %d byte limit", length, maxFrameLen)
+	}
+	payload := make([]byte, length)
+	if _, err := io.ReadFull(reader, payload); err != nil {
+		return nil, err
+	}
+	return payload, nil
 }
@@ -0,0 +1,89 @@
+package main
+
+import (
+	"bufio"
+	"errors"
+	"fmt"
+	"net"
+	"os"
+	"strings"
+)
+
+// splitAddress turns tcp://host:port or unix:///path into a network and
+// address for the net package.
+func splitAddress(address string) (string, string, error) {
+	switch {
+	case strings.HasPrefix(address, "tcp://"):
+		return "tcp", strings.TrimPrefix(address, "tcp://"), nil
+	case strings.HasPrefix(address, "unix://"):
+		return "unix", strings.TrimPrefix(address, "unix://"), nil
+	default:
+		return "", "", fmt.Errorf("unsupported address %q, expected tcp://host:port or unix:///path", address)
+	}
+}
+
+// connect serves the host that spawned this kernel, proving it is the kernel
+// the host started by sending the token first.
+func connect(address string, token string) error {
+	if token == "" {
+		return errors.New("--connect needs NEUROPAD_KERNEL_TOKEN set")
+	}
+	network, addr, err := splitAddress(address)
+	if err != nil {
+		return err
+	}
+	conn, err := net.Dial(network, addr)
+	if err != nil {
+		return err
+	}
+	defer conn.Close()
+	writer := bufio.NewWriter(conn)
+	if err := writeFrame(writer, []byte(token)); err != nil {
+		return err
+	}
+	markOutput = true
+	serve(bufio.NewReader(conn), writer, "")
+	return nil
+}
+
+// listen serves one host connection at a time on address until a host asks
+// the kernel to shut down.
+func listen(address string, token string) error {
+	network, addr, err := splitAddress(address)
+	if err != nil {
+		return err
+	}
+	var listener net.Listener
+	if network == "tcp" {
+		if token == "" {
+			// Anyone who can reach the port could run code as this user.
+			return errors.New("refusing to listen on TCP without NEUROPAD_KERNEL_TOKEN set")
+		}
+		listener, err = net.Listen("tcp", addr)
+	} else {
+		if info, statErr := os.Lstat(addr); statErr == nil && info.Mode()&os.ModeSocket != 0 {
+			os.Remove(addr)
+		}
+		listener, err = listenUnix(addr)
+	}
+	if err != nil {
+		return err
+	}
+	defer listener.Close()
+	fmt.Fprintf(os.Stderr, "go kernel listening on %s\n", address)
+
+	for {
+		conn, err := listener.Accept()
+		if err != nil {
+			if errors.Is(err, net.ErrClosed) {
+				return err
+			}
+			continue
+		}
+		done := serve(bufio.NewReader(conn), bufio.NewWriter(conn), token)
+		conn.Close()
+		if done {
+			return nil
+		}
+	}
+}
@@ -2,54 +2,131 @@ package main
 
 import (
 	"bufio"
+	"crypto/subtle"
 	"encoding/json"
+	"errors"
+	"flag"
+	"fmt"
 	"io"
 	"os"
-	"strings"
 )
 
 func main() {
-	reader := bufio.NewReader(os.Stdin)
-	writer := bufio.NewWriter(os.Stdout)
-	defer writer.Flush()
+	connectAddr := flag.String("connect", "", "serve the host listening at this address")
+	listenAddr := flag.String("listen", "", "wait for hosts at tcp://host:port or unix:///path")
+	flag.Parse()
 
+	// Cells have no business seeing the token, nor passing it on to programs.
+	token := os.Getenv("NEUROPAD_KERNEL_TOKEN")
+	os.Unsetenv("NEUROPAD_KERNEL_TOKEN")
+
+	var err error
+	switch {
+	case *connectAddr != "" && *listenAddr == "":
+		err = connect(*connectAddr, token)
+	case *listenAddr != "" && *connectAddr == "":
+		err = listen(*listenAddr, token)
+	default:
+		err = errors.New("expected exactly one of --connect or --listen")
+	}
+	if err != nil {
+		fmt.Fprintf(os.Stderr, "go kernel: %v\n", err)
+		os.Exit(1)
+	}
+}
+
+// serve answers requests until the input ends and reports whether it was
+// asked to shut down. An empty token accepts any hello. Every reply is
+// flushed by writeFrame under writeMu, so writer needs no flush of its own.
+func serve(reader *bufio.Reader, writer *bufio.Writer, token string) bool {
+	// Cells run one at a time off the request loop, which stays free to
+	// answer completions and pings while a program compiles.
+	executions := make(chan execution, 64)
+	defer close(executions)
+	go runExecutions(writer, executions)
+
+	authenticated := token == ""
 	for {
-		line, err := reader.ReadString('\n')
+		frame, err := readFrame(reader)
 		if err != nil {
-			if err == io.EOF {
-				return
+			if err == io.EOF || err == io.ErrUnexpectedEOF {
+				return false
 			}
 			writeErr(writer, "", "io_error", err.Error())
-			continue
-		}
-		line = strings.TrimSpace(line)
-		if line == "" {
-			continue
+			return false
 		}
 
 		var req request
-		if err := json.Unmarshal([]byte(line), &req); err != nil {
+		if err := json.Unmarshal(frame, &req); err != nil {
 			writeErr(writer, "", "parse_error", err.Error())
 			continue
 		}
 
+		if req.Method == "hello" && !authenticated {
+			var p helloParams
+			_ = json.Unmarshal(req.Params, &p)
+			if subtle.ConstantTimeCompare([]byte(p.Token), []byte(token)) != 1 {
+				writeErr(writer, req.ID, "unauthorized", "invalid kernel token")
+				return false
+			}
+			authenticated = true
+		} else if !authenticated {
+			writeErr(writer, req.ID, "unauthorized", "expected hello with the kernel token")
+			return false
+		}
+
 		switch req.Method {
+		case "hello":
+			writeResult(writer, req.ID, helloResult{
+				Kind:            "hello",
+				ProtocolVersion: protocolVersion,
+				Language:        "go",
+				LanguageVersion: goVersion(),
+				Methods:         methods,
+			})
 		case "execute":
 			var p executeParams
 			if err := json.Unmarshal(req.Params, &p); err != nil {
 				writeErr(writer, req.ID, "bad_request", err.Error())
 				continue
 			}
-			out, execErr := runGoCode(p.Code)
-			if execErr != nil {
-				writeErr(writer, req.ID, "execution_error", execErr.Error())
+			executions <- execution{id: req.ID, code: p.Code}
+		case "complete", "inspect":
+			var p cursorParams
+			if err := json.Unmarshal(req.Params, &p); err != nil {
+				writeErr(writer, req.ID, "bad_request", err.Error())
 				continue
 			}
-			writeResult(writer, req.ID, map[string]string{"text/plain": out})
+			if req.Method == "complete" {
+				writeResult(writer, req.ID, completeGo(p))
+			} else {
+				writeResult(writer, req.ID, inspectGo(p))
+			}
 		case "interrupt", "restart", "ping":
-			writeResult(writer, req.ID, map[string]bool{"ok": true})
+			writeResult(writer, req.ID, ackResult{Kind: "ack"})
+		case "shutdown":
+			writeResult(writer, req.ID, ackResult{Kind: "ack"})
+			return true
 		default:
 			writeErr(writer, req.ID, "unknown_method", req.Method)
 		}
 	}
 }
+
+type execution struct {
+	id   string
+	code string
+}
+
+func runExecutions(writer *bufio.Writer, executions <-chan execution) {
+	for ex := range executions {
+		writeOutputMark('+', ex.id)
+		out, execErr := runGoCode(ex.code)
+		writeOutputMark('-', ex.id)
+		if execErr != nil {
+			writeErr(writer, ex.id, "execution_error", execErr.Error())
+			continue
+		}
+		writeResult(writer, ex.id, executeResult{Kind: "execute", Data: map[string]string{"text/plain": out}})
+	}
+}
@@ -10,7 +10,7 @@ type request struct {
 
 type envelope struct {
 	ID     *string     `json:"id,omitempty"`
-	Event  *string     `json:"event,omitempty"`
+	Event  interface{} `json:"event,omitempty"`
 	Result interface{} `json:"result,omitempty"`
 	Error  *ipcError   `json:"error,omitempty"`
 }
@@ -20,6 +20,45 @@ type ipcError struct {
 	Message string `json:"message"`
 }
 
+type helloParams struct {
+	Token string `json:"token"`
+}
+
 type executeParams struct {
 	Code string `json:"code"`
 }
+
+type cursorParams struct {
+	Code      string `json:"code"`
+	CursorPos int    `json:"cursor_pos"`
+}
+
+type helloResult struct {
+	Kind            string   `json:"kind"`
+	ProtocolVersion int      `json:"protocol_version"`
+	Language        string   `json:"language"`
+	LanguageVersion string   `json:"language_version"`
+	Methods         []string `json:"methods"`
+}
+
+type ackResult struct {
+	Kind string `json:"kind"`
+}
+
+type executeResult struct {
+	Kind string            `json:"kind"`
+	Data map[string]string `json:"data"`
+}
+
+type completeResult struct {
+	Kind        string   `json:"kind"`
+	Matches     []string `json:"matches"`
+	CursorStart int      `json:"cursor_start"`
+	CursorEnd   int      `json:"cursor_end"`
+}
+
+type inspectResult struct {
+	Kind  string            `json:"kind"`
+	Found bool              `json:"found"`
+	Data  map[string]string `json:"data"`
+}
@@ -1,74 +1,305 @@
 #!/usr/bin/env python3
+import argparse
+import ast
+import builtins
+import hmac
 import json
+import keyword
+import os
+import platform
+import pydoc
+import queue
+import re
+import socket
+import stat
+import struct
 import sys
+import threading
 import traceback
 
+PROTOCOL_VERSION = 3
+METHODS = ["hello", "execute", "interrupt", "restart", "ping", "complete", "inspect", "shutdown"]
+IDENTIFIER = re.compile(r"[A-Za-z_][A-Za-z0-9_.]*$")
 
-def write_result(req_id: str, result):
+# Messages travel on a socket of their own, framed as FRAME_MAGIC, a
+# big-endian u32 length and the JSON payload. stdout and stderr carry nothing
+# but program output, which the host shows as it arrives.
+FRAME_MAGIC = b"\0NPF"
+MAX_FRAME_LEN = 64 * 1024 * 1024
+# Written to both stdout and stderr as OUTPUT_MARK, "+" or "-", the request
+# id and a newline around every cell, so the host knows which output is whose
+# and when it has all of it.
+OUTPUT_MARK = b"\0NPM"
+# Only a host that spawned the kernel reads its stdout and stderr.
+MARK_OUTPUT = False
+
+# The current host connection. Replies are written from both the request
+# loop and the execution thread.
+PROTOCOL_IN = None
+PROTOCOL_OUT = None
+WRITE_LOCK = threading.Lock()
+EXECUTIONS = queue.Queue()
+
+
+def send_frame(data: bytes):
+    with WRITE_LOCK:
+        try:
+            PROTOCOL_OUT.write(FRAME_MAGIC + struct.pack(">I", len(data)) + data)
+            PROTOCOL_OUT.flush()
+        except (OSError, ValueError):
+            # The host detached; the reply has nobody to go to.
+            pass
+
+
+def send(payload):
+    send_frame(json.dumps(payload).encode("utf-8"))
+
+
+def read_exact(count: int):
+    data = b""
+    while len(data) < count:
+        block = PROTOCOL_IN.read(count - len(data))
+        if not block:
+            return None
+        data += block
+    return data
+
+
+def read_frame():
+    """The next frame's payload, or None at end of input or once the stream
+    turns out corrupt."""
+    header = read_exact(len(FRAME_MAGIC) + 4)
+    if header is None or header[: len(FRAME_MAGIC)] != FRAME_MAGIC:
+        return None
+    (length,) = struct.unpack(">I", header[len(FRAME_MAGIC):])
+    if length > MAX_FRAME_LEN:
+        return None
+    return read_exact(length)
+
+
+
+def write_result(req_id: str, kind: str, **fields):
+    result = {"kind": kind, **fields}
     payload = {"result": result}
     if req_id:
         payload["id"] = req_id
-    print(json.dumps(payload), flush=True)
+    send(payload)
 
 
 def write_error(req_id: str, code: str, message: str):
     payload = {"error": {"code": code, "message": message}}
     if req_id:
         payload["id"] = req_id
-    print(json.dumps(payload), flush=True)
+    send(payload)
+
 
+def execute_python(code: str):
+    """Runs a cell. What it prints goes straight to stdout and stderr, between
+    the cell's marks; the result is the value of a final expression, as in an
+    interactive session."""
+    tree = ast.parse(code, "<neuropad>", "exec")
+    last = None
+    if tree.body and isinstance(tree.body[-1], ast.Expr):
+        last = ast.Expression(tree.body.pop().value)
+    scope = {}
+    exec(compile(tree, "<neuropad>", "exec"), scope, scope)
+    if last is None:
+        return {}
+    value = eval(compile(last, "<neuropad>", "eval"), scope, scope)
+    if value is None:
+        return {}
+    return {"text/plain": repr(value)}
 
-class CaptureStdout:
-    def __init__(self):
-        self.buffer = []
 
-    def write(self, text):
-        self.buffer.append(text)
+def mark_output(kind: bytes, req_id: str):
+    if not MARK_OUTPUT or not req_id or "\n" in req_id:
+        return
+    line = OUTPUT_MARK + kind + req_id.encode("utf-8") + b"\n"
+    for stream, fd in ((sys.__stdout__, 1), (sys.__stderr__, 2)):
+        try:
+            # Whatever the cell buffered belongs before the mark.
+            stream.flush()
+            os.write(fd, line)
+        except (OSError, ValueError):
+            pass
 
-    def flush(self):
-        pass
 
-    def text(self):
-        return "".join(self.buffer)
+def run_executions():
+    """Runs cells one at a time so the request loop stays free for completions."""
+    while True:
+        req_id, code = EXECUTIONS.get()
+        mark_output(b"+", req_id)
+        try:
+            data = execute_python(code)
+        except BaseException as exc:  # noqa: BLE001
+            # exit() included: it would end this thread, and with it every
+            # later cell, without a reply.
+            detail = "".join(traceback.format_exception_only(type(exc), exc)).strip()
+            mark_output(b"-", req_id)
+            write_error(req_id, "execution_error", detail)
+        else:
+            mark_output(b"-", req_id)
+            write_result(req_id, "execute", data=data)
 
 
-def execute_python(code: str):
-    scope = {}
-    capture = CaptureStdout()
-    real_stdout = sys.stdout
+def word_at(code: str, cursor_pos: int):
+    """The dotted name ending at the cursor and where it starts."""
+    cursor_pos = max(0, min(cursor_pos, len(code)))
+    match = IDENTIFIER.search(code[:cursor_pos])
+    if not match:
+        return "", cursor_pos
+    return match.group(0), match.start()
+
+
+def complete_python(code: str, cursor_pos: int):
+    word, start = word_at(code, cursor_pos)
+    names = set(keyword.kwlist) | set(dir(builtins))
+    matches = sorted(name for name in names if word and name.startswith(word))
+    return {"matches": matches, "cursor_start": start, "cursor_end": start + len(word)}
+
+
+def inspect_python(code: str, cursor_pos: int):
+    end = cursor_pos
+    while end < len(code) and (code[end].isalnum() or code[end] == "_"):
+        end += 1
+    word, _ = word_at(code, end)
+    target = getattr(builtins, word, None) if word else None
+    if target is None:
+        return {"found": False, "data": {}}
+    return {"found": True, "data": {"text/plain": pydoc.render_doc(target, renderer=pydoc.plaintext)}}
+
+
+def serve(token):
+    """Answers requests until the input ends. Returns True once asked to shut down."""
+    authenticated = token is None
+    while True:
+        frame = read_frame()
+        if frame is None:
+            return False
+        try:
+            req = json.loads(frame)
+        except (json.JSONDecodeError, UnicodeDecodeError) as exc:
+            write_error("", "parse_error", str(exc))
+            continue
+
+        req_id = req.get("id", "")
+        method = req.get("method", "")
+        params = req.get("params", {}) or {}
+
+        if method == "hello":
+            if not authenticated and not hmac.compare_digest(str(params.get("token", "")), token):
+                write_error(req_id, "unauthorized", "invalid kernel token")
+                return False
+            authenticated = True
+            write_result(
+                req_id,
+                "hello",
+                protocol_version=PROTOCOL_VERSION,
+                language="python",
+                language_version=platform.python_version(),
+                methods=METHODS,
+            )
+        elif not authenticated:
+            write_error(req_id, "unauthorized", "expected hello with the kernel token")
+            return False
+        elif method == "execute":
+            EXECUTIONS.put((req_id, str(params.get("code", ""))))
+        elif method in ("complete", "inspect"):
+            code = str(params.get("code", ""))
+            cursor_pos = int(params.get("cursor_pos", len(code)))
+            handler = complete_python if method == "complete" else inspect_python
+            write_result(req_id, method, **handler(code, cursor_pos))
+        elif method in ("interrupt", "restart", "ping"):
+            write_result(req_id, "ack")
+        elif method == "shutdown":
+            write_result(req_id, "ack")
+            return True
+        else:
+            write_error(req_id, "unknown_method", method)
+
+
+def socket_address(address: str):
+    """The socket family and address for tcp://host:port or unix:///path."""
+    if address.startswith("tcp://"):
+        host, _, port = address[len("tcp://"):].rpartition(":")
+        return socket.AF_INET6 if ":" in host else socket.AF_INET, (host.strip("[]"), int(port))
+    if address.startswith("unix://"):
+        return socket.AF_UNIX, address[len("unix://"):]
+    sys.exit(f"unsupported address '{address}', expected tcp://host:port or unix:///path")
+
+
+def bind(address: str, token):
+    family, target = socket_address(address)
+    if address.startswith("tcp://"):
+        if token is None:
+            # Anyone who can reach the port could run code as this user.
+            sys.exit("refusing to listen on TCP without NEUROPAD_KERNEL_TOKEN set")
+        return socket.create_server(target, family=family)
     try:
-        sys.stdout = capture
-        exec(compile(code, "<neuropad>", "exec"), scope, scope)
+        # Replace a socket left behind by an earlier run, nothing else.
+        if stat.S_ISSOCK(os.lstat(target).st_mode):
+            os.unlink(target)
+    except FileNotFoundError:
+        pass
+    server = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    # Owner-only from the moment the socket appears.
+    previous_umask = os.umask(0o177)
+    try:
+        server.bind(target)
     finally:
-        sys.stdout = real_stdout
-    text = capture.text()
-    if not text:
-        text = "ok"
-    return {"text/plain": text}
+        os.umask(previous_umask)
+    server.listen()
+    return server
 
 
-for line in sys.stdin:
-    line = line.strip()
-    if not line:
-        continue
-    try:
-        req = json.loads(line)
-    except json.JSONDecodeError as exc:
-        write_error("", "parse_error", str(exc))
-        continue
+def use_connection(conn):
+    global PROTOCOL_IN, PROTOCOL_OUT
+    PROTOCOL_IN = conn.makefile("rb")
+    PROTOCOL_OUT = conn.makefile("wb")
 
-    req_id = req.get("id", "")
-    method = req.get("method", "")
-    params = req.get("params", {}) or {}
 
-    if method == "execute":
-        code = str(params.get("code", ""))
-        try:
-            write_result(req_id, execute_python(code))
-        except Exception as exc:  # noqa: BLE001
-            detail = "".join(traceback.format_exception_only(type(exc), exc)).strip()
-            write_error(req_id, "execution_error", detail)
-    elif method in ("interrupt", "restart", "ping"):
-        write_result(req_id, {"ok": True})
+def listen(address: str, token):
+    """Serves one host connection at a time until a host asks for shutdown."""
+    server = bind(address, token)
+    print(f"python kernel listening on {address}", file=sys.stderr, flush=True)
+    while True:
+        conn, _ = server.accept()
+        with conn:
+            use_connection(conn)
+            if serve(token):
+                break
+
+
+def connect(address: str, token):
+    """Connects back to the host that spawned this kernel and proves it is
+    the kernel the host started by sending the token first."""
+    global MARK_OUTPUT
+    if token is None:
+        sys.exit("--connect needs NEUROPAD_KERNEL_TOKEN set")
+    family, target = socket_address(address)
+    with socket.socket(family, socket.SOCK_STREAM) as conn:
+        conn.connect(target)
+        use_connection(conn)
+        send_frame(token.encode("utf-8"))
+        MARK_OUTPUT = True
+        serve(None)
+
+
+def main():
+    parser = argparse.ArgumentParser(description="NeuroPad Python kernel")
+    mode = parser.add_mutually_exclusive_group(required=True)
+    mode.add_argument("--connect", metavar="ADDRESS", help="serve the host listening at ADDRESS")
+    mode.add_argument("--listen", metavar="ADDRESS", help="wait for hosts at tcp://host:port or unix:///path")
+    args = parser.parse_args()
+    # Cells have no business seeing the token, nor passing it on to programs.
+    token = os.environ.pop("NEUROPAD_KERNEL_TOKEN", None) or None
+    # Printed lines reach the host while the cell is still running.
+    sys.stdout.reconfigure(line_buffering=True)
+    threading.Thread(target=run_executions, daemon=True).start()
+    if args.connect:
+        connect(args.connect, token)
     else:
-        write_error(req_id, "unknown_method", method)
+        listen(args.listen, token)
+
+
+main()
@@ -1,50 +1,265 @@
 #!/usr/bin/env ruby
 require "json"
+require "openssl"
+require "optparse"
 require "securerandom"
+require "socket"
 
 $stdout.sync = true
+$stderr.sync = true
 
-def write_result(id, result)
+PROTOCOL_VERSION = 3
+METHODS = %w[hello execute interrupt restart ping complete inspect shutdown].freeze
+IDENTIFIER = /[A-Za-z_][A-Za-z0-9_]*[?!]?\z/
+
+# Messages travel on a socket of their own, framed as FRAME_MAGIC, a
+# big-endian u32 length and the JSON payload. stdout and stderr carry nothing
+# but program output, which the host shows as it arrives.
+FRAME_MAGIC = "\0NPF".b
+MAX_FRAME_LEN = 64 * 1024 * 1024
+# Written to both stdout and stderr as OUTPUT_MARK, "+" or "-", the request
+# id and a newline around every cell, so the host knows which output is whose
+# and when it has all of it.
+OUTPUT_MARK = "\0NPM".b
+# Only a host that spawned the kernel reads its stdout and stderr.
+$mark_output = false
+
+# The current host connection. Replies come from both the request loop and
+# the execution thread.
+WRITE_LOCK = Mutex.new
+EXECUTIONS = Queue.new
+$protocol = nil
+
+def send_frame(data)
+  WRITE_LOCK.synchronize do
+    $protocol.write(FRAME_MAGIC + [data.bytesize].pack("N") + data)
+  rescue IOError, SystemCallError
+    # The host detached; the reply has nobody to go to.
+  end
+end
+
+def send_envelope(payload)
+  send_frame(JSON.generate(payload).b)
+end
+
+# The next frame's payload, or nil at end of input or once the stream turns
+# out corrupt.
+def read_frame
+  header = $protocol.read(FRAME_MAGIC.bytesize + 4)
+  return nil if header.nil? || header.bytesize < FRAME_MAGIC.bytesize + 4
+  return nil unless header.byteslice(0, FRAME_MAGIC.bytesize) == FRAME_MAGIC
+
+  length = header.byteslice(FRAME_MAGIC.bytesize, 4).unpack1("N")
+  return nil if length > MAX_FRAME_LEN
+
+  payload = $protocol.read(length)
+  payload && payload.bytesize == length ? payload : nil
+end
+
+def write_result(id, kind, fields = {})
   payload = {}
   payload["id"] = id unless id.nil? || id.empty?
-  payload["result"] = result
-  puts(JSON.generate(payload))
+  payload["result"] = { "kind" => kind }.merge(fields)
+  send_envelope(payload)
 end
 
 def write_error(id, code, message)
   payload = {}
   payload["id"] = id unless id.nil? || id.empty?
   payload["error"] = { "code" => code, "message" => message }
-  puts(JSON.generate(payload))
+  send_envelope(payload)
 end
 
-while (line = STDIN.gets)
-  line = line.strip
-  next if line.empty?
+def mark_output(kind, id)
+  id = id.to_s
+  return if !$mark_output || id.empty? || id.include?("\n")
 
-  begin
-    req = JSON.parse(line)
-  rescue JSON::ParserError => e
-    write_error("", "parse_error", e.message)
-    next
+  line = OUTPUT_MARK + kind + id.b + "\n"
+  [STDOUT, STDERR].each do |stream|
+    stream.write(line)
+  rescue IOError, SystemCallError
+    # Nobody is reading this stream any more.
   end
+end
 
-  id = req["id"] || SecureRandom.uuid
-  method = req["method"]
-  params = req["params"] || {}
-
-  case method
-  when "execute"
+# Runs cells one at a time so the request loop stays free for completions.
+Thread.new do
+  loop do
+    id, code = EXECUTIONS.pop
+    mark_output("+", id)
     begin
-      code = params["code"].to_s
       output = eval(code).inspect
-      write_result(id, { "text/plain" => output })
-    rescue StandardError => e
+      mark_output("-", id)
+      write_result(id, "execute", { "data" => { "text/plain" => output } })
+    # Exception, so that `exit` in a cell does not end this thread, and with
+    # it every later cell, without a reply.
+    rescue Exception => e # rubocop:disable Lint/RescueException
+      mark_output("-", id)
       write_error(id, "execution_error", e.message)
     end
-  when "interrupt", "restart", "ping"
-    write_result(id, { "ok" => true })
-  else
-    write_error(id, "unknown_method", method.to_s)
   end
 end
+
+# The identifier ending at the cursor and where it starts.
+def word_at(code, cursor_pos)
+  cursor_pos = cursor_pos.clamp(0, code.length)
+  match = IDENTIFIER.match(code[0, cursor_pos])
+  match ? [match[0], match.begin(0)] : ["", cursor_pos]
+end
+
+def complete_ruby(code, cursor_pos)
+  word, start = word_at(code, cursor_pos)
+  names = (Kernel.private_instance_methods + Kernel.instance_methods + Object.constants).map(&:to_s)
+  matches = word.empty? ? [] : names.uniq.select { |name| name.start_with?(word) }.sort
+  { "matches" => matches, "cursor_start" => start, "cursor_end" => start + word.length }
+end
+
+def inspect_ruby(code, cursor_pos)
+  finish = cursor_pos
+  finish += 1 while finish < code.length && code[finish] =~ /[A-Za-z0-9_?!]/
+  word, = word_at(code, finish)
+  return { "found" => false, "data" => {} } if word.empty?
+
+  text =
+    if word.match?(/\A[A-Z]\w*\z/) && Object.const_defined?(word)
+      value = Object.const_get(word)
+      value.is_a?(Module) ? "#{value.class} #{value}\nancestors: #{value.ancestors.take(6).join(", ")}" : value.inspect
+    elsif Kernel.respond_to?(word, true)
+      meth = Kernel.method(word)
+      "#{word}(#{meth.parameters.map { |kind, name| "#{kind} #{name}".strip }.join(", ")})"
+    end
+  return { "found" => false, "data" => {} } if text.nil?
+
+  { "found" => true, "data" => { "text/plain" => text } }
+end
+
+# Answers requests until the input ends. Returns true once asked to shut down.
+def serve(token)
+  authenticated = token.nil?
+  while (frame = read_frame)
+    begin
+      req = JSON.parse(frame.force_encoding(Encoding::UTF_8))
+    rescue JSON::ParserError => e
+      write_error("", "parse_error", e.message)
+      next
+    end
+
+    id = req["id"] || SecureRandom.uuid
+    method = req["method"]
+    params = req["params"] || {}
+
+    if method == "hello"
+      unless authenticated || OpenSSL.secure_compare(params["token"].to_s, token)
+        write_error(id, "unauthorized", "invalid kernel token")
+        return false
+      end
+      authenticated = true
+    elsif !authenticated
+      write_error(id, "unauthorized", "expected hello with the kernel token")
+      return false
+    end
+
+    case method
+    when "hello"
+      write_result(id, "hello", {
+        "protocol_version" => PROTOCOL_VERSION,
+        "language" => "ruby",
+        "language_version" => RUBY_VERSION,
+        "methods" => METHODS
+      })
+    when "execute"
+      EXECUTIONS << [id, params["code"].to_s]
+    when "complete", "inspect"
+      code = params["code"].to_s
+      cursor_pos = (params["cursor_pos"] || code.length).to_i
+      fields = method == "complete" ? complete_ruby(code, cursor_pos) : inspect_ruby(code, cursor_pos)
+      write_result(id, method, fields)
+    when "interrupt", "restart", "ping"
+      write_result(id, "ack")
+    when "shutdown"
+      write_result(id, "ack")
+      return true
+    else
+      write_error(id, "unknown_method", method.to_s)
+    end
+  end
+  false
+end
+
+def socket_address(address)
+  case address
+  when %r{\Atcp://(.+):(\d+)\z} then [:tcp, Regexp.last_match(1).delete("[]"), Regexp.last_match(2).to_i]
+  when %r{\Aunix://(.+)\z} then [:unix, Regexp.last_match(1)]
+  else abort "unsupported address '#{address}', expected tcp://host:port or unix:///path"
+  end
+end
+
+def bind(address, token)
+  kind, *target = socket_address(address)
+  if kind == :tcp
+    # Anyone who can reach the port could run code as this user.
+    abort "refusing to listen on TCP without NEUROPAD_KERNEL_TOKEN set" if token.nil?
+
+    return TCPServer.new(*target)
+  end
+
+  path = target.first
+  File.delete(path) if File.socket?(path)
+  # Owner-only from the moment the socket appears.
+  previous_umask = File.umask(0o177)
+  begin
+    UNIXServer.new(path)
+  ensure
+    File.umask(previous_umask)
+  end
+end
+
+def use_connection(conn)
+  conn.binmode
+  conn.sync = true
+  $protocol = conn
+end
+
+# Serves one host connection at a time until a host asks for shutdown.
+def listen(address, token)
+  server = bind(address, token)
+  warn "ruby kernel listening on #{address}"
+  loop do
+    conn = server.accept
+    use_connection(conn)
+    done = serve(token)
+    conn.close
+    break if done
+  end
+end
+
+# Connects back to the host that spawned this kernel and proves it is the
+# kernel the host started by sending the token first.
+def connect(address, token)
+  abort "--connect needs NEUROPAD_KERNEL_TOKEN set" if token.nil?
+
+  kind, *target = socket_address(address)
+  conn = kind == :tcp ? TCPSocket.new(*target) : UNIXSocket.new(*target)
+  use_connection(conn)
+  send_frame(token.b)
+  $mark_output = true
+  serve(nil)
+  conn.close
+end
+
+mode = nil
+OptionParser.new do |opts|
+  opts.banner = "Usage: ruby_kernel.rb (--connect ADDRESS | --listen ADDRESS)"
+  opts.on("--connect ADDRESS", "serve the host listening at ADDRESS") { |value| mode = [:connect, value] }
+  opts.on("--listen ADDRESS", "wait for hosts at tcp://host:port or unix:///path") { |value| mode = [:listen, value] }
+end.parse!
+abort "expected --connect or --listen" if mode.nil?
+
+# Cells have no business seeing the token, nor passing it on to programs.
+token = ENV.delete("NEUROPAD_KERNEL_TOKEN")
+token = nil if token&.empty?
+if mode.first == :connect
+  connect(mode.last, token)
+else
+  listen(mode.last, token)
+end